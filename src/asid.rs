@@ -0,0 +1,192 @@
+// Address space IDs (ASIDs): tagging TLB entries with which address space they came from.
+// Without them, switching to another page table means flushing the whole TLB, since everything in it
+// came from the old one. With them each address space gets a number that goes in satp, the TLB keeps
+// track of which number each entry was cached under, and only uses entries with the current one. So
+// switching is just a satp write, and when we take a page away we only flush it for the one ASID.
+
+// ASID 0 is the kernel's own page table. There's only so many ASIDs (the hardware has up to 16 bits of them,
+// init finds out how many), so they're handed out in "generations": every address space remembers the
+// generation its ASID is from, and once we run out we start a new generation, throw away every ASID and
+// flush every hart's TLB. Any address space with an ASID from an old generation gets a new one the next time
+// it's switched to. The ones running on a hart when we roll over keep theirs, so we don't pull an ASID out
+// from under a hart that's using it.
+
+// If the hardware has no ASID bits at all, everything is ASID 0 and we flush the TLB on every switch. We do
+// the same when it only has a few: every hart can be holding on to one across a roll over, so with not many
+// more ASIDs than harts there might be none left to hand out, and allocate would go round forever.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{consts::NUM_CPUS, cpu::Cpu, println, spinlock::Spinlock};
+
+// Where the ASID field is in satp, and the most bits it can have on RV64
+const SATP_ASID_SHIFT: usize = 44;
+const MAX_ASID_BITS: usize = 16;
+const SATP_ASID_MASK: usize = (1 << MAX_ASID_BITS) - 1;
+
+// The kernel page table's ASID, never handed out to anyone else
+pub const KERNEL_ASID: usize = 0;
+
+// How many ASID bits this hart has, filled in by init
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+
+// An address space's ASID, and the generation it's from, packed into one word:
+// generation << MAX_ASID_BITS | asid. Generation 0 never matches, so a new one starts without an ASID
+pub struct Asid(AtomicUsize);
+
+impl Asid {
+    pub const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    // The hardware ASID, for flushing the TLB. If it's from an old generation it might not be in anyone's
+    // TLB any more, but flushing it anyway doesn't hurt
+    pub fn number(&self) -> usize {
+        self.0.load(Ordering::Relaxed) & SATP_ASID_MASK
+    }
+}
+
+fn generation_of(context: usize) -> usize {
+    context >> MAX_ASID_BITS
+}
+
+struct Allocator {
+    generation: usize,
+    // Bit N is set when ASID N is taken in this generation
+    used: [u64; (1 << MAX_ASID_BITS) / 64],
+    // Where to start looking for a free ASID
+    next: usize,
+    // What each hart is running right now, in the same packed form as Asid
+    active: [usize; NUM_CPUS],
+    // What each hart was running when we last rolled over, those keep their ASID into the new generation
+    reserved: [usize; NUM_CPUS],
+    // Harts that have to flush their whole TLB before they switch to anything, set when we roll over
+    flush_pending: [bool; NUM_CPUS],
+}
+
+static ALLOCATOR: Spinlock<Allocator> = Spinlock::new(
+    "asid",
+    Allocator {
+        generation: 1,
+        used: [0; (1 << MAX_ASID_BITS) / 64],
+        // We never look below `next`, so starting at 1 keeps the kernel's ASID 0 to itself
+        next: 1,
+        active: [0; NUM_CPUS],
+        reserved: [0; NUM_CPUS],
+        flush_pending: [false; NUM_CPUS],
+    },
+);
+
+impl Allocator {
+    fn count(&self) -> usize {
+        1 << bits()
+    }
+
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & (1 << (asid % 64)) != 0
+    }
+
+    fn mark_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    // We've run out: start a new generation, where nothing but the kernel and the ASIDs harts are using
+    // right now is taken, and have every hart flush its TLB before it uses any of the others
+    fn roll_over(&mut self) {
+        self.generation += 1;
+        self.used = [0; (1 << MAX_ASID_BITS) / 64];
+        for hart in 0..NUM_CPUS {
+            self.reserved[hart] = self.active[hart];
+            let asid = self.active[hart] & SATP_ASID_MASK;
+            self.mark_used(asid);
+            self.flush_pending[hart] = true;
+        }
+        self.next = 1;
+    }
+
+    fn allocate(&mut self) -> usize {
+        loop {
+            if let Some(asid) = (self.next..self.count()).find(|&asid| !self.is_used(asid)) {
+                self.mark_used(asid);
+                self.next = asid + 1;
+                return (self.generation << MAX_ASID_BITS) | asid;
+            }
+            self.roll_over();
+        }
+    }
+
+    // `context` is from an old generation, if it was running on a hart when we rolled over it keeps
+    // its ASID, otherwise it gets a new one
+    fn renew(&mut self, context: usize) -> usize {
+        let asid = context & SATP_ASID_MASK;
+        if context != 0 && self.reserved.contains(&context) {
+            let renewed = (self.generation << MAX_ASID_BITS) | asid;
+            for reserved in self.reserved.iter_mut().filter(|r| **r == context) {
+                *reserved = renewed;
+            }
+            renewed
+        } else {
+            self.allocate()
+        }
+    }
+}
+
+// How many ASID bits the hardware has, 0 if it doesn't do ASIDs
+pub fn bits() -> usize {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+// Find out how many ASID bits there are, called by the boot hart once paging is on.
+// The ASID field in satp only keeps the bits the hardware has, so we write all ones and see what sticks
+pub fn init() {
+    let satp = riscv::register::satp::read().bits();
+    let bits = unsafe {
+        riscv::register::satp::write(satp | (SATP_ASID_MASK << SATP_ASID_SHIFT));
+        let stuck = (riscv::register::satp::read().bits() >> SATP_ASID_SHIFT) & SATP_ASID_MASK;
+        riscv::register::satp::write(satp);
+        // We were running under ASID 0xFFFF for a moment, get rid of anything cached under it
+        riscv::asm::sfence_vma_all();
+        stuck.count_ones() as usize
+    };
+    // Like Linux, we want at least twice as many as there are harts (and the kernel) before it's worth it
+    if 1 << bits < 2 * (NUM_CPUS + 1) {
+        println!("{bits} ASID bits, not enough to be worth using");
+        return;
+    }
+    ASID_BITS.store(bits, Ordering::Relaxed);
+    println!("{bits} ASID bits");
+}
+
+// What goes in satp to switch to a page table at `table` with ASID `asid`
+pub fn satp_for(mode: usize, asid: usize, table: usize) -> usize {
+    (mode << 60) | (asid << SATP_ASID_SHIFT) | (table >> 12)
+}
+
+// Get `space` an ASID that's good in this generation and mark it as running on this hart.
+// Returns the ASID, and whether this hart has to flush its whole TLB before switching to it
+pub fn activate(space: &Asid) -> (usize, bool) {
+    if bits() == 0 {
+        return (KERNEL_ASID, true);
+    }
+    let mut allocator = ALLOCATOR.lock();
+    let mut context = space.0.load(Ordering::Relaxed);
+    if generation_of(context) != allocator.generation {
+        context = allocator.renew(context);
+        space.0.store(context, Ordering::Relaxed);
+    }
+    let me = Cpu::get_id();
+    allocator.active[me] = context;
+    let flush = core::mem::take(&mut allocator.flush_pending[me]);
+    (context & SATP_ASID_MASK, flush)
+}
+
+// This hart is going back to the kernel's page table
+pub fn activate_kernel() -> bool {
+    if bits() == 0 {
+        return true;
+    }
+    let mut allocator = ALLOCATOR.lock();
+    let me = Cpu::get_id();
+    allocator.active[me] = KERNEL_ASID;
+    core::mem::take(&mut allocator.flush_pending[me])
+}
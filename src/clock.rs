@@ -0,0 +1,71 @@
+// This module keeps track of time.
+// Every hart has a `time` register that counts up at a fixed rate from when the machine turned on,
+// on QEMU this is backed by the CLINT's mtime register. The rate it counts at (the "timebase frequency")
+// is different per machine, so we read it from the device tree (see platform.rs).
+// On top of that we keep a `ticks` counter that goes up by one every timer interrupt on the boot hart,
+// so HZ (in consts.rs) times a second.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use riscv::register;
+
+use crate::{consts::HZ, platform::platform};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// The number of timer interrupts the boot hart has seen since we turned them on
+// Once we have a scheduler, processes that want to sleep for some number of ticks will wait on this
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// How many times a second the `time` register goes up
+#[inline]
+pub fn timebase_frequency() -> u64 {
+    platform().timebase_frequency
+}
+
+// The raw value of the `time` register, this only ever goes up
+// Supervisor mode can read it because timer_init sets mcounteren.TM (or the SBI firmware does)
+#[inline]
+pub fn now() -> u64 {
+    register::time::read() as u64
+}
+
+// The time since boot in nanoseconds
+#[inline]
+pub fn now_nanos() -> u64 {
+    cycles_to_nanos(now())
+}
+
+// Convert a number of `time` cycles to nanoseconds
+// We do the math in u128 so we don't overflow when multiplying by a billion
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    (cycles as u128 * NANOS_PER_SECOND as u128 / timebase_frequency() as u128) as u64
+}
+
+// Convert nanoseconds to a number of `time` cycles
+pub fn nanos_to_cycles(nanos: u64) -> u64 {
+    (nanos as u128 * timebase_frequency() as u128 / NANOS_PER_SECOND as u128) as u64
+}
+
+// How many `time` cycles there are between timer interrupts
+#[inline]
+pub fn tick_interval() -> u64 {
+    timebase_frequency() / HZ
+}
+
+// The number of ticks since boot
+// With the `tickless` feature nothing is counting ticks, so we work it out from the time instead
+#[inline]
+pub fn ticks() -> u64 {
+    if cfg!(feature = "tickless") {
+        now() / tick_interval()
+    } else {
+        TICKS.load(Ordering::Relaxed)
+    }
+}
+
+// Called by the timer interrupt handler on the boot hart, and only the boot hart
+// (otherwise we'd count every tick once per hart)
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
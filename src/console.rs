@@ -2,27 +2,121 @@
 // print characters to the console. It also provides a function to initialize
 // the console which should be called before any other functions in this module.
 
-use crate::spinlock;
+#[cfg(feature = "sbi")]
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::spinlock::Spinlock;
 use crate::uart::{uart_init, uart_put_c_sync};
+#[cfg(feature = "sbi")]
+use crate::{panic::PANICKED, sbi};
 
 // Here we define a global lock that we will use to synchronize access to the
 // console, you'll notice put_c doesn't actually use this lock,
 // and that's because put_c is meant for *kernel* code, and therefore
 // won't have a lock on it.
-spinlock!(CONSOLE_LOCK);
+static CONSOLE_LOCK: Spinlock<()> = Spinlock::new("console", ());
+
+// When we're running under SBI firmware and it has a debug console, we print through
+// that instead of the UART. On real boards the firmware knows where the console is, even
+// if it's not a 16550a UART like QEMU's
+#[cfg(feature = "sbi")]
+static USE_SBI_CONSOLE: AtomicBool = AtomicBool::new(false);
 
-// Here we're simply initializing the console spin lock.
-// and then initializing the UART, which is the device we'll be using
+// Here we're simply initializing the UART, which is the device we'll be using
 // to output text in QEMU.
 pub fn init_console() {
-    unsafe {
-        CONSOLE_LOCK = Some(Spinlock::new());
+    #[cfg(feature = "sbi")]
+    if sbi::probe_extension(sbi::EXT_DBCN) {
+        USE_SBI_CONSOLE.store(true, Ordering::Relaxed);
+        return;
     }
     uart_init();
 }
 
-const BACKSPACE: char = '\x08';
+// Send a single character out, through the firmware if we're using its console
+// or straight to the UART otherwise
+fn put_c_raw(c: char) {
+    #[cfg(feature = "sbi")]
+    if USE_SBI_CONSOLE.load(Ordering::Relaxed) {
+        // Same as the UART, if we've panicked spin here so we don't lose the panic message
+        while PANICKED.load(Ordering::Relaxed) {
+            core::hint::spin_loop();
+        }
+        let mut bytes = [0; 4];
+        for byte in c.encode_utf8(&mut bytes).bytes() {
+            let _ = sbi::console_write_byte(byte);
+        }
+        return;
+    }
+    uart_put_c_sync(c);
+}
+
+pub const BACKSPACE: char = '\x08';
+
+// How many typed characters we hold on to before nobody reads them, past this they're dropped
+const INPUT_SIZE: usize = 128;
+
+// What's been typed that nobody has read yet, the UART's interrupt puts bytes in (see uart_interrupt)
+// and get_c takes them out. `read` and `write` only ever count up, the index is them wrapped around
+struct Input {
+    bytes: [u8; INPUT_SIZE],
+    read: usize,
+    write: usize,
+}
+
+static INPUT: Spinlock<Input> = Spinlock::new(
+    "console_input",
+    Input {
+        bytes: [0; INPUT_SIZE],
+        read: 0,
+        write: 0,
+    },
+);
+
+// A byte came in from the UART, called from its interrupt
+pub fn receive(byte: u8) {
+    let mut input = INPUT.lock();
+    if input.write - input.read < INPUT_SIZE {
+        let index = input.write % INPUT_SIZE;
+        input.bytes[index] = byte;
+        input.write += 1;
+    }
+}
+
+// Whether get_c has something for us. The firmware's console doesn't interrupt us when something's typed,
+// so with that we can't know without asking it (see input_needs_polling)
+pub fn has_input() -> bool {
+    let input = INPUT.lock();
+    input.write != input.read
+}
+
+// True if nothing will wake us up when someone types, so whoever wants input has to keep checking
+pub fn input_needs_polling() -> bool {
+    #[cfg(feature = "sbi")]
+    if USE_SBI_CONSOLE.load(Ordering::Relaxed) {
+        return true;
+    }
+    false
+}
+
+// Get a character someone typed if there is one, this doesn't wait
+pub fn get_c() -> Option<u8> {
+    #[cfg(feature = "sbi")]
+    if USE_SBI_CONSOLE.load(Ordering::Relaxed) {
+        let mut byte = [0];
+        return match sbi::console_read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        };
+    }
+    let mut input = INPUT.lock();
+    if input.read == input.write {
+        return None;
+    }
+    let byte = input.bytes[input.read % INPUT_SIZE];
+    input.read += 1;
+    Some(byte)
+}
 
 // This is the function we'll use to print characters to the console.
 // it can be called from anywhere in the kernel, but we'll most likely use
@@ -33,10 +127,10 @@ pub fn put_c(c: char) {
         // by moving the cursor back one space, printing a space, and then
         // moving the cursor back again, making it look like the character
         // has been deleted.
-        uart_put_c_sync(BACKSPACE);
-        uart_put_c_sync(' ');
-        uart_put_c_sync(BACKSPACE);
+        put_c_raw(BACKSPACE);
+        put_c_raw(' ');
+        put_c_raw(BACKSPACE);
     } else {
-        uart_put_c_sync(c);
+        put_c_raw(c);
     }
 }
@@ -1,3 +1,20 @@
 pub const NUM_CPUS: usize = 8; // Max number of CPUs in our system
-pub const KERNEL_START: usize = 0x8000_0000; // Start of kernel memory
-pub const PHYS_STOP: usize = KERNEL_START + 128 * 1024 * 1024;
+
+// Where the kernel is loaded in physical memory, this has to match BASE_ADDRESS in linker.ld
+// (it runs KERNEL_OFFSET above this, see memlayout.rs)
+#[cfg(not(feature = "sbi"))]
+pub const KERNEL_START: usize = 0x8000_0000;
+// When we boot under SBI firmware, the firmware lives at 0x8000_0000 and we get loaded after it
+#[cfg(feature = "sbi")]
+pub const KERNEL_START: usize = 0x8020_0000;
+
+// The end of physical memory used to be a constant here (PHYS_STOP), now it comes from the device tree
+// See `platform::platform().memory_end`
+
+// How many pages of stack each hart gets in the kernel unless the command line says otherwise.
+// If you're running into "stack overflow on hart N" panics, boot with `kstack_pages=N` (see kstack.rs)
+pub const KERNEL_STACK_PAGES: usize = 4;
+// The most `kstack_pages` can ask for, every stack slot is this big so it sets how many stacks fit
+pub const MAX_KERNEL_STACK_PAGES: usize = 32;
+
+pub const HZ: u64 = 10; // How many timer ticks we want per second, see clock.rs
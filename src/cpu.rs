@@ -1,15 +1,51 @@
-use crate::consts::NUM_CPUS;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    clock,
+    consts::NUM_CPUS,
+    percpu::{PerCpu, PerCpuRef, PerCpuRefMut},
+    println,
+    sched::{Context, Pid},
+    spinlock::{disable_interrupts, InterruptGuard},
+};
 
 // Represents a CPU core in our system
-#[derive(Clone, Copy)]
 pub struct Cpu {
-    // pub process: Process,
-    // pub context: Context,
+    // The process running on this hart, None if it's just running the scheduler
+    // We don't have processes yet, so each hart is its own "process" (see sched.rs)
+    pub process: Option<Pid>,
+    // Where to jump back to in the scheduler when a process gives up the hart
+    pub context: Context,
     pub interrupt_disable_count: usize, // number of times we've disabled interrupts
     pub interrupts_were_on: bool,       // whether or not interrupts were on before we disabled them
+    pub interrupt_depth: usize, // how many interrupt handlers we're inside of, see kernel_trap
+    pub stats: CpuStats,
+}
+
+// Counters for what each hart has been up to, the `wakeups` monitor command prints these
+// They're atomics so another hart can read them while we're still counting
+pub struct CpuStats {
+    // How many times we've woken up from Cpu::idle
+    pub wakeups: AtomicU64,
+    // How many interrupts we've handled
+    pub interrupts: AtomicU64,
 }
 
 impl Cpu {
+    const fn new() -> Self {
+        Self {
+            process: None,
+            context: Context::new(),
+            interrupt_disable_count: 0,
+            interrupts_were_on: false,
+            interrupt_depth: 0,
+            stats: CpuStats {
+                wakeups: AtomicU64::new(0),
+                interrupts: AtomicU64::new(0),
+            },
+        }
+    }
+
     // WARNING: Must be called with interrupts disabled
     // Get the current ID of this core, this is thanks to use stashing the core ID in the tp register
     pub fn get_id() -> usize {
@@ -20,19 +56,78 @@ impl Cpu {
         }
     }
 
-    // WARNING: Must be called with interrupts disabled
-    // Will get the current CPU struct for the core that's currently running
-    // This is done by getting the core ID and then indexing into the CPUS array (declared below)
-    pub fn mine() -> &'static mut Self {
-        let id = Self::get_id();
-        unsafe { &mut CPUS[id] }
+    // Get the Cpu struct for the core that's currently running
+    // You need interrupts off to call this (that's what the guard proves), and the reference only lasts
+    // as long as they stay off, otherwise we could get moved to another hart and still be holding this one
+    pub fn mine(guard: &InterruptGuard) -> PerCpuRef<'_, Self> {
+        CPUS.get(guard)
+    }
+
+    // Same as above, but we can change it. Nothing else can look at our Cpu until it's dropped
+    pub fn mine_mut(guard: &mut InterruptGuard) -> PerCpuRefMut<'_, Self> {
+        CPUS.get_mut(guard)
+    }
+
+    // Whether we're running inside an interrupt handler, where we can't wait on anything
+    pub fn in_interrupt() -> bool {
+        let interrupts = disable_interrupts();
+        let depth = Self::mine(&interrupts).interrupt_depth;
+        depth > 0
+    }
+
+    // Sleep until an interrupt comes in, instead of burning power spinning
+    // Interrupts need to be on or we'll never wake up!
+    pub fn idle() {
+        unsafe {
+            riscv::asm::wfi();
+        }
+        // By the time we get here the interrupt has already been handled
+        let interrupts = disable_interrupts();
+        Self::mine(&interrupts)
+            .stats
+            .wakeups
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Like idle, but doesn't go to sleep if `awake` says there's already something to do.
+    // We check with interrupts off so an interrupt can't come in between checking and `wfi` and leave us
+    // asleep with work waiting. `wfi` still wakes up with interrupts off, the interrupt just gets handled
+    // once we turn them back on
+    pub fn idle_unless(awake: impl Fn() -> bool) {
+        let interrupts = disable_interrupts();
+        if awake() {
+            return;
+        }
+        unsafe {
+            riscv::asm::wfi();
+        }
+        Self::mine(&interrupts)
+            .stats
+            .wakeups
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Print how often each hart has been woken up since boot, in wakeups per second
+// Without the `tickless` feature this should be about HZ for every hart,
+// plus a wakeup on the boot hart for every key typed into the console (see monitor.rs)
+pub fn print_wakeup_stats() {
+    let now = clock::now().max(1);
+    let frequency = clock::timebase_frequency();
+    for hart in 0..NUM_CPUS {
+        // Safety: we only read the stats, and they're atomics
+        let stats = unsafe { &(*CPUS.remote(hart)).stats };
+        let wakeups = stats.wakeups.load(Ordering::Relaxed);
+        if wakeups == 0 {
+            continue;
+        }
+        // Multiply first so we don't lose everything after the decimal point
+        let per_second = (wakeups as u128 * frequency as u128 / now as u128) as u64;
+        let interrupts = stats.interrupts.load(Ordering::Relaxed);
+        println!("CPU {hart}: {wakeups} wakeups, {per_second}/s, {interrupts} interrupts");
     }
 }
 
-// Array of CPUs, one for each core
-pub static mut CPUS: [Cpu; NUM_CPUS] = [Cpu {
-    // process: Process::new(),
-    // context: Context::new(),
-    interrupt_disable_count: 0,
-    interrupts_were_on: false,
-}; 8];
+// Every hart's Cpu struct, see percpu.rs for how these get handed out
+// This is public so disable_interrupts can get at it, it's what makes the guard PerCpu asks for
+pub static CPUS: PerCpu<Cpu> = PerCpu::new([const { Cpu::new() }; NUM_CPUS]);
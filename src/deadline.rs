@@ -0,0 +1,135 @@
+// A small fixed-size min-heap of deadlines (values of the `time` register).
+// Each hart keeps one of these (see timer.rs) so it can always program its timer for the
+// *next* thing that needs to happen, rather than waking up on every tick to check.
+// We don't use a Vec here since we touch this from the timer interrupt, where we really
+// don't want to be calling into the allocator.
+
+// Two things can want to wake up at exactly the same time, so every deadline we add gets an ID back
+// and that's what it gets cancelled by. Otherwise cancelling one could take out the other.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// The most deadlines a single hart can be waiting on at once
+pub const MAX_DEADLINES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Which deadline to cancel, given back when it's added
+pub struct DeadlineId(u64);
+
+// IDs are handed out from one counter for every hart, so an ID from one hart's heap never matches
+// something in another's. A u64 won't wrap around before the heat death of the universe
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy)]
+struct Item {
+    deadline: u64,
+    id: DeadlineId,
+}
+
+pub struct DeadlineHeap {
+    // items[0] is always the earliest deadline, and every item is earlier than its two children
+    // (items[2i + 1] and items[2i + 2])
+    items: [Item; MAX_DEADLINES],
+    len: usize,
+}
+
+impl DeadlineHeap {
+    pub const fn new() -> Self {
+        Self {
+            items: [Item {
+                deadline: 0,
+                id: DeadlineId(0),
+            }; MAX_DEADLINES],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The earliest deadline, without removing it
+    #[inline]
+    pub fn peek(&self) -> Option<u64> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.items[0].deadline)
+        }
+    }
+
+    // Add a deadline, returns None if we're full
+    pub fn push(&mut self, deadline: u64) -> Option<DeadlineId> {
+        if self.len == MAX_DEADLINES {
+            return None;
+        }
+        let id = DeadlineId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        self.items[self.len] = Item { deadline, id };
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Some(id)
+    }
+
+    // Remove and return the earliest deadline
+    pub fn pop(&mut self) -> Option<u64> {
+        let earliest = self.peek()?;
+        self.remove_at(0);
+        Some(earliest)
+    }
+
+    // Remove the deadline push gave us `id` for, returns false if it wasn't there
+    pub fn remove(&mut self, id: DeadlineId) -> bool {
+        match self.items[..self.len].iter().position(|item| item.id == id) {
+            Some(index) => {
+                self.remove_at(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Take out the item at index by moving the last item into its place and then fixing the heap up
+    fn remove_at(&mut self, index: usize) {
+        self.len -= 1;
+        if index == self.len {
+            return;
+        }
+        self.items[index] = self.items[self.len];
+        // The item we moved in could be bigger or smaller than what was there, so try both ways
+        self.sift_down(index);
+        self.sift_up(index);
+    }
+
+    // Move an item up towards the root while it's earlier than its parent
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.items[index].deadline >= self.items[parent].deadline {
+                break;
+            }
+            self.items.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    // Move an item down towards the leaves while it's later than one of its children
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let left = 2 * index + 1;
+            let right = left + 1;
+            let mut earliest = index;
+            if left < self.len && self.items[left].deadline < self.items[earliest].deadline {
+                earliest = left;
+            }
+            if right < self.len && self.items[right].deadline < self.items[earliest].deadline {
+                earliest = right;
+            }
+            if earliest == index {
+                break;
+            }
+            self.items.swap(index, earliest);
+            index = earliest;
+        }
+    }
+}
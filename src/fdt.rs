@@ -0,0 +1,949 @@
+// This module parses the Flattened Device Tree (FDT), also called a DTB (Device Tree Blob).
+// When QEMU (or real firmware) jumps into our kernel it leaves the address of this blob in a1.
+// The blob describes the machine we're running on: how much RAM there is, how many harts (CPUs)
+// there are, and where all the memory-mapped devices (UART, PLIC, CLINT, virtio) live.
+// This means we don't have to hard-code all those addresses and hope QEMU never changes them!
+
+// The blob is laid out like so (every number in it is big-endian):
+// - A header, which tells us where the other blocks are
+// - The memory reservation block, regions of RAM we shouldn't touch
+// - The structure block, a flat list of "tokens" describing a tree of nodes and their properties
+// - The strings block, where the names of properties are stored (properties refer to them by offset)
+
+// We don't have an allocator when we parse this (we need the tree to know where RAM is!)
+// so everything here just borrows from the blob itself.
+
+// This module only uses `core` and never panics on a malformed blob (everything is bounds checked
+// and just returns None), so it can be pulled into a host program with `#[path = "src/fdt.rs"] mod fdt;`
+// and pointed at a tree QEMU dumped for us, see `just dump-dtb`.
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+// These are the tokens that make up the structure block
+const FDT_BEGIN_NODE: u32 = 0x1; // Followed by the node's name
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3; // Followed by the value's length, the name offset, then the value
+const FDT_NOP: u32 = 0x4;
+
+// How deep the tree can be before we give up, QEMU's trees are only ~4 deep
+const MAX_DEPTH: usize = 16;
+
+// How many interrupt-parent hops we'll follow before assuming the tree has a loop in it
+const MAX_INTERRUPT_HOPS: usize = 32;
+
+// Read a big-endian u32 at the given offset, None if it would be out of bounds
+#[inline]
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Read a value made of `cells` 32-bit cells, addresses are 2 cells on riscv64 for example
+fn read_cells(data: &[u8], offset: usize, cells: usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    for i in 0..cells {
+        value = (value << 32) | read_u32(data, offset + i * 4)? as u64;
+    }
+    Some(value)
+}
+
+// Read a null-terminated string starting at the given offset
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let rest = data.get(offset..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+// Everything in the structure block is aligned to 4 bytes
+#[inline]
+const fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+/// A parsed device tree, this is just a few slices into the blob
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structure: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Parse the header of a device tree blob, returns None if it doesn't look like one
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if read_u32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = read_u32(data, 4)? as usize;
+        let data = data.get(..total_size)?;
+
+        let struct_offset = read_u32(data, 8)? as usize;
+        let strings_offset = read_u32(data, 12)? as usize;
+        let strings_size = read_u32(data, 32)? as usize;
+        let struct_size = read_u32(data, 36)? as usize;
+
+        Some(Self {
+            data,
+            structure: data.get(struct_offset..struct_offset.checked_add(struct_size)?)?,
+            strings: data.get(strings_offset..strings_offset.checked_add(strings_size)?)?,
+        })
+    }
+
+    /// Parse a device tree blob at a physical address
+    ///
+    /// # Safety
+    /// `addr` must either be 0 or point to readable memory
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt<'static>> {
+        // The spec says the blob must be 8-byte aligned, if it isn't we probably got garbage
+        if addr == 0 || addr % 8 != 0 {
+            return None;
+        }
+        // Read just the magic and size first so we know how big of a slice to make
+        let header = core::slice::from_raw_parts(addr as *const u8, 8);
+        if read_u32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = read_u32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    /// The size of the entire blob in bytes
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The hart the firmware booted us on
+    pub fn boot_cpuid(&self) -> Option<u32> {
+        read_u32(self.data, 28)
+    }
+
+    /// Iterate over the memory reservation block, these are regions of RAM we shouldn't hand out
+    pub fn memory_reservations(&self) -> MemoryReservationIter<'a> {
+        MemoryReservationIter {
+            data: self.data,
+            offset: read_u32(self.data, 16).map(|o| o as usize),
+        }
+    }
+
+    /// Iterate over every node in the tree, parents always come before their children
+    pub fn nodes(&self) -> NodeIter<'a> {
+        NodeIter {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            stack: [None; MAX_DEPTH],
+            done: false,
+        }
+    }
+
+    /// Find the first node that's compatible with any of the given strings
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'a>> {
+        self.nodes()
+            .find(|node| compatible.iter().any(|c| node.is_compatible(c)))
+    }
+
+    /// Find a node by its full path, like `/cpus` or `/soc/plic@c000000`
+    /// The unit address (the `@...` part) can be left off if there's only one node with that name
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut nodes = self.nodes();
+        let root = nodes.next()?;
+
+        let mut want = match components.next() {
+            Some(want) => want,
+            None => return Some(root),
+        };
+        let mut depth = 1;
+
+        for node in nodes {
+            // If we've come back up above where we're looking, the node isn't there
+            if node.depth < depth {
+                return None;
+            }
+            let matches = node.name == want || (!want.contains('@') && node.base_name() == want);
+            if node.depth == depth && matches {
+                match components.next() {
+                    Some(next) => {
+                        want = next;
+                        depth += 1;
+                    }
+                    None => return Some(node),
+                }
+            }
+        }
+        None
+    }
+
+    /// Find the node with the given phandle, this is how nodes refer to each other
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Find the parent of a node, since the tree is just a flat list of tokens we have to
+    /// walk it from the start to figure this out
+    pub fn parent(&self, node: &Node<'a>) -> Option<Node<'a>> {
+        let mut nodes = self.nodes();
+        while let Some(current) = nodes.next() {
+            if current == *node {
+                return nodes.parent_of_current();
+            }
+        }
+        None
+    }
+
+    /// Find the interrupt controller a node's interrupts go to
+    /// This is the node pointed to by `interrupt-parent`, and if there isn't one we go up the tree
+    /// until we find a node that has it or is itself an interrupt controller
+    pub fn interrupt_parent(&self, node: &Node<'a>) -> Option<Node<'a>> {
+        let mut current = *node;
+        for _ in 0..MAX_INTERRUPT_HOPS {
+            let next = match current
+                .property("interrupt-parent")
+                .and_then(|p| p.as_u32())
+            {
+                Some(phandle) => self.find_phandle(phandle)?,
+                None => self.parent(&current)?,
+            };
+            if next.property("#interrupt-cells").is_some() {
+                return Some(next);
+            }
+            current = next;
+        }
+        None
+    }
+
+    /// Iterate over a node's `interrupts`, split up using the `#interrupt-cells` of its controller
+    pub fn interrupts(&self, node: &Node<'a>) -> Option<InterruptIter<'a>> {
+        let value = node.property("interrupts")?.value;
+        let controller = self.interrupt_parent(node)?;
+        Some(InterruptIter {
+            fdt: *self,
+            value,
+            offset: 0,
+            controller: Some(controller),
+        })
+    }
+
+    /// Iterate over a node's `interrupts-extended`, where every interrupt names its own controller
+    /// QEMU uses this for the PLIC and CLINT to say which hart contexts they talk to
+    pub fn interrupts_extended(&self, node: &Node<'a>) -> Option<InterruptIter<'a>> {
+        let value = node.property("interrupts-extended")?.value;
+        Some(InterruptIter {
+            fdt: *self,
+            value,
+            offset: 0,
+            controller: None,
+        })
+    }
+}
+
+/// Iterates over the (address, size) pairs in the memory reservation block
+pub struct MemoryReservationIter<'a> {
+    data: &'a [u8],
+    offset: Option<usize>,
+}
+
+impl<'a> Iterator for MemoryReservationIter<'a> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        let offset = self.offset?;
+        let address = read_cells(self.data, offset, 2)?;
+        let size = read_cells(self.data, offset + 8, 2)?;
+        // The list ends with an entry of all zeros
+        if address == 0 && size == 0 {
+            self.offset = None;
+            return None;
+        }
+        self.offset = Some(offset + 16);
+        Some(Region {
+            address: address as usize,
+            size: size as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+/// How many 32-bit cells an address and a size take up in a `reg` property
+/// These are set by a node for its *children* with #address-cells and #size-cells
+pub struct Cells {
+    pub address: usize,
+    pub size: usize,
+}
+
+impl Cells {
+    // The spec says to assume these if a node doesn't specify
+    pub const DEFAULT: Cells = Cells {
+        address: 2,
+        size: 1,
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+/// An (address, size) pair from a `reg` property
+pub struct Region {
+    pub address: usize,
+    pub size: usize,
+}
+
+impl Region {
+    #[inline]
+    pub fn end(&self) -> usize {
+        self.address + self.size
+    }
+
+    #[inline]
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.address && addr < self.end()
+    }
+}
+
+#[derive(Clone, Copy)]
+/// A single node in the tree, like `/soc/serial@10000000`
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    /// The name of the node including the unit address (e.g. `serial@10000000`), root is ""
+    pub name: &'a str,
+    /// How far down the tree we are, root is 0
+    pub depth: usize,
+    // Offset into the structure block right after our name, this is where our properties start
+    props_offset: usize,
+    // The cells our *parent* defined, we need these to decode our own `reg`
+    parent_cells: Cells,
+}
+
+// Two nodes are the same if they start at the same place in the same blob
+impl PartialEq for Node<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.props_offset == other.props_offset
+            && core::ptr::eq(self.fdt.structure, other.fdt.structure)
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Iterate over all of this node's properties
+    pub fn properties(&self) -> PropertyIter<'a> {
+        PropertyIter {
+            fdt: self.fdt,
+            offset: self.props_offset,
+        }
+    }
+
+    /// Find a property by name
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    /// The name of the node without the unit address, `serial@10000000` -> `serial`
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    /// The `device_type` property, used for `memory` and `cpu` nodes
+    pub fn device_type(&self) -> Option<&'a str> {
+        self.property("device_type")?.as_str()
+    }
+
+    /// The number other nodes use to refer to this one, if it has one
+    pub fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))?
+            .as_u32()
+    }
+
+    /// Whether the node is usable, nodes without a `status` are, otherwise it has to be "okay"
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|p| p.as_str()) {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    /// Whether this node's `compatible` list has the given string
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.property("compatible")
+            .map(|prop| prop.strings().any(|s| s == compatible))
+            .unwrap_or(false)
+    }
+
+    /// The cells this node defines for its children
+    pub fn cells(&self) -> Cells {
+        let address = self.property("#address-cells").and_then(|p| p.as_u32());
+        let size = self.property("#size-cells").and_then(|p| p.as_u32());
+        Cells {
+            address: address
+                .map(|a| a as usize)
+                .unwrap_or(Cells::DEFAULT.address),
+            size: size.map(|s| s as usize).unwrap_or(Cells::DEFAULT.size),
+        }
+    }
+
+    /// Iterate over the regions in this node's `reg` property
+    pub fn reg(&self) -> RegIter<'a> {
+        RegIter {
+            value: self.property("reg").map(|p| p.value).unwrap_or(&[]),
+            cells: self.parent_cells,
+            offset: 0,
+        }
+    }
+}
+
+/// Walks the structure block, yielding every node
+pub struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    depth: usize,
+    // Each node on the path from the root to where we are along with the cells it defines,
+    // we keep a fixed array here since we can't allocate
+    stack: [Option<(Node<'a>, Cells)>; MAX_DEPTH],
+    done: bool,
+}
+
+impl<'a> NodeIter<'a> {
+    // The parent of the node we last returned from `next`
+    fn parent_of_current(&self) -> Option<Node<'a>> {
+        let parent_depth = self.depth.checked_sub(2)?;
+        self.stack[parent_depth].map(|(node, _)| node)
+    }
+
+    fn advance(&mut self) -> Option<Node<'a>> {
+        loop {
+            let token = read_u32(self.fdt.structure, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(self.fdt.structure, self.offset)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+                    if self.depth >= MAX_DEPTH {
+                        return None;
+                    }
+                    let parent_cells = match self.depth.checked_sub(1) {
+                        Some(parent) => self.stack[parent]?.1,
+                        None => Cells::DEFAULT,
+                    };
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        props_offset: self.offset,
+                        parent_cells,
+                    };
+                    // Properties always come before child nodes, so we can grab
+                    // the cells for our children right now
+                    self.stack[self.depth] = Some((node, node.cells()));
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    // Skip over the property, we only care about nodes here
+                    let len = read_u32(self.fdt.structure, self.offset)? as usize;
+                    self.offset = align4(self.offset + 8 + len);
+                }
+                FDT_NOP => {}
+                // FDT_END, or something we don't understand, either way we're done
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        if self.done {
+            return None;
+        }
+        let node = self.advance();
+        self.done = node.is_none();
+        node
+    }
+}
+
+#[derive(Clone, Copy)]
+/// A property of a node, the value is raw bytes and it's up to us to know what's in them
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+impl<'a> Property<'a> {
+    /// Read the value as a single u32
+    pub fn as_u32(&self) -> Option<u32> {
+        read_u32(self.value, 0)
+    }
+
+    /// Read the value as a u32 or u64 depending on its length
+    pub fn as_usize(&self) -> Option<usize> {
+        read_cells(self.value, 0, self.value.len() / 4).map(|v| v as usize)
+    }
+
+    /// Read the value as a string, if it's a list of strings this is the first one
+    pub fn as_str(&self) -> Option<&'a str> {
+        read_str(self.value, 0)
+    }
+
+    /// Iterate over a list of null-separated strings, like `compatible`
+    pub fn strings(&self) -> impl Iterator<Item = &'a str> + 'a {
+        self.value
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+}
+
+/// Iterates over the properties of a single node
+pub struct PropertyIter<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Property<'a>> {
+        loop {
+            match read_u32(self.fdt.structure, self.offset)? {
+                FDT_NOP => self.offset += 4,
+                FDT_PROP => {
+                    let len = read_u32(self.fdt.structure, self.offset + 4)? as usize;
+                    let name_offset = read_u32(self.fdt.structure, self.offset + 8)? as usize;
+                    let value_start = self.offset + 12;
+                    let value = self.fdt.structure.get(value_start..value_start + len)?;
+                    let name = read_str(self.fdt.strings, name_offset)?;
+                    self.offset = align4(value_start + len);
+                    return Some(Property { name, value });
+                }
+                // Hit a child node or the end of our node, no more properties
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterates over the (address, size) pairs in a `reg` property
+pub struct RegIter<'a> {
+    value: &'a [u8],
+    cells: Cells,
+    offset: usize,
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        // A node with no address or size cells can't have a reg (and we'd never move forward)
+        if self.cells.address + self.cells.size == 0 {
+            return None;
+        }
+        let address = read_cells(self.value, self.offset, self.cells.address)?;
+        let size_offset = self.offset + self.cells.address * 4;
+        let size = read_cells(self.value, size_offset, self.cells.size)?;
+        self.offset = size_offset + self.cells.size * 4;
+        Some(Region {
+            address: address as usize,
+            size: size as usize,
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+/// A single interrupt, the controller it goes to and the cells describing it
+/// For the PLIC this is one cell, the IRQ number
+pub struct Interrupt<'a> {
+    pub controller: Node<'a>,
+    specifier: &'a [u8],
+}
+
+impl Interrupt<'_> {
+    /// Read one of the cells of the specifier
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        read_u32(self.specifier, index * 4)
+    }
+
+    /// The first cell, which is the IRQ number for every controller we care about
+    pub fn irq(&self) -> Option<u32> {
+        self.cell(0)
+    }
+}
+
+/// Iterates over `interrupts` or `interrupts-extended`
+pub struct InterruptIter<'a> {
+    fdt: Fdt<'a>,
+    value: &'a [u8],
+    offset: usize,
+    // Some for `interrupts` (they all go to the same controller),
+    // None for `interrupts-extended` (each one starts with the controller's phandle)
+    controller: Option<Node<'a>>,
+}
+
+impl<'a> Iterator for InterruptIter<'a> {
+    type Item = Interrupt<'a>;
+
+    fn next(&mut self) -> Option<Interrupt<'a>> {
+        let controller = match self.controller {
+            Some(controller) => controller,
+            None => {
+                let phandle = read_u32(self.value, self.offset)?;
+                self.offset += 4;
+                self.fdt.find_phandle(phandle)?
+            }
+        };
+        let cells = controller.property("#interrupt-cells")?.as_u32()? as usize;
+        // An interrupt with no cells would mean we never move forward
+        if cells == 0 {
+            return None;
+        }
+        let end = self.offset + cells * 4;
+        let specifier = self.value.get(self.offset..end)?;
+        self.offset = end;
+        Some(Interrupt {
+            controller,
+            specifier,
+        })
+    }
+}
+
+// These run on the host, not in the kernel: `just test-fdt` builds this file on its own with the test harness
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tiny tree in the same shape as QEMU's, made by hand since it's small enough to follow along with:
+    //
+    // /memreserve/ 0x88000000 0x1000;
+    // / {
+    //     #address-cells = <2>; #size-cells = <2>; compatible = "test,board";
+    //     memory@80000000 { device_type = "memory"; reg = <0 0x80000000 0 0x8000000>; };
+    //     cpus {
+    //         #address-cells = <1>; #size-cells = <0>;
+    //         cpu@0 { device_type = "cpu"; reg = <0>; };
+    //         cpu@1 { device_type = "cpu"; reg = <1>; status = "disabled"; };
+    //     };
+    //     soc {
+    //         plic@c000000 {
+    //             compatible = "sifive,plic-1.0.0", "riscv,plic0"; reg = <0 0xc000000 0 0x600000>;
+    //             #interrupt-cells = <1>; phandle = <1>;
+    //         };
+    //         serial@10000000 {
+    //             compatible = "ns16550a"; reg = <0 0x10000000 0 0x100>;
+    //             interrupts = <10>; interrupt-parent = <1>;
+    //         };
+    //     };
+    // };
+    const BLOB: &[u8] = &[
+        // Header: magic, total size, structure/strings/reservation offsets, version 17,
+        // last compatible version 16, boot hart 0, strings and structure sizes
+        0xd0, 0x0d, 0xfe, 0xed, 0x00, 0x00, 0x02, 0xec, 0x00, 0x00, 0x00, 0x48, 0x00, 0x00, 0x02,
+        0x78, 0x00, 0x00, 0x00, 0x28, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x72, 0x00, 0x00, 0x02, 0x30,
+        // Memory reservations: 0x88000000 for 0x1000, then the all-zero end
+        0x00, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, // Structure block
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00,
+        0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x00, 0x04, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x03, 0x00,
+        0x00, 0x00, 0x0b, 0x00, 0x00, 0x00, 0x1b, 0x74, 0x65, 0x73, 0x74, 0x2c, 0x62, 0x6f, 0x61,
+        0x72, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x40,
+        0x38, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x00, 0x07, 0x00, 0x00, 0x00, 0x26, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x00,
+        0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00, 0x01, 0x63, 0x70, 0x75, 0x73, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x01, 0x63, 0x70, 0x75, 0x40, 0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x26, 0x63, 0x70, 0x75, 0x00, 0x00, 0x00,
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x63, 0x70, 0x75, 0x40, 0x31, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x26, 0x63, 0x70, 0x75,
+        0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x36, 0x64,
+        0x69, 0x73, 0x61, 0x62, 0x6c, 0x65, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x73, 0x6f, 0x63, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x70, 0x6c, 0x69, 0x63, 0x40, 0x63, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x00, 0x1b, 0x73,
+        0x69, 0x66, 0x69, 0x76, 0x65, 0x2c, 0x70, 0x6c, 0x69, 0x63, 0x2d, 0x31, 0x2e, 0x30, 0x2e,
+        0x30, 0x00, 0x72, 0x69, 0x73, 0x63, 0x76, 0x2c, 0x70, 0x6c, 0x69, 0x63, 0x30, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x32, 0x00, 0x00,
+        0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x3d, 0x00, 0x00, 0x00, 0x01,
+        0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x4e, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x73, 0x65, 0x72, 0x69, 0x61, 0x6c,
+        0x40, 0x31, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00,
+        0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x1b, 0x6e, 0x73, 0x31, 0x36, 0x35, 0x35, 0x30, 0x61,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+        0x32, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x56, 0x00,
+        0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x61,
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00, 0x09, // Strings block
+        0x23, 0x61, 0x64, 0x64, 0x72, 0x65, 0x73, 0x73, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00,
+        0x23, 0x73, 0x69, 0x7a, 0x65, 0x2d, 0x63, 0x65, 0x6c, 0x6c, 0x73, 0x00, 0x63, 0x6f, 0x6d,
+        0x70, 0x61, 0x74, 0x69, 0x62, 0x6c, 0x65, 0x00, 0x64, 0x65, 0x76, 0x69, 0x63, 0x65, 0x5f,
+        0x74, 0x79, 0x70, 0x65, 0x00, 0x72, 0x65, 0x67, 0x00, 0x73, 0x74, 0x61, 0x74, 0x75, 0x73,
+        0x00, 0x23, 0x69, 0x6e, 0x74, 0x65, 0x72, 0x72, 0x75, 0x70, 0x74, 0x2d, 0x63, 0x65, 0x6c,
+        0x6c, 0x73, 0x00, 0x70, 0x68, 0x61, 0x6e, 0x64, 0x6c, 0x65, 0x00, 0x69, 0x6e, 0x74, 0x65,
+        0x72, 0x72, 0x75, 0x70, 0x74, 0x73, 0x00, 0x69, 0x6e, 0x74, 0x65, 0x72, 0x72, 0x75, 0x70,
+        0x74, 0x2d, 0x70, 0x61, 0x72, 0x65, 0x6e, 0x74, 0x00, 0x00, 0x00,
+    ];
+
+    fn fdt() -> Fdt<'static> {
+        Fdt::new(BLOB).expect("test blob didn't parse")
+    }
+
+    #[test]
+    fn header() {
+        let fdt = fdt();
+        assert_eq!(fdt.total_size(), BLOB.len());
+        assert_eq!(fdt.boot_cpuid(), Some(0));
+        let reservations: Vec<_> = fdt
+            .memory_reservations()
+            .map(|r| (r.address, r.size))
+            .collect();
+        assert_eq!(reservations, [(0x8800_0000, 0x1000)]);
+    }
+
+    #[test]
+    fn nodes() {
+        let names: Vec<_> = fdt().nodes().map(|node| (node.name, node.depth)).collect();
+        assert_eq!(
+            names,
+            [
+                ("", 0),
+                ("memory@80000000", 1),
+                ("cpus", 1),
+                ("cpu@0", 2),
+                ("cpu@1", 2),
+                ("soc", 1),
+                ("plic@c000000", 2),
+                ("serial@10000000", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn find_and_parent() {
+        let fdt = fdt();
+        let serial = fdt.find_node("/soc/serial").unwrap();
+        assert_eq!(serial.name, "serial@10000000");
+        assert!(fdt.find_node("/soc/serial@10000000") == Some(serial));
+        assert!(fdt.find_node("/soc/serial@20000000").is_none());
+        assert!(fdt.find_node("/cpus/serial").is_none());
+        assert_eq!(fdt.find_node("/").unwrap().depth, 0);
+
+        assert_eq!(fdt.parent(&serial).unwrap().name, "soc");
+        assert_eq!(
+            fdt.find_compatible(&["riscv,plic0"]).unwrap().name,
+            "plic@c000000"
+        );
+        assert_eq!(fdt.find_phandle(1).unwrap().name, "plic@c000000");
+        assert!(fdt.find_phandle(2).is_none());
+    }
+
+    #[test]
+    fn properties() {
+        let fdt = fdt();
+        let plic = fdt.find_node("/soc/plic").unwrap();
+        let compatible: Vec<_> = plic.property("compatible").unwrap().strings().collect();
+        assert_eq!(compatible, ["sifive,plic-1.0.0", "riscv,plic0"]);
+        assert!(plic.is_compatible("riscv,plic0"));
+        assert!(!plic.is_compatible("riscv,plic"));
+        assert_eq!(plic.phandle(), Some(1));
+        assert!(plic.property("interrupts").is_none());
+
+        let memory = fdt.find_node("/memory").unwrap();
+        assert_eq!(memory.device_type(), Some("memory"));
+        assert_eq!(plic.property("#interrupt-cells").unwrap().as_u32(), Some(1));
+
+        assert!(fdt.find_node("/cpus/cpu@0").unwrap().is_enabled());
+        assert!(!fdt.find_node("/cpus/cpu@1").unwrap().is_enabled());
+    }
+
+    #[test]
+    fn reg_cells() {
+        let fdt = fdt();
+        // Two address and two size cells from the root
+        let memory: Vec<_> = fdt
+            .find_node("/memory")
+            .unwrap()
+            .reg()
+            .map(|r| (r.address, r.size))
+            .collect();
+        assert_eq!(memory, [(0x8000_0000, 0x800_0000)]);
+
+        // cpus has one address cell and no size cells, so each cpu's reg is just its hart ID
+        let cpus = fdt.find_node("/cpus").unwrap();
+        assert_eq!(cpus.cells().address, 1);
+        assert_eq!(cpus.cells().size, 0);
+        let cpu1: Vec<_> = fdt
+            .find_node("/cpus/cpu@1")
+            .unwrap()
+            .reg()
+            .map(|r| (r.address, r.size))
+            .collect();
+        assert_eq!(cpu1, [(1, 0)]);
+
+        // soc doesn't say, so its children get the defaults (2 and 1), which splits serial's reg differently
+        assert_eq!(fdt.find_node("/soc").unwrap().cells().address, 2);
+        let serial = fdt.find_node("/soc/serial").unwrap().reg().next().unwrap();
+        assert_eq!(serial.address, 0x1000_0000);
+        assert_eq!(serial.size, 0);
+        assert_eq!(fdt.find_node("/soc/serial").unwrap().reg().count(), 1);
+    }
+
+    #[test]
+    fn interrupts() {
+        let fdt = fdt();
+        let serial = fdt.find_node("/soc/serial").unwrap();
+        let plic = fdt.interrupt_parent(&serial).unwrap();
+        assert_eq!(plic.name, "plic@c000000");
+        let irqs: Vec<_> = fdt.interrupts(&serial).unwrap().map(|i| i.irq()).collect();
+        assert_eq!(irqs, [Some(10)]);
+        // The memory node has no interrupts and no controller above it
+        let memory = fdt.find_node("/memory").unwrap();
+        assert!(fdt.interrupts(&memory).is_none());
+        assert!(fdt.interrupt_parent(&memory).is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        // Not even a whole header
+        assert!(Fdt::new(&BLOB[..3]).is_none());
+        assert!(Fdt::new(&BLOB[..20]).is_none());
+        // The header says it's bigger than what we've got
+        assert!(Fdt::new(&BLOB[..BLOB.len() - 1]).is_none());
+        // Wrong magic
+        let mut bad = BLOB.to_vec();
+        bad[0] = 0;
+        assert!(Fdt::new(&bad).is_none());
+    }
+
+    #[test]
+    fn truncated_structure() {
+        // Cut the structure block off at every point, everything should stop early instead of panicking
+        let full = read_u32(BLOB, 36).unwrap() as usize;
+        for struct_size in 0..full {
+            let mut blob = BLOB.to_vec();
+            blob[36..40].copy_from_slice(&(struct_size as u32).to_be_bytes());
+            let fdt = Fdt::new(&blob).unwrap();
+            assert!(fdt.nodes().count() <= 8);
+            for node in fdt.nodes() {
+                node.properties().for_each(drop);
+                node.reg().for_each(drop);
+                if let Some(irqs) = fdt.interrupts(&node) {
+                    irqs.for_each(drop);
+                }
+            }
+        }
+        // Halfway through there's only the first few nodes
+        let mut blob = BLOB.to_vec();
+        blob[36..40].copy_from_slice(&(full as u32 / 2).to_be_bytes());
+        let fdt = Fdt::new(&blob).unwrap();
+        assert!(fdt.find_node("/memory").is_some());
+        assert!(fdt.find_node("/soc/serial").is_none());
+    }
+
+    // What QEMU's virt machine gives us with `-m 128M -smp 3` (`just dump-dtb` makes it). The same
+    // things platform.rs looks for, in a tree with all the other bits QEMU puts in
+    const VIRT: &[u8] = include_bytes!("../testdata/virt.dtb");
+
+    fn virt() -> Fdt<'static> {
+        Fdt::new(VIRT).expect("virt.dtb didn't parse")
+    }
+
+    // The first reg of `path` as (address, size)
+    fn first_reg(fdt: &Fdt, path: &str) -> (usize, usize) {
+        let reg = fdt.find_node(path).unwrap().reg().next().unwrap();
+        (reg.address, reg.size)
+    }
+
+    #[test]
+    fn virt_memory() {
+        let fdt = virt();
+        assert_eq!(fdt.total_size(), VIRT.len());
+        assert_eq!(fdt.memory_reservations().count(), 0);
+        let memory: Vec<_> = fdt
+            .nodes()
+            .filter(|node| node.device_type() == Some("memory"))
+            .flat_map(|node| node.reg().map(|r| (r.address, r.size)))
+            .collect();
+        assert_eq!(memory, [(0x8000_0000, 128 * 1024 * 1024)]);
+    }
+
+    #[test]
+    fn virt_cpus() {
+        let fdt = virt();
+        assert_eq!(fdt.boot_cpuid(), Some(0));
+        let cpus = fdt.find_node("/cpus").unwrap();
+        assert_eq!(
+            cpus.property("timebase-frequency").unwrap().as_u32(),
+            Some(10_000_000)
+        );
+        let harts: Vec<_> = fdt
+            .nodes()
+            .filter(|node| node.device_type() == Some("cpu") && node.is_enabled())
+            .map(|node| node.reg().next().unwrap().address)
+            .collect();
+        assert_eq!(harts, [0, 1, 2]);
+        // Every hart has its own interrupt controller, which the PLIC and CLINT point at
+        let intc = fdt.find_node("/cpus/cpu@1/interrupt-controller").unwrap();
+        assert!(intc.is_compatible("riscv,cpu-intc"));
+        assert_eq!(fdt.parent(&intc).unwrap().name, "cpu@1");
+    }
+
+    #[test]
+    fn virt_devices() {
+        let fdt = virt();
+        let uart = fdt.find_compatible(&["ns16550a"]).unwrap();
+        assert_eq!(first_reg(&fdt, "/soc/serial"), (0x1000_0000, 0x100));
+        let irqs: Vec<_> = fdt.interrupts(&uart).unwrap().map(|i| i.irq()).collect();
+        assert_eq!(irqs, [Some(10)]);
+        assert_eq!(fdt.interrupt_parent(&uart).unwrap().name, "plic@c000000");
+
+        let plic = fdt.find_compatible(&["riscv,plic0"]).unwrap();
+        assert_eq!(first_reg(&fdt, "/soc/plic"), (0xc00_0000, 0x60_0000));
+        assert_eq!(plic.property("riscv,ndev").unwrap().as_u32(), Some(0x5f));
+        let clint = fdt.find_compatible(&["riscv,clint0"]).unwrap();
+        assert_eq!(first_reg(&fdt, "/soc/clint"), (0x200_0000, 0x1_0000));
+
+        // Supervisor external (9) and machine external (11) for each hart on the PLIC, software (3) and timer
+        // (7) on the CLINT, all through the harts' own interrupt controllers
+        for (node, wanted) in [(plic, [11, 9]), (clint, [3, 7])] {
+            let extended: Vec<_> = fdt
+                .interrupts_extended(&node)
+                .unwrap()
+                .map(|i| (fdt.parent(&i.controller).unwrap().name, i.irq().unwrap()))
+                .collect();
+            let expected: Vec<_> = ["cpu@0", "cpu@1", "cpu@2"]
+                .into_iter()
+                .flat_map(|cpu| wanted.map(|irq| (cpu, irq)))
+                .collect();
+            assert_eq!(extended, expected);
+        }
+    }
+
+    #[test]
+    fn virt_virtio() {
+        let fdt = virt();
+        // QEMU lists them from the top down, slot N is at 0x10000000 + N * 0x1000 with IRQ N
+        let mut virtio: Vec<_> = fdt
+            .nodes()
+            .filter(|node| node.is_compatible("virtio,mmio"))
+            .map(|node| {
+                let reg = node.reg().next().unwrap();
+                let irq = fdt.interrupts(&node).unwrap().next().unwrap().irq();
+                (reg.address, reg.size, irq)
+            })
+            .collect();
+        assert_eq!(virtio.len(), 8);
+        virtio.sort();
+        for (slot, (address, size, irq)) in (1..).zip(virtio) {
+            assert_eq!(address, 0x1000_0000 + slot * 0x1000);
+            assert_eq!(size, 0x1000);
+            assert_eq!(irq, Some(slot as u32));
+        }
+    }
+}
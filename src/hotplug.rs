@@ -0,0 +1,204 @@
+// Bringing harts online, and taking them offline again while we're running.
+// We find out which harts there are from the device tree (see platform.rs). The boot hart sets
+// everything up on its own, then lets the others start one at a time, waiting for each one to say
+// it's online before starting the next. That way a hart that never shows up can't hold everyone else up,
+// and the boot messages don't come out all jumbled together.
+
+// Once we're running a hart can be "parked": it hands its work to another hart, stops its timer and sits
+// in `wfi` until it's asked to come back. It's still powered on, we just stop giving it anything to do.
+// The `cpu_offline` and `cpu_online` monitor commands do this (see monitor.rs).
+
+// A hart is online when it's running kernel code and can take IPIs, `run_on` only ever waits on
+// online harts (see ipi.rs), so a parked hart is never waited on. That also means it doesn't get TLB
+// shootdowns, so a parked hart keeps interrupts off and runs nothing but its idle loop until it comes
+// back, then throws out its whole TLB before touching anything else.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::{
+    clock, consts::NUM_CPUS, cpu::Cpu, ipi, platform::platform, println,
+    spinlock::disable_interrupts, timer, timer_wheel, tlb,
+};
+
+// How long we wait for a hart to come online or park before giving up on it
+const TIMEOUT_NANOS: u64 = 1_000_000_000;
+
+// Bit N is set while hart N is online
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+// Set by the boot hart when it's hart N's turn to start
+static START: [AtomicBool; NUM_CPUS] = [const { AtomicBool::new(false) }; NUM_CPUS];
+
+// Set to ask hart N to park, cleared to ask it to come back
+static PARK: [AtomicBool; NUM_CPUS] = [const { AtomicBool::new(false) }; NUM_CPUS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HotplugError {
+    // There's no hart with that ID on this machine
+    NoSuchHart,
+    // The boot hart runs the monitor, so it has to stay
+    BootHart,
+    // A hart can't wait for itself to park
+    ThisHart,
+    AlreadyOffline,
+    AlreadyOnline,
+    // We asked, but it didn't do it in time
+    TimedOut,
+}
+
+// Every hart that's online right now
+pub fn online_mask() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn is_online(hart: usize) -> bool {
+    hart < NUM_CPUS && online_mask() & (1 << hart) != 0
+}
+
+pub fn num_online() -> usize {
+    online_mask().count_ones() as usize
+}
+
+// Wait until `done` is true or we've waited too long, returns whether it happened
+fn wait_until(done: impl Fn() -> bool) -> bool {
+    let give_up = clock::now() + clock::nanos_to_cycles(TIMEOUT_NANOS);
+    while !done() {
+        if clock::now() >= give_up {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+// Called by every hart but the boot hart at the start of main, waits until it's our turn to start
+pub fn wait_for_start() {
+    let hart = Cpu::get_id();
+    while !START[hart].load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+// Called by every hart once it can take traps and interrupts are on
+pub fn mark_online() {
+    let interrupts = disable_interrupts();
+    ONLINE.fetch_or(1 << Cpu::get_id(), Ordering::AcqRel);
+    drop(interrupts);
+}
+
+// Called by the boot hart once it's online, starts every other hart one at a time
+pub fn start_secondaries() {
+    let boot_hart = platform().boot_hart;
+    let present = platform().hart_mask;
+    for hart in (0..NUM_CPUS).filter(|&hart| hart != boot_hart && present & (1 << hart) != 0) {
+        START[hart].store(true, Ordering::Release);
+        if !wait_until(|| is_online(hart)) {
+            println!("CPU {hart} didn't come online, carrying on without it");
+        }
+    }
+    println!("{} of {} harts online", num_online(), platform().num_harts);
+}
+
+// Ask `hart` to park, and wait until it has
+pub fn cpu_offline(hart: usize) -> Result<(), HotplugError> {
+    if hart >= NUM_CPUS || platform().hart_mask & (1 << hart) == 0 {
+        return Err(HotplugError::NoSuchHart);
+    }
+    if hart == platform().boot_hart {
+        return Err(HotplugError::BootHart);
+    }
+    if !is_online(hart) {
+        return Err(HotplugError::AlreadyOffline);
+    }
+    // Nothing moves us to another hart, so it's fine to check this with interrupts on
+    if hart == Cpu::get_id() {
+        return Err(HotplugError::ThisHart);
+    }
+    PARK[hart].store(true, Ordering::Release);
+    // Wake it up so it notices, it checks PARK every time it comes out of `wfi`
+    ipi::send_ipi(hart);
+    if wait_until(|| !is_online(hart)) {
+        Ok(())
+    } else {
+        // Take the request back, otherwise it'd park whenever it did get round to checking
+        // and `cpus` would say it's parked while it's still online
+        PARK[hart].store(false, Ordering::Release);
+        Err(HotplugError::TimedOut)
+    }
+}
+
+// Ask a parked `hart` to come back, and wait until it has
+pub fn cpu_online(hart: usize) -> Result<(), HotplugError> {
+    if hart >= NUM_CPUS || platform().hart_mask & (1 << hart) == 0 {
+        return Err(HotplugError::NoSuchHart);
+    }
+    if is_online(hart) {
+        return Err(HotplugError::AlreadyOnline);
+    }
+    PARK[hart].store(false, Ordering::Release);
+    ipi::send_ipi(hart);
+    if wait_until(|| is_online(hart)) {
+        Ok(())
+    } else {
+        Err(HotplugError::TimedOut)
+    }
+}
+
+// Called by every hart from its idle loop in main, parks it if cpu_offline asked it to
+pub fn park_if_asked() {
+    let me = Cpu::get_id();
+    if !PARK[me].load(Ordering::Acquire) {
+        return;
+    }
+
+    // Interrupts stay off until we're back online. Once we're out of ONLINE nobody shoots down our TLB,
+    // so we can't go running interrupt handlers on translations that might not be there any more
+    let interrupts = disable_interrupts();
+
+    // Go offline first so nobody starts waiting on us
+    ONLINE.fetch_and(!(1 << me), Ordering::AcqRel);
+
+    // Anything waiting on our timer goes to the lowest numbered hart that's still online.
+    // The boot hart can't be parked, so there's always one
+    let target = online_mask().trailing_zeros() as usize;
+    timer_wheel::migrate(me, target);
+    timer::park();
+    println!("CPU {me} parked, its timers went to CPU {target}");
+
+    // `wfi` still wakes up on a poke with interrupts off. Someone who saw us online just before we
+    // left might have put a call in our mailbox and be waiting on it, so we answer those by hand
+    while PARK[me].load(Ordering::Acquire) {
+        ipi::poll_mailbox();
+        unsafe {
+            riscv::asm::wfi();
+        }
+    }
+    ipi::poll_mailbox();
+
+    // We didn't get any TLB shootdowns while we were offline, so throw out everything we might have cached
+    tlb::flush_local_all();
+    timer::unpark();
+    ONLINE.fetch_or(1 << me, Ordering::AcqRel);
+    drop(interrupts);
+    println!("CPU {me} back online");
+}
+
+// Print which harts are online, for the `cpus` monitor command
+pub fn print_cpus() {
+    let present = platform().hart_mask;
+    for hart in (0..NUM_CPUS).filter(|&hart| present & (1 << hart) != 0) {
+        let state = if is_online(hart) {
+            "online"
+        } else if PARK[hart].load(Ordering::Relaxed) {
+            "parked"
+        } else {
+            "offline"
+        };
+        let boot = if hart == platform().boot_hart {
+            " (boot)"
+        } else {
+            ""
+        };
+        println!("CPU {hart}: {state}{boot}");
+    }
+}
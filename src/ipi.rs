@@ -0,0 +1,184 @@
+// Inter-processor interrupts (IPIs): one hart poking another.
+// Most of the time harts leave each other alone, but sometimes one needs another to do something
+// right now, on that hart: flush its TLB after we changed a page table everyone uses (see tlb.rs),
+// or wake up from `wfi` because what it was sleeping on is ready (see sched.rs).
+
+// The poke itself is a supervisor software interrupt, and there are two ways to send one:
+// - SBI: when we're booted under SBI firmware we just ask it to (the IPI extension)
+// - CLINT: otherwise, every hart has an MSIP register in the CLINT, writing 1 to it gives that hart a
+//   *machine* software interrupt. timervec.S then clears it and passes it on as a supervisor software
+//   interrupt, the same trick as the timer.
+// An IPI on its own doesn't say anything, so each hart has a mailbox of functions other harts want
+// it to call. run_on puts a call in every target's mailbox, pokes them, and waits for them to finish.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    hotplug, println,
+    spinlock::{disable_interrupts, Spinlock},
+    timer,
+};
+
+// How many calls can be waiting in a hart's mailbox at once
+const MAX_PENDING_CALLS: usize = 8;
+
+// What run_on calls on each hart, with the argument it was given
+// WARNING: This runs from an interrupt handler with interrupts off, so keep it short and don't sleep!
+pub type CrossCall = fn(usize);
+
+#[derive(Clone, Copy)]
+struct Call {
+    func: CrossCall,
+    arg: usize,
+    // Counted down once the call is done, lives on the stack of whoever called run_on
+    remaining: *const AtomicUsize,
+}
+
+// Safety: `remaining` points at an atomic, and run_on doesn't return until every hart is done with it
+unsafe impl Send for Call {}
+
+struct Mailbox {
+    calls: [Option<Call>; MAX_PENDING_CALLS],
+}
+
+static MAILBOXES: [Spinlock<Mailbox>; NUM_CPUS] = [const {
+    Spinlock::new(
+        "ipi_mailbox",
+        Mailbox {
+            calls: [None; MAX_PENDING_CALLS],
+        },
+    )
+}; NUM_CPUS];
+
+// The physical address of hart `hart_id`'s MSIP register, writing 1 to it sends that hart a machine software interrupt
+#[cfg(not(feature = "sbi"))]
+pub fn clint_msip_loc(hart_id: usize) -> usize {
+    crate::platform::platform().clint.base + hart_id * 4
+}
+
+// Poke `hart` with a supervisor software interrupt, it'll check its mailbox and wake up if it was idle
+pub fn send_ipi(hart: usize) {
+    #[cfg(feature = "sbi")]
+    if let Err(error) = crate::sbi::send_ipi(1, hart) {
+        println!("send_ipi: SBI couldn't poke hart {hart}: {error:?}");
+    }
+    // The CLINT is mapped in the kernel page table, in the direct map (see vm.rs)
+    #[cfg(not(feature = "sbi"))]
+    unsafe {
+        (crate::memlayout::phys_to_virt(clint_msip_loc(hart)) as *mut u32).write_volatile(1);
+    }
+}
+
+// Run every call waiting in this hart's mailbox
+fn run_pending_calls() {
+    loop {
+        // Take one call out at a time, the function might be slow and other harts want to get at the mailbox
+        let call = {
+            let mut mailbox = MAILBOXES[Cpu::get_id()].lock();
+            mailbox.calls.iter_mut().find_map(|slot| slot.take())
+        };
+        let Some(call) = call else {
+            return;
+        };
+        (call.func)(call.arg);
+        // Safety: the caller of run_on is waiting for this to hit 0, so it's still there
+        unsafe { (*call.remaining).fetch_sub(1, Ordering::Release) };
+    }
+}
+
+// Call `func(arg)` on every hart in `hart_mask` (bit N for hart N) and wait until they've all done it.
+// Harts that aren't online (see hotplug.rs) are skipped, returns how many harts it actually ran on.
+// If we're in the mask we run it too, straight away with interrupts off like everyone else
+pub fn run_on(hart_mask: usize, func: CrossCall, arg: usize) -> usize {
+    let remaining = AtomicUsize::new(0);
+    // We keep interrupts off the whole time so we stay on this hart, which means we'll never see our
+    // own IPIs. If two harts run_on each other at once they'd both be stuck waiting, so while we wait
+    // we keep checking our own mailbox too
+    let interrupts = disable_interrupts();
+    let me = Cpu::get_id();
+    let targets = hart_mask & hotplug::online_mask();
+    let mut ran_on = 0;
+
+    for hart in (0..NUM_CPUS).filter(|&hart| hart != me && targets & (1 << hart) != 0) {
+        let call = Call {
+            func,
+            arg,
+            remaining: &remaining,
+        };
+        remaining.fetch_add(1, Ordering::Relaxed);
+        // If their mailbox is full, keep emptying ours until they've made room
+        loop {
+            let mut mailbox = MAILBOXES[hart].lock();
+            if let Some(slot) = mailbox.calls.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(call);
+                break;
+            }
+            drop(mailbox);
+            run_pending_calls();
+            core::hint::spin_loop();
+        }
+        send_ipi(hart);
+        ran_on += 1;
+    }
+
+    if hart_mask & (1 << me) != 0 {
+        func(arg);
+        ran_on += 1;
+    }
+
+    while remaining.load(Ordering::Acquire) > 0 {
+        run_pending_calls();
+        core::hint::spin_loop();
+    }
+    drop(interrupts);
+    ran_on
+}
+
+// Run `func(arg)` on every other hart that's online, and on this one
+pub fn run_on_all(func: CrossCall, arg: usize) -> usize {
+    run_on(usize::MAX, func, arg)
+}
+
+// Called by kernel_trap in trap.rs when we get a supervisor software interrupt.
+// That's either another hart poking us, or timervec.S passing on a timer interrupt when we're using
+// the CLINT. We can't tell which, so we check both: an empty mailbox or a timer with nothing due
+// just means there was nothing to do
+pub fn software_interrupt() {
+    // Acknowledge the interrupt first by clearing the SSIP bit (bit 1) in sip,
+    // so a poke that comes in while we're working isn't lost
+    unsafe {
+        core::arch::asm!("csrc sip, {}", in(reg) 2);
+    }
+    run_pending_calls();
+    timer::timer_software_interrupt();
+}
+
+// Acknowledge a poke and run whatever's in our mailbox, without waiting for the interrupt.
+// A parked hart (see hotplug.rs) calls this with interrupts off, since the only thing it still does is
+// answer cross-calls from harts that saw it online just before it parked
+pub fn poll_mailbox() {
+    unsafe {
+        core::arch::asm!("csrc sip, {}", in(reg) 2);
+    }
+    run_pending_calls();
+}
+
+static SELF_TEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn count_call(expected_arg: usize) {
+    assert_eq!(expected_arg, 42, "ipi self test: wrong argument");
+    SELF_TEST_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Check that every hart that's online runs a cross-call, called by the boot hart once interrupts are on
+pub fn self_test() {
+    let ran_on = run_on_all(count_call, 42);
+    assert_eq!(
+        SELF_TEST_COUNT.load(Ordering::Relaxed),
+        ran_on,
+        "ipi self test: not every hart ran the call"
+    );
+    println!("IPI self test OK ({ran_on} harts)");
+}
@@ -4,26 +4,33 @@
 // kernel stacks, page-table pages, and pipe buffers.
 // We'll see what those are later but for now, let's focus on the memory allocation.
 
-use core::{
-    alloc::GlobalAlloc,
-    ptr::{addr_of_mut, null_mut},
-};
+use core::{alloc::GlobalAlloc, ptr::null_mut};
 
-use crate::{consts::PHYS_STOP, println, spinlock::Spinlock};
+use crate::{
+    mcslock::McsLock,
+    memlayout::{phys_to_virt, virt_to_phys},
+    platform::platform,
+    println, reclaim,
+};
 
 // This is the size of each page in memory
 pub const PAGE_SIZE: usize = 4096;
 
-// This is the maximum virtual address that we can allocate memory to
-pub const MAX_VIRTUAL_ADDRESS: usize = 1 << (9 + 9 + 9 + 12 - 1); // 2^(9+9+9+12-1) = 2^39
-
 extern "C" {
     static kernel_end: u8;
 }
 
+// We hand out pages by their address in the direct map (see memlayout.rs), not the kernel's own addresses.
+// So this is where the kernel ends in the direct map, kernel_end itself is up where the kernel is linked
 #[inline]
 fn g_kernel_end() -> usize {
-    unsafe { &kernel_end as *const u8 as usize }
+    phys_to_virt(virt_to_phys(unsafe { &kernel_end as *const u8 as usize }))
+}
+
+// The end of physical memory in the direct map, this comes from the device tree now (see platform.rs)
+#[inline]
+fn phys_stop() -> usize {
+    phys_to_virt(platform().memory_end)
 }
 
 #[repr(transparent)]
@@ -32,38 +39,58 @@ struct Run {
 }
 
 struct KernelMemory {
-    lock: Option<Spinlock>,
     free: Option<*mut Run>,
 }
 
-static mut KERNEL_MEMORY: KernelMemory = KernelMemory {
-    lock: None,
-    free: None,
-};
+// Safety: the pages on the free list don't belong to any hart, whoever holds the lock can hand them out
+unsafe impl Send for KernelMemory {}
 
-// Initialize the kernel memory allocator's spinlock and
-// free list of memory chunks
+// Every hart allocating pages fights over this one, so it's an MCS lock to keep things fair
+static KERNEL_MEMORY: McsLock<KernelMemory> = McsLock::new("kmem", KernelMemory { free: None });
+
+// Initialize the kernel memory allocator's free list of memory chunks
 pub fn kinit() {
-    unsafe {
-        let lock = &mut KERNEL_MEMORY.lock;
-        Spinlock::init(lock);
-    }
     // Free all memory from the end of the kernel to the end of physical memory
     // This takes care of setting up all pages of memory to be free
     // Then we have them available in KernelMemory::free which is a linked list of free pages
     let end = g_kernel_end();
-    free_range(end, PHYS_STOP);
+    let platform = platform();
+    // QEMU puts the device tree somewhere in RAM (usually near the end), and firmware can have bits of RAM
+    // it wants left alone (see platform.rs). We don't want to hand any of those pages out so we skip over them
+    let skip_to = |page: usize| {
+        let pa = virt_to_phys(page);
+        if pa < platform.dtb_end && platform.dtb_start < pa + PAGE_SIZE {
+            Some(platform.dtb_end)
+        } else {
+            platform.reserved_end(pa, pa + PAGE_SIZE)
+        }
+    };
+    let mut start = end;
+    let mut page = get_page_round_up(end);
+    while page < phys_stop() {
+        match skip_to(page) {
+            Some(skip_end) => {
+                if page > start {
+                    free_range(start, page);
+                }
+                page = get_page_round_up(phys_to_virt(skip_end)).min(phys_stop());
+                start = page;
+            }
+            None => page += PAGE_SIZE,
+        }
+    }
+    free_range(start, phys_stop());
 }
 
 #[inline]
 /// Given a size of memory, get the next page size up (e.g. 4097 -> 8192, 4096 -> 4096, 4 -> 4096)
-const fn get_page_round_up(n: usize) -> usize {
+pub const fn get_page_round_up(n: usize) -> usize {
     (n + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
 }
 
 #[inline]
 /// Given a size of memory, get the next page size down (e.g. 4097 -> 4096, 4096 -> 4096, 4 -> 0)
-const fn get_page_round_down(n: usize) -> usize {
+pub const fn get_page_round_down(n: usize) -> usize {
     n & !(PAGE_SIZE - 1)
 }
 
@@ -86,7 +113,7 @@ fn free_range(page_start: usize, page_end: usize) {
 // Utility to set the memory at a given address from start to start + size to a given value
 pub fn set_memory(start: *mut u8, size: usize, value: u8) -> *mut u8 {
     //println!("set_memory: {:#x}: +{:#x} to {:#x}", start as usize, size, value);
-    if (start as usize) < g_kernel_end() || (start as usize) >= PHYS_STOP {
+    if (start as usize) < g_kernel_end() || (start as usize) >= phys_stop() {
         panic!("set_mem");
     }
 
@@ -108,7 +135,7 @@ pub fn free_page(page: *mut u8) {
     // 1. The page number is a multiple of the page size
     // 2. The page number is greater than the end of the kernel memory (otherwise we're freeing kernel memory)
     // 3. The page number is less than the end of physical memory (otherwise we're freeing memory we don't have)
-    if page_num % PAGE_SIZE != 0 || page_num < g_kernel_end() || page_num >= PHYS_STOP {
+    if page_num % PAGE_SIZE != 0 || page_num < g_kernel_end() || page_num >= phys_stop() {
         panic!("free_page");
     }
 
@@ -122,37 +149,39 @@ pub fn free_page(page: *mut u8) {
     // Now we set the run to point to the page
     run = page as *mut Run;
 
+    // Now we lock the kernel memory allocator's spinlock and
+    // add the page to the free list, setting the next page to the current free list's head
+    let mut memory = KERNEL_MEMORY.lock();
     unsafe {
-        // Now we lock the kernel memory allocator's spinlock and
-        // add the page to the free list, setting the next page to the current free list's head
-        let lock = &mut KERNEL_MEMORY.lock;
-        let guard = Spinlock::acquire((*lock).as_mut());
-        (*run).next = KERNEL_MEMORY.free;
-        KERNEL_MEMORY.free = Some(run);
-        drop(guard);
+        (*run).next = memory.free;
     }
+    memory.free = Some(run);
+    drop(memory);
 }
 
 // Allocate a new page of memory
-// this will return a pointer to the newly allocated page
+// this will return a pointer to the newly allocated page, in the direct map.
+// Page table entries and devices want its physical address, see memlayout::virt_to_phys
 pub fn allocate_page() -> Option<*mut u8> {
     // We need to pop the head off the free list,
     // so we lock the kernel memory allocator's spinlock
     // Grab the head of the free list and replace it with the next page
     // If there is no head, we're out of memory
-    unsafe {
-        let lock = addr_of_mut!(KERNEL_MEMORY.lock);
-        let guard = Spinlock::acquire((*lock).as_mut());
-        let run = KERNEL_MEMORY.free.take();
+    // If we're out, the reclaimer might be able to take a page back from someone (see reclaim.rs)
+    loop {
+        let mut memory = KERNEL_MEMORY.lock();
+        let run = memory.free.take();
         if let Some(run) = run {
             let page = run as *mut u8;
-            KERNEL_MEMORY.free = (*run).next;
-            drop(guard);
+            memory.free = unsafe { (*run).next };
+            drop(memory);
             set_memory(page, PAGE_SIZE, 0);
-            Some(page)
-        } else {
+            return Some(page);
+        }
+        drop(memory);
+        if !reclaim::reclaim_one() {
             println!("boo-womp no more pages");
-            None
+            return None;
         }
     }
 }
@@ -0,0 +1,268 @@
+// Every hart's kernel stack, and catching a hart running off the end of it.
+// entry.S points each hart's sp at the top of a boot stack before any Rust runs, so those have to be
+// there before we can allocate anything. They're one small static the linker sets aside in .bss.stack
+// (see linker.ld), each hart gets a slot in it. They're only for getting the kernel page table up though:
+// once it is, `init` gives every hart a real stack in the stack region (see memlayout.rs) and each hart
+// moves onto it with `switch_to_own_stack` before it takes any traps. Those are made of pages from kalloc,
+// and how big they are is up to the kernel command line (see platform.rs), so running out of stack
+// doesn't mean rebuilding the kernel.
+
+// Stacks grow down, and used to sit right on top of each other, so a hart that used too much stack
+// would quietly scribble over the next hart's. Now there are unmapped pages under every stack:
+// running into them is a page fault, which kernel_trap turns into a "stack overflow on hart N" panic
+// instead of a mystery crash somewhere else later. For the boot stacks that's a guard page kvm_make leaves
+// out of the kernel page table.
+
+// There's a catch: when a trap happens kernelvec.S saves registers onto the stack, and if the stack is
+// what overflowed that faults too, over and over. So kernelvec checks sp against the bottom of this
+// hart's stack (kept in sscratch) first, and if it's run out it switches to a small spare stack and calls
+// stack_overflow below.
+
+// Processes get kernel stacks the same way the harts do. The stack region is split into slots big enough
+// for the biggest stack we allow, and a stack goes at the top of its slot. Only the stack pages are ever
+// mapped, so everything under them is just a hole in the page table.
+// A process running on one of these needs sscratch set to its bottom, like a hart's.
+
+use core::{
+    ptr::addr_of,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    consts::{MAX_KERNEL_STACK_PAGES, NUM_CPUS},
+    cpu::Cpu,
+    kalloc::{allocate_page, free_page, PAGE_SIZE},
+    memlayout::{virt_to_phys, KSTACK_SIZE, KSTACK_START},
+    platform::platform,
+    println,
+    spinlock::Spinlock,
+    vm::{self, VmError},
+};
+
+// How much stack each hart gets to boot on, there's not much going on before they have their real ones
+pub const BOOT_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+// Each hart's boot slot: a guard page, then its boot stack on top
+pub const BOOT_SLOT_SIZE: usize = PAGE_SIZE + BOOT_STACK_SIZE;
+
+// Where kernelvec goes once a stack has overflowed, there just needs to be enough here to panic
+pub const OVERFLOW_STACK_SIZE: usize = 2 * PAGE_SIZE;
+
+// Page aligned so the guard pages line up with real pages
+#[repr(C, align(4096))]
+pub struct BootStacks([[u8; BOOT_SLOT_SIZE]; NUM_CPUS]);
+
+#[link_section = ".bss.stack"]
+pub static mut BOOT_STACKS: BootStacks = BootStacks([[0; BOOT_SLOT_SIZE]; NUM_CPUS]);
+
+#[repr(C, align(16))]
+pub struct OverflowStacks([[u8; OVERFLOW_STACK_SIZE]; NUM_CPUS]);
+
+#[link_section = ".bss.stack"]
+pub static mut OVERFLOW_STACKS: OverflowStacks =
+    OverflowStacks([[0; OVERFLOW_STACK_SIZE]; NUM_CPUS]);
+
+fn boot_stacks_start() -> usize {
+    unsafe { addr_of!(BOOT_STACKS) as usize }
+}
+
+// The guard page under `hart`'s boot stack
+pub fn boot_guard_page(hart: usize) -> usize {
+    boot_stacks_start() + hart * BOOT_SLOT_SIZE
+}
+
+// How many pages of stack everyone gets, set with `kstack_pages=N` on the kernel command line
+pub fn stack_pages() -> usize {
+    platform().kernel_stack_pages
+}
+
+pub fn stack_size() -> usize {
+    stack_pages() * PAGE_SIZE
+}
+
+// Each slot in the stack region has room for the biggest stack and a guard page under it
+pub const SLOT_SIZE: usize = (1 + MAX_KERNEL_STACK_PAGES) * PAGE_SIZE;
+
+// How many stacks fit in the stack region
+const SLOTS: usize = KSTACK_SIZE / SLOT_SIZE;
+
+// Bit N is set when stack slot N is taken
+static TAKEN_SLOTS: Spinlock<[u64; SLOTS.div_ceil(64)]> =
+    Spinlock::new("kstack", [0; SLOTS.div_ceil(64)]);
+
+// Which slot each hart's stack is in, NO_STACK until `init` has run
+const NO_STACK: usize = usize::MAX;
+static HART_SLOTS: [AtomicUsize; NUM_CPUS] = [const { AtomicUsize::new(NO_STACK) }; NUM_CPUS];
+
+// Where sp starts for the stack in `slot`, stacks grow down
+fn slot_top(slot: usize) -> usize {
+    KSTACK_START + (slot + 1) * SLOT_SIZE
+}
+
+// If `address` is in the unmapped part of a slot under its stack, which slot that is
+fn slot_below_stack(address: usize) -> Option<usize> {
+    let offset = address.checked_sub(KSTACK_START)?;
+    let slot = offset / SLOT_SIZE;
+    (slot < SLOTS && offset % SLOT_SIZE < SLOT_SIZE - stack_size()).then_some(slot)
+}
+
+// Give every hart a stack of its own. Called once by the boot hart when the kernel page table is up,
+// before the other harts are started
+pub fn init() {
+    let present = platform().hart_mask;
+    for hart in (0..NUM_CPUS).filter(|&hart| present & (1 << hart) != 0) {
+        let stack = KernelStack::allocate().expect("kstack: no memory for the hart stacks");
+        HART_SLOTS[hart].store(stack.slot, Ordering::Release);
+    }
+    println!("Kernel stacks are {}KiB", stack_size() / 1024);
+}
+
+// Move this hart off its boot stack and onto the one `init` gave it, and carry on in `next`.
+// Nothing on the boot stack survives this, so `next` starts from scratch.
+// This hart has to be using the kernel page table already, the boot one doesn't have the stack region
+pub fn switch_to_own_stack(next: fn() -> !) -> ! {
+    let slot = HART_SLOTS[Cpu::get_id()].load(Ordering::Acquire);
+    assert!(
+        slot != NO_STACK,
+        "switch_to_own_stack: this hart has no stack"
+    );
+    unsafe {
+        core::arch::asm!(
+            "mv sp, {top}",
+            "jr {next}",
+            top = in(reg) slot_top(slot),
+            next = in(reg) next,
+            options(noreturn)
+        );
+    }
+}
+
+// The lowest address `hart`'s stack can use, what goes in sscratch
+pub fn stack_bottom(hart: usize) -> usize {
+    slot_top(HART_SLOTS[hart].load(Ordering::Acquire)) - stack_size()
+}
+
+// If `address` is under one of the harts' stacks (boot or real), whose it is
+pub fn guard_page_owner(address: usize) -> Option<usize> {
+    if let Some(offset) = address.checked_sub(boot_stacks_start()) {
+        let hart = offset / BOOT_SLOT_SIZE;
+        if hart < NUM_CPUS && offset % BOOT_SLOT_SIZE < PAGE_SIZE {
+            return Some(hart);
+        }
+    }
+    let slot = slot_below_stack(address)?;
+    (0..NUM_CPUS).find(|&hart| HART_SLOTS[hart].load(Ordering::Relaxed) == slot)
+}
+
+// A process's kernel stack, give it back with `free`
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    // Map a new stack into a free slot, None if we're out of memory or slots
+    pub fn allocate() -> Option<Self> {
+        let slot = {
+            let mut slots = TAKEN_SLOTS.lock();
+            let slot = (0..SLOTS).find(|&slot| slots[slot / 64] & (1 << (slot % 64)) == 0)?;
+            slots[slot / 64] |= 1 << (slot % 64);
+            slot
+        };
+        let stack = Self { slot };
+        // The slot's lock is dropped while we map, allocating a page can end up waiting on other harts
+        for i in 0..stack_pages() {
+            let mapped = allocate_page()
+                .ok_or(VmError::OutOfMemory)
+                .and_then(|page| {
+                    vm::kvm_map_pages(
+                        stack.bottom() + i * PAGE_SIZE,
+                        PAGE_SIZE,
+                        virt_to_phys(page as usize),
+                        vm::PTE_READ | vm::PTE_WRITE,
+                    )
+                    .inspect_err(|_| free_page(page))
+                });
+            if mapped.is_err() {
+                if i > 0 {
+                    vm::kvm_unmap(stack.bottom(), i, true)
+                        .expect("KernelStack: unmapping after running out");
+                }
+                release_slot(slot);
+                return None;
+            }
+        }
+        Some(stack)
+    }
+
+    // The page right under the stack, the first one that faults when it runs out
+    fn guard(&self) -> usize {
+        self.bottom() - PAGE_SIZE
+    }
+
+    // The lowest address the stack can use, what goes in sscratch while it's in use
+    pub fn bottom(&self) -> usize {
+        self.top() - stack_size()
+    }
+
+    // Where sp starts, stacks grow down
+    pub fn top(&self) -> usize {
+        slot_top(self.slot)
+    }
+
+    // Unmap the stack and give its pages and slot back. Nobody can be running on it
+    pub fn free(self) {
+        vm::kvm_unmap(self.bottom(), stack_pages(), true).expect("KernelStack: unmap");
+        release_slot(self.slot);
+    }
+}
+
+fn release_slot(slot: usize) {
+    TAKEN_SLOTS.lock()[slot / 64] &= !(1 << (slot % 64));
+}
+
+// If `address` is under a process stack, which slot that stack's in
+pub fn process_guard_page_owner(address: usize) -> Option<usize> {
+    slot_below_stack(address)
+}
+
+// Hand out a couple of process stacks, use every page of them, check their guard pages are holes,
+// then give them back. Called once by the boot hart with interrupts on
+pub fn self_test() {
+    let stacks = [0, 1].map(|_| KernelStack::allocate().expect("kstack self test: no stack"));
+    for (i, stack) in stacks.iter().enumerate() {
+        for page in (stack.bottom()..stack.top()).step_by(PAGE_SIZE) {
+            unsafe { (page as *mut usize).write_volatile(i) };
+        }
+        assert!(
+            vm::kvm_translate(stack.guard()).is_none(),
+            "kstack self test: guard page is mapped"
+        );
+        assert_eq!(
+            process_guard_page_owner(stack.guard()),
+            Some(stack.slot),
+            "kstack self test: guard page has the wrong owner"
+        );
+    }
+    for (i, stack) in stacks.iter().enumerate() {
+        let seen = unsafe { ((stack.top() - PAGE_SIZE) as *const usize).read_volatile() };
+        assert_eq!(seen, i, "kstack self test: stacks overlap");
+    }
+    let bottom = stacks[0].bottom();
+    for stack in stacks {
+        stack.free();
+    }
+    assert!(
+        vm::kvm_translate(bottom).is_none(),
+        "kstack self test: free left the stack mapped"
+    );
+    println!(
+        "Process kernel stack self test OK ({SLOTS} slots of {}KiB)",
+        stack_size() / 1024
+    );
+}
+
+// kernelvec.S calls this on the spare stack when a trap comes in and there's no room left on the real one
+#[no_mangle]
+pub extern "C" fn stack_overflow() -> ! {
+    panic!("stack overflow on hart {}", Cpu::get_id());
+}
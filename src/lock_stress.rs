@@ -0,0 +1,153 @@
+// Stress tests for our locks, run on every hart at once when built with the `lock-stress` feature.
+// These only mean something with more than one hart, so run them with `just qemu-stress`
+// (which uses QEMU's `-smp`). Every hart hammers the same locks, mostly reading and sometimes writing,
+// and checks it never sees the data half changed. If a lock is broken one of the asserts will panic.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    clock, cpu::Cpu, mcslock::McsLock, platform::platform, println, rwlock::RwSpinlock,
+    seqlock::SeqLock, spinlock::Spinlock, ticketlock::TicketLock,
+};
+
+// How many times each hart goes round each test
+const ITERATIONS: usize = 20_000;
+
+// Every how many iterations a hart writes instead of reads
+const WRITE_EVERY: usize = 8;
+
+// How long we wait for every hart to show up (in case some didn't start), and then to finish
+const START_TIMEOUT_NANOS: u64 = 1_000_000_000;
+const FINISH_TIMEOUT_NANOS: u64 = 30_000_000_000;
+
+// Writers set every slot to the same value, so readers should never see two different values
+static RW_DATA: RwSpinlock<[u64; 8]> = RwSpinlock::new("stress_rw", [0; 8]);
+
+// Writers keep the second number as the first one flipped
+static SEQ_DATA: SeqLock<(u64, u64)> = SeqLock::new("stress_seq", (0, !0));
+
+// Every hart adds to these, if the lock works they'll add up exactly
+static SPIN_COUNT: Spinlock<u64> = Spinlock::new("stress_spin", 0);
+static TICKET_COUNT: TicketLock<u64> = TicketLock::new("stress_ticket", 0);
+static MCS_COUNT: McsLock<u64> = McsLock::new("stress_mcs", 0);
+
+static ARRIVED: AtomicUsize = AtomicUsize::new(0);
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+static RW_WRITES: AtomicU64 = AtomicU64::new(0);
+static SEQ_WRITES: AtomicU64 = AtomicU64::new(0);
+
+// Wait until every hart has gotten here (or we give up waiting), returns how many did
+fn barrier(counter: &AtomicUsize, timeout_nanos: u64) -> usize {
+    counter.fetch_add(1, Ordering::AcqRel);
+    let expected = platform().num_harts;
+    let give_up = clock::now() + clock::nanos_to_cycles(timeout_nanos);
+    loop {
+        let arrived = counter.load(Ordering::Acquire);
+        if arrived >= expected || clock::now() >= give_up {
+            return arrived;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn stress_rwlock(hart: usize) {
+    for i in 0..ITERATIONS {
+        if i % WRITE_EVERY == hart % WRITE_EVERY {
+            let mut data = RW_DATA.write();
+            let value = data[0] + 1;
+            for slot in data.iter_mut() {
+                *slot = value;
+            }
+            RW_WRITES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let data = RW_DATA.read();
+            assert!(
+                data.iter().all(|&slot| slot == data[0]),
+                "rwlock stress: reader saw a half finished write"
+            );
+        }
+    }
+}
+
+fn stress_seqlock(hart: usize) {
+    for i in 0..ITERATIONS {
+        if i % WRITE_EVERY == hart % WRITE_EVERY {
+            SEQ_DATA.write(|(value, flipped)| {
+                *value += 1;
+                *flipped = !*value;
+            });
+            SEQ_WRITES.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let (value, flipped) = SEQ_DATA.read();
+            assert_eq!(
+                flipped, !value,
+                "seqlock stress: reader saw a half finished write"
+            );
+        }
+    }
+}
+
+fn stress_counters() {
+    for _ in 0..ITERATIONS {
+        *SPIN_COUNT.lock() += 1;
+        *TICKET_COUNT.lock() += 1;
+        *MCS_COUNT.lock() += 1;
+    }
+}
+
+// Called by every hart from main, with interrupts on
+pub fn run() {
+    // Nothing else can move us to another hart, so this is fine with interrupts on
+    let hart = Cpu::get_id();
+    let is_boot_hart = hart == platform().boot_hart;
+    let harts = barrier(&ARRIVED, START_TIMEOUT_NANOS);
+    if is_boot_hart {
+        println!("Lock stress test: {harts} harts, {ITERATIONS} iterations each");
+    }
+    let start = clock::now();
+
+    stress_rwlock(hart);
+    stress_seqlock(hart);
+    stress_counters();
+
+    let finished = barrier(&FINISHED, FINISH_TIMEOUT_NANOS);
+    if !is_boot_hart {
+        return;
+    }
+    if finished < harts {
+        println!(
+            "Lock stress test: only {finished} of {harts} harts finished in time, skipping totals"
+        );
+        return;
+    }
+    let expected = (harts * ITERATIONS) as u64;
+    assert_eq!(
+        *SPIN_COUNT.lock(),
+        expected,
+        "spinlock stress: lost an update"
+    );
+    assert_eq!(
+        *TICKET_COUNT.lock(),
+        expected,
+        "ticket lock stress: lost an update"
+    );
+    assert_eq!(
+        *MCS_COUNT.lock(),
+        expected,
+        "mcs lock stress: lost an update"
+    );
+    assert_eq!(
+        RW_DATA.read()[0],
+        RW_WRITES.load(Ordering::Relaxed),
+        "rwlock stress: lost a write"
+    );
+    assert_eq!(
+        SEQ_DATA.read().0,
+        SEQ_WRITES.load(Ordering::Relaxed),
+        "seqlock stress: lost a write"
+    );
+    println!(
+        "Lock stress test OK in {}ms",
+        clock::cycles_to_nanos(clock::now() - start) / 1_000_000
+    );
+}
@@ -0,0 +1,293 @@
+// Lockdep: catching deadlocks before they happen.
+// The classic deadlock is hart 1 taking lock A then B, while hart 2 takes B then A. If they both get
+// their first lock at the same time they wait on each other forever. This only happens when the timing
+// is just wrong, so it can hide for a long time. But the *ordering* problem is there every time,
+// so if we remember which locks were taken while holding which, we can spot it the first time both
+// orders show up, even if the harts never actually got stuck.
+
+// With the `lockdep` feature (without it this module isn't built at all), every lock belongs to a "class", which is just its name, so
+// e.g. every inode lock would be one class. Each hart keeps a list of the classes it's holding, and
+// whenever it takes a lock of class B while holding A we remember the edge A -> B in a graph. If B can
+// already reach A in the graph, those two orders together could deadlock, so we print both chains
+// of locks that led to it and panic.
+
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    print, println,
+    println::PRINTLN_LOCK,
+    spinlock::{disable_interrupts, RawLock, TestAndSet},
+};
+
+// The most lock classes we can tell apart, the graph is one u64 bitmask per class
+const MAX_CLASSES: usize = 64;
+
+// The most locks a single hart can hold at once that we keep track of
+const MAX_HELD: usize = 16;
+
+const NO_CLASS: usize = usize::MAX;
+
+// Each lock caches which class it is, so we only look its name up the first time
+pub struct LockClass {
+    id: AtomicUsize,
+}
+
+impl LockClass {
+    pub const fn new() -> Self {
+        Self {
+            id: AtomicUsize::new(NO_CLASS),
+        }
+    }
+}
+
+// The locks a hart took, in the order it took them
+#[derive(Clone, Copy)]
+struct Chain {
+    classes: [u8; MAX_HELD],
+    len: usize,
+    hart: usize,
+}
+
+impl Chain {
+    const fn empty() -> Self {
+        Self {
+            classes: [0; MAX_HELD],
+            len: 0,
+            hart: 0,
+        }
+    }
+}
+
+// Turned off if something goes wrong (like running out of classes), so we don't report nonsense
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+// What each hart is holding right now, only ever touched by that hart with interrupts off
+static mut HELD: [Chain; NUM_CPUS] = [Chain::empty(); NUM_CPUS];
+
+// DEPENDS[a] has bit b set if we've seen class b taken while holding class a
+// It's atomic so the common case (an edge we've already seen) doesn't need GRAPH_LOCK
+static DEPENDS: [AtomicU64; MAX_CLASSES] = [const { AtomicU64::new(0) }; MAX_CLASSES];
+
+// Everything below here is protected by GRAPH_LOCK. This has to be a raw lock, if it was a
+// Spinlock taking it would call back into lockdep
+static GRAPH_LOCK: TestAndSet = TestAndSet::UNLOCKED;
+static mut CLASS_NAMES: [&str; MAX_CLASSES] = [""; MAX_CLASSES];
+static mut NUM_CLASSES: usize = 0;
+// For every edge a -> b, the chain of locks held when we first saw it
+static mut FIRST_SEEN: [[Chain; MAX_CLASSES]; MAX_CLASSES] =
+    [[Chain::empty(); MAX_CLASSES]; MAX_CLASSES];
+
+// WARNING: Must be called with GRAPH_LOCK held
+// Find (or make) the class for a lock name
+unsafe fn class_of(class: &LockClass, name: &'static str) -> Option<usize> {
+    let id = class.id.load(Ordering::Relaxed);
+    if id != NO_CLASS {
+        return Some(id);
+    }
+    let names = &mut *addr_of_mut!(CLASS_NAMES);
+    let count = &mut *addr_of_mut!(NUM_CLASSES);
+    let id = match names[..*count].iter().position(|&n| n == name) {
+        Some(id) => id,
+        None if *count < MAX_CLASSES => {
+            names[*count] = name;
+            *count += 1;
+            *count - 1
+        }
+        None => return None,
+    };
+    class.id.store(id, Ordering::Relaxed);
+    Some(id)
+}
+
+// WARNING: Must be called with GRAPH_LOCK held
+fn class_name(id: u8) -> &'static str {
+    unsafe { (*addr_of!(CLASS_NAMES))[id as usize] }
+}
+
+fn print_chain(chain: &Chain, then: Option<u8>) {
+    print!("    hart {}:", chain.hart);
+    for &class in &chain.classes[..chain.len] {
+        print!(" {} ->", class_name(class));
+    }
+    match then {
+        Some(class) => println!(" {}", class_name(class)),
+        None => println!(" (end)"),
+    }
+}
+
+// WARNING: Must be called with GRAPH_LOCK held
+// Find a path from `from` to `to` through the graph, returns how many classes are on it
+fn find_path(from: usize, to: usize, path: &mut [u8; MAX_CLASSES]) -> Option<usize> {
+    // Breadth first, remembering how we got to each class
+    let mut came_from = [NO_CLASS; MAX_CLASSES];
+    let mut queue = [0; MAX_CLASSES];
+    let (mut head, mut tail) = (0, 1);
+    queue[0] = from;
+    came_from[from] = from;
+    while head < tail {
+        let class = queue[head];
+        head += 1;
+        if class == to {
+            // Walk back to the start, then flip it around
+            let mut len = 0;
+            let mut current = to;
+            while current != from {
+                path[len] = current as u8;
+                len += 1;
+                current = came_from[current];
+            }
+            path[len] = from as u8;
+            len += 1;
+            path[..len].reverse();
+            return Some(len);
+        }
+        let edges = DEPENDS[class].load(Ordering::Relaxed);
+        for next in 0..MAX_CLASSES {
+            if edges & (1 << next) != 0 && came_from[next] == NO_CLASS {
+                came_from[next] = class;
+                queue[tail] = next;
+                tail += 1;
+            }
+        }
+    }
+    None
+}
+
+// WARNING: Must be called with GRAPH_LOCK held
+// We're about to add held -> class, but class can already get back to held
+unsafe fn report(held: &Chain, holding: usize, class: usize) -> ! {
+    // Stop checking, and don't take the println lock, we might be in the middle of taking it
+    ENABLED.store(false, Ordering::Relaxed);
+    PRINTLN_LOCK.locking.store(false, Ordering::Relaxed);
+
+    println!("lockdep: possible deadlock!");
+    println!(
+        "  taking `{}` while holding `{}`:",
+        class_name(class as u8),
+        class_name(holding as u8)
+    );
+    print_chain(held, Some(class as u8));
+    println!(
+        "  but `{}` was taken after `{}` before:",
+        class_name(holding as u8),
+        class_name(class as u8)
+    );
+    let mut path = [0; MAX_CLASSES];
+    let len = find_path(class, holding, &mut path).unwrap_or(0);
+    let first_seen = &*addr_of!(FIRST_SEEN);
+    for step in path[..len].windows(2) {
+        print_chain(
+            &first_seen[step[0] as usize][step[1] as usize],
+            Some(step[1]),
+        );
+    }
+    panic!(
+        "lockdep: `{}` and `{}` taken in both orders",
+        class_name(holding as u8),
+        class_name(class as u8)
+    );
+}
+
+// Called by a lock right before it starts waiting, with interrupts off
+pub fn acquire(class: &LockClass, name: &'static str) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    // Safety: only this hart touches its HELD, and interrupts are off
+    let held = unsafe { &mut (*addr_of_mut!(HELD))[Cpu::get_id()] };
+
+    let mut id = class.id.load(Ordering::Relaxed);
+    // See if there's anything new to add to the graph, usually there isn't
+    let new_edges = id == NO_CLASS
+        || held.classes[..held.len].iter().any(|&h| {
+            h as usize != id && DEPENDS[h as usize].load(Ordering::Relaxed) & (1 << id) == 0
+        });
+
+    if new_edges {
+        GRAPH_LOCK.acquire();
+        unsafe {
+            id = match class_of(class, name) {
+                Some(id) => id,
+                None => {
+                    ENABLED.store(false, Ordering::Relaxed);
+                    GRAPH_LOCK.release();
+                    println!("lockdep: out of lock classes, turning off");
+                    return;
+                }
+            };
+            for &holding in &held.classes[..held.len] {
+                let holding = holding as usize;
+                // Two locks of the same class (like two inodes) need their own rules, we don't check those
+                if holding == id || DEPENDS[holding].load(Ordering::Relaxed) & (1 << id) != 0 {
+                    continue;
+                }
+                let mut path = [0; MAX_CLASSES];
+                if find_path(id, holding, &mut path).is_some() {
+                    report(held, holding, id);
+                }
+                (*addr_of_mut!(FIRST_SEEN))[holding][id] = *held;
+                DEPENDS[holding].fetch_or(1 << id, Ordering::Relaxed);
+            }
+        }
+        GRAPH_LOCK.release();
+    }
+
+    if held.len == MAX_HELD {
+        ENABLED.store(false, Ordering::Relaxed);
+        println!("lockdep: holding too many locks, turning off");
+        return;
+    }
+    held.classes[held.len] = id as u8;
+    held.len += 1;
+    held.hart = Cpu::get_id();
+}
+
+// Called by a lock right after it lets go, with interrupts still off
+pub fn release(class: &LockClass) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let id = class.id.load(Ordering::Relaxed);
+    // Safety: same as acquire
+    let held = unsafe { &mut (*addr_of_mut!(HELD))[Cpu::get_id()] };
+    // Locks don't have to be let go of in order, so find the most recent one of this class
+    if let Some(index) = held.classes[..held.len]
+        .iter()
+        .rposition(|&h| h as usize == id)
+    {
+        held.classes.copy_within(index + 1..held.len, index);
+        held.len -= 1;
+    }
+}
+
+// Print every ordering we've seen, for the `lockdep` monitor command
+pub fn print_graph() {
+    // Interrupts have to be off while we hold GRAPH_LOCK, a lock taken in an interrupt handler would need it too
+    let interrupts = disable_interrupts();
+    GRAPH_LOCK.acquire();
+    let count = unsafe { *addr_of!(NUM_CLASSES) };
+    // Copy the names out so we're not holding GRAPH_LOCK while printing (println takes a lock too)
+    let names = unsafe { *addr_of!(CLASS_NAMES) };
+    GRAPH_LOCK.release();
+    drop(interrupts);
+
+    println!("{count} lock classes");
+    for (from, name) in names[..count].iter().enumerate() {
+        let edges = DEPENDS[from].load(Ordering::Relaxed);
+        if edges == 0 {
+            continue;
+        }
+        print!("  {name} ->");
+        for (to, other) in names[..count].iter().enumerate() {
+            if edges & (1 << to) != 0 {
+                print!(" {other}");
+            }
+        }
+        println!("");
+    }
+}
@@ -0,0 +1,127 @@
+// Lock statistics, so we can find out which locks harts spend their time fighting over.
+// Spinlocks only carry a LockStats when we're built with the `lock-stats` feature,
+// otherwise keeping count would slow every lock down (and make it bigger) for nothing.
+
+// The first time a lock is taken it adds itself to a list here, the `locks` monitor command
+// (see monitor.rs) then goes through the list and prints the most contended ones.
+// Times are in `time` register cycles, the same ones the CLINT counts (see clock.rs).
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::{clock, println};
+
+// The most locks we'll keep track of, any more than this just don't show up
+const MAX_TRACKED_LOCKS: usize = 64;
+
+pub struct LockStats {
+    name: &'static str,
+    // How many times the lock was taken
+    acquisitions: AtomicU64,
+    // How many of those had to wait because someone else had it
+    contended: AtomicU64,
+    // How many times we went round the waiting loop in total
+    spins: AtomicU64,
+    // The longest anyone has held the lock for, and when the current holder took it
+    max_hold: AtomicU64,
+    acquired_at: AtomicU64,
+    // Whether we're in TRACKED yet
+    tracked: AtomicBool,
+}
+
+// Every lock we've seen, null for empty slots
+static TRACKED: [AtomicPtr<LockStats>; MAX_TRACKED_LOCKS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_TRACKED_LOCKS];
+static NUM_TRACKED: AtomicUsize = AtomicUsize::new(0);
+
+impl LockStats {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            acquisitions: AtomicU64::new(0),
+            contended: AtomicU64::new(0),
+            spins: AtomicU64::new(0),
+            max_hold: AtomicU64::new(0),
+            acquired_at: AtomicU64::new(0),
+            tracked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Called with the lock held, right after we got it
+    pub fn acquired(&self, spins: u64) {
+        if !self.tracked.swap(true, Ordering::Relaxed) {
+            let slot = NUM_TRACKED.fetch_add(1, Ordering::Relaxed);
+            if slot < MAX_TRACKED_LOCKS {
+                TRACKED[slot].store(self as *const _ as *mut _, Ordering::Release);
+            }
+        }
+        // We hold the lock so nobody else is writing these, Relaxed is fine
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        if spins > 0 {
+            self.contended.fetch_add(1, Ordering::Relaxed);
+            self.spins.fetch_add(spins, Ordering::Relaxed);
+        }
+        self.acquired_at.store(clock::now(), Ordering::Relaxed);
+    }
+
+    // Called with the lock held, right before we let go of it
+    pub fn released(&self) {
+        let held = clock::now().saturating_sub(self.acquired_at.load(Ordering::Relaxed));
+        self.max_hold.fetch_max(held, Ordering::Relaxed);
+    }
+
+    // Take this lock out of TRACKED, called when the lock is dropped
+    pub fn forget(&self) {
+        if !self.tracked.load(Ordering::Relaxed) {
+            return;
+        }
+        let me = self as *const _ as *mut LockStats;
+        for slot in TRACKED.iter() {
+            let _ = slot.compare_exchange(me, null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+}
+
+// Print the `count` locks with the most contended acquisitions
+pub fn print_most_contended(count: usize) {
+    if !cfg!(feature = "lock-stats") {
+        println!("Lock statistics are off, build with the `lock-stats` feature");
+        return;
+    }
+
+    // Grab everything we're tracking and sort it, most contended first
+    let mut locks: [Option<&LockStats>; MAX_TRACKED_LOCKS] = [None; MAX_TRACKED_LOCKS];
+    for (lock, slot) in locks.iter_mut().zip(TRACKED.iter()) {
+        // Safety: locks take themselves out of TRACKED when they're dropped
+        *lock = unsafe { slot.load(Ordering::Acquire).as_ref() };
+    }
+    locks.sort_unstable_by_key(|lock| {
+        core::cmp::Reverse(lock.map_or(0, |lock| lock.contended.load(Ordering::Relaxed)))
+    });
+
+    let tracked = NUM_TRACKED.load(Ordering::Relaxed);
+    if tracked > MAX_TRACKED_LOCKS {
+        println!("({} locks weren't tracked)", tracked - MAX_TRACKED_LOCKS);
+    }
+    println!(
+        "{:<16} {:>10} {:>10} {:>12} {:>12}",
+        "lock", "acquired", "contended", "spins", "max hold"
+    );
+    for lock in locks.iter().flatten().take(count) {
+        let max_hold = lock.max_hold.load(Ordering::Relaxed);
+        println!(
+            "{:<16} {:>10} {:>10} {:>12} {:>10}us",
+            lock.name,
+            lock.acquisitions.load(Ordering::Relaxed),
+            lock.contended.load(Ordering::Relaxed),
+            lock.spins.load(Ordering::Relaxed),
+            clock::cycles_to_nanos(max_hold) / 1000
+        );
+    }
+}
@@ -16,15 +16,18 @@
 // Needed to use Vec and String, since we have a GlobalAllocator setup in [kalloc.rs] we can use it
 extern crate alloc;
 
-use core::{
-    arch::global_asm,
-    sync::atomic::{AtomicBool, Ordering},
-};
+use core::arch::global_asm;
 
-use consts::NUM_CPUS;
 use cpu::Cpu;
+use spinlock::disable_interrupts;
 use vm::kvm_init_hart;
 
+// Handing out address space IDs, so switching page tables doesn't flush the whole TLB
+mod asid;
+
+// Module for keeping track of time
+mod clock;
+
 // Module for interacting with the console
 mod console;
 
@@ -34,28 +37,105 @@ mod consts;
 // Module for managing the current core
 mod cpu;
 
+// A heap of times each hart is waiting for, used by the timer
+mod deadline;
+
+// Module for parsing the device tree that describes our machine
+mod fdt;
+
+// Bringing harts online at boot, and parking them while we run
+mod hotplug;
+
+// Harts poking each other, and getting each other to run functions
+mod ipi;
+
 // Module for handling memory allocation in user space
 mod kalloc;
 
+// Every hart's and process's kernel stack, with unmapped pages under each one
+mod kstack;
+
+// Catches locks being taken in an order that could deadlock, only built with the `lockdep` feature
+#[cfg(feature = "lockdep")]
+mod lockdep;
+
+// Tests that hammer our locks from every hart at once
+mod lock_stress;
+
+// Module for keeping count of how much locks are fought over
+mod lockstat;
+
+// A fair spinlock where waiters queue up and each spin on their own memory
+mod mcslock;
+
+// Where everything is in the kernel's half of the address space, and getting between physical and virtual addresses
+mod memlayout;
+
+// A tiny command shell on the console for poking at the kernel
+mod monitor;
+
 // Defining our panic handler in this module
 mod panic;
 
+// Data that each hart has its own copy of
+mod percpu;
+
 // Module for handling println! and print! macros
 #[macro_use]
 mod println;
 
+// Module describing the machine we're running on (RAM, harts, device addresses)
+mod platform;
+
+// The interrupt controller that devices' interrupts come through
 mod plic;
 
+// Taking pages back from address spaces when memory runs out
+mod reclaim;
+
+// A lock many readers can hold at once, or one writer
+mod rwlock;
+
+// Module for talking to SBI firmware (OpenSBI) when we boot under it
+#[cfg(feature = "sbi")]
+mod sbi;
+
+// Sleeping until something happens, and waking back up
+mod sched;
+
+// A lock where readers don't take a lock at all, they just try again if a writer got in the way
+mod seqlock;
+
+// Locks that sleep while they wait, for holding across slow things like disk reads
+mod sleeplock;
+
 // Module for handling mutually exclusive spin locks
-#[macro_use]
 mod spinlock;
 
 // Actual entrypoint (bootstrapping code) is in this module
 mod start;
 
+// Writing pages out to a swap disk when memory runs out, and reading them back in
+mod swap;
+
+// Module for handling system calls
+mod syscall;
+
+// A fair spinlock that hands out the lock in the order harts asked for it
+mod ticketlock;
+
 // This defines the setup and handling of machine timer interrupts
 mod timer;
 
+// Kernel timers that call a function at a deadline, and sleeping
+mod timer_wheel;
+
+// Flushing stale translations out of every hart's TLB
+mod tlb;
+
+// Module for handling traps (interrupts and exceptions) in the kernel
+mod trap;
+
 // Module for handling UART communication
 mod uart;
 
@@ -65,7 +145,8 @@ mod virtio;
 // Module for handling Virtual Memory and Page Tables
 mod vm;
 
-static INITIALIZED: AtomicBool = AtomicBool::new(false);
+// Memory that's contiguous in virtual addresses, made from pages from anywhere
+mod vmalloc;
 
 #[no_mangle]
 // Even though this is called main, this isn't actually the start of our program!
@@ -77,39 +158,96 @@ pub fn main() -> ! {
     // this is so we can only have one CPU do initialization of shared resources such
     // as the console and the println! macros
     let cpu_id = Cpu::get_id();
-    if cpu_id == 0 {
+    if cpu_id == platform::platform().boot_hart {
         // If we're the first CPU, we need to initialize our shared resources
         console::init_console();
-        println::init_println();
         // First output to the console! If we get here we're doing good because we can now debug
         // *much* easier
         println!("Kernel booting!");
+        platform::platform().print_summary();
+        #[cfg(feature = "sbi")]
+        sbi::print_info();
         kalloc::kinit();
         vm::kvm_init_base();
+        vm::kvm_self_test();
         vm::kvm_init_hart();
+        asid::init();
         println!("KVM Init");
-
-        println!("CPU 0 Finished Setup!");
-        // Signal to the other CPUs that we're done initializing
-        // This will allow the other CPUs to start
-        INITIALIZED.store(true, Ordering::SeqCst);
+        // We've been on a small boot stack so far, now there's a page table to put real ones in
+        kstack::init();
     } else {
-        // If we're not CPU 0, we're going to be waiting on the sidelines until
-        // CPU 0 finishes initializing, telling us we can start
-        while !INITIALIZED.load(Ordering::SeqCst) {
-            // Wait for CPU 0 to finish initializing
-            // This hint is a special way for the compiler to know we're busy-waiting (spin-locking)
-            // It emits a special instruction that signals to the CPU that we're waiting for something
-            // and the CPU can then so some optimizations to make this more efficient
-            core::hint::spin_loop();
-        }
+        // If we're not the boot CPU (CPU 0, unless SBI firmware picked another), we're going to
+        // be waiting on the sidelines until it finishes initializing and tells us it's our turn
+        // (harts are brought online one at a time, see hotplug.rs)
+        hotplug::wait_for_start();
         // CPU 0 is done and we have access to shared resources using locks
         println!("CPU {} starting", cpu_id);
         kvm_init_hart();
     }
-    // TEMP: Just spin forever for now, we'd want to head into our scheduler from here
+    // Everything from here on runs on this hart's own stack, see kstack.rs.
+    // The boot stack is left behind, so we pick back up in a new function
+    kstack::switch_to_own_stack(main_on_own_stack)
+}
+
+// Where main carries on once this hart is off its boot stack
+fn main_on_own_stack() -> ! {
+    let cpu_id = Cpu::get_id();
+    if cpu_id == platform::platform().boot_hart {
+        trap::init_hart();
+        plic::init();
+        plic::init_hart();
+        timer_wheel::init();
+        reclaim::init();
+        swap::init();
+
+        println!("CPU {} Finished Setup!", cpu_id);
+        // The other CPUs get started once we're online, see below
+    } else {
+        trap::init_hart();
+        plic::init_hart();
+    }
+    // Until we have real processes each hart counts as its own, with its hart ID as the pid (see sched.rs)
+    Cpu::mine_mut(&mut disable_interrupts()).process = Some(cpu_id);
+    // We're all setup to handle traps now, so we can let the timer interrupts in
+    trap::interrupts_on();
+    // Other harts can poke us now
+    hotplug::mark_online();
+    if cpu_id == platform::platform().boot_hart {
+        // Now we're ready, let everyone else start
+        hotplug::start_secondaries();
+        // Make sure kernel timers and sleeping work now that we can get timer interrupts, and that
+        // address spaces don't see each other's pages. Some of them sleep for a while, so like the
+        // stress tests they're off unless asked for
+        if cfg!(feature = "self-test") {
+            timer_wheel::self_test();
+            syscall::self_test();
+            ipi::self_test();
+            vm::address_space_self_test();
+            reclaim::self_test();
+            vmalloc::self_test();
+            kstack::self_test();
+        }
+        if cfg!(feature = "swap-stress") {
+            swap::stress();
+        }
+    }
+    // Every hart joins in on these, so they're off unless asked for
+    if cfg!(feature = "lock-stress") {
+        lock_stress::run();
+    }
+    // The boot hart keeps an eye on the console for monitor commands, it gets woken up when something's typed
+    let is_boot_hart = cpu_id == platform::platform().boot_hart;
+    if is_boot_hart {
+        monitor::init();
+    }
+    // TEMP: Just idle forever for now, we'd want to head into our scheduler from here
     loop {
-        core::hint::spin_loop();
+        if is_boot_hart {
+            monitor::poll();
+        }
+        // If someone ran `cpu_offline` on us, this is where we stop until `cpu_online`
+        hotplug::park_if_asked();
+        Cpu::idle_unless(|| is_boot_hart && monitor::has_input());
     }
 }
 
@@ -121,21 +259,30 @@ pub fn main() -> ! {
 // that you should go look at the second you see include_str!("entry.S") below
 
 // First we need to initialize the stack for each CPU
-// This is going to be a 4KB stack for each CPU
 // The reason we do this is we don't want CPUs to share a stack
 // because then they'll get in each other's way
-// So we create a new slice of 4KB for each CPU
-
-const STACK_PER_CPU: usize = 4096;
-
-static STACK0: [u8; STACK_PER_CPU * NUM_CPUS] = [0; STACK_PER_CPU * NUM_CPUS];
+// So each CPU gets its own slot in kstack::BOOT_STACKS: a guard page, then a small stack to boot on.
+// Once the kernel page table is up every CPU moves to a bigger stack made of allocated pages
+// (see kstack.rs for what the guard pages are for, and how to make the stacks bigger)
 
 // Here we're telling Rust to include the assembly code from entry.S
 // I could have wrote it all in here, but it's easier to follow with syntax highlighting
-// The sym STACK0 is a symbol (hence the `sym` keyword) that we're telling Rust to insert into the assembly code
-// This symbol is the address of the stack we just defined
-// This will get inserted where the {} is in the assembly code
-// Also, we insert the constant STACK_PER_CPU into the assembly code so
-// we have a single place where we can change the stack size
+// The sym BOOT_STACKS is a symbol (hence the `sym` keyword) that we're telling Rust to insert into the assembly code
+// This symbol is the address of the boot stacks
+// This will get inserted where {boot_stacks} is in the assembly code
+// Also, we insert the constant BOOT_SLOT_SIZE into the assembly code so
+// we have a single place where we can change the boot stack size
+// The rest are what entry.S needs to turn paging on and jump up to where the kernel's linked (see memlayout.rs),
+// and for setting up machine mode when we're not under SBI firmware
 
-global_asm!(include_str!("entry.S"), sym STACK0, const STACK_PER_CPU);
+global_asm!(
+    include_str!("entry.S"),
+    boot_stacks = sym kstack::BOOT_STACKS,
+    boot_slot_size = const kstack::BOOT_SLOT_SIZE,
+    boot_table = sym vm::BOOT_PAGE_TABLE,
+    offset = const memlayout::KERNEL_OFFSET as isize,
+    machine_mode = const cfg!(not(feature = "sbi")) as usize,
+    // Every exception but an `ecall` from supervisor mode (bit 9)
+    medeleg = const 0xffff & !(1 << 9),
+    scratch = sym timer::TIMER_SCRATCH,
+);
@@ -0,0 +1,128 @@
+// An MCS lock (named after Mellor-Crummey and Scott, who came up with it) is a queue of waiting harts.
+// Each hart that wants the lock adds a "node" to the end of the queue, then spins on a flag in
+// its *own* node. When the holder is done it flips the flag in the next node in line.
+// Like a ticket lock it's fair, but each waiter is spinning on its own memory, so a release only
+// has to bother one hart's cache instead of all of them. This matters more the more harts there are.
+
+// Normally the node lives on the stack of whoever is taking the lock, but our guards get moved around
+// (they're returned from Spinlock::lock) so instead each hart has a few nodes of its own to use.
+// Interrupts are off while we hold a spinlock, so a hart can only be holding a few MCS locks at once.
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    spinlock::{RawLock, Spinlock},
+};
+
+// A Spinlock that queues its waiters, use it like any other Spinlock
+pub type McsLock<T> = Spinlock<T, Mcs>;
+
+// How many MCS locks a single hart can hold at the same time
+const NODES_PER_HART: usize = 4;
+
+struct McsNode {
+    // The hart waiting after us, null if nobody is (yet)
+    next: AtomicPtr<McsNode>,
+    // Set while we're waiting, the hart before us clears it to hand us the lock
+    waiting: AtomicBool,
+}
+
+static NODES: [[McsNode; NODES_PER_HART]; NUM_CPUS] = [const {
+    [const {
+        McsNode {
+            next: AtomicPtr::new(null_mut()),
+            waiting: AtomicBool::new(false),
+        }
+    }; NODES_PER_HART]
+}; NUM_CPUS];
+
+// Which of each hart's nodes are in use, one bit per node
+// Only the hart itself touches its own entry (with interrupts off), it's atomic so it can be a plain static
+static NODES_IN_USE: [AtomicU8; NUM_CPUS] = [const { AtomicU8::new(0) }; NUM_CPUS];
+
+pub struct Mcs {
+    // The last node in the queue, null when nobody has the lock
+    tail: AtomicPtr<McsNode>,
+    // Which node (hart * NODES_PER_HART + index) the holder used, so release can find it
+    holder: AtomicUsize,
+}
+
+impl RawLock for Mcs {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = Mcs {
+        tail: AtomicPtr::new(null_mut()),
+        holder: AtomicUsize::new(0),
+    };
+
+    fn acquire(&self) -> u64 {
+        // Grab one of our free nodes
+        let hart = Cpu::get_id();
+        let in_use = NODES_IN_USE[hart].load(Ordering::Relaxed);
+        let index = in_use.trailing_ones() as usize;
+        if index >= NODES_PER_HART {
+            panic!("mcs: too many nested MCS locks");
+        }
+        NODES_IN_USE[hart].store(in_use | (1 << index), Ordering::Relaxed);
+        let node = &NODES[hart][index];
+        node.next.store(null_mut(), Ordering::Relaxed);
+        node.waiting.store(true, Ordering::Relaxed);
+
+        // Put ourselves at the end of the queue
+        let me = node as *const McsNode as *mut McsNode;
+        let previous = self.tail.swap(me, Ordering::AcqRel);
+        let mut spins = 0;
+        if !previous.is_null() {
+            // Someone's ahead of us, tell them we're next and wait for them to hand the lock over
+            // Safety: nodes are statics, they never go away
+            unsafe { (*previous).next.store(me, Ordering::Release) };
+            while node.waiting.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+                spins += 1;
+            }
+        }
+        self.holder
+            .store(hart * NODES_PER_HART + index, Ordering::Relaxed);
+        spins
+    }
+
+    fn release(&self) {
+        let holder = self.holder.load(Ordering::Relaxed);
+        let (hart, index) = (holder / NODES_PER_HART, holder % NODES_PER_HART);
+        let node = &NODES[hart][index];
+        let me = node as *const McsNode as *mut McsNode;
+
+        let mut next = node.next.load(Ordering::Acquire);
+        if next.is_null() {
+            // Nobody's told us they're waiting, if we're still the tail there really is nobody and we're done
+            if self
+                .tail
+                .compare_exchange(me, null_mut(), Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                NODES_IN_USE[hart].fetch_and(!(1 << index), Ordering::Relaxed);
+                return;
+            }
+            // Someone has swapped themselves in as the tail but hasn't linked up to us yet, wait for them
+            loop {
+                next = node.next.load(Ordering::Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+        // Hand the lock to the next in line
+        // Safety: nodes are statics, they never go away
+        unsafe { (*next).waiting.store(false, Ordering::Release) };
+        NODES_IN_USE[hart].fetch_and(!(1 << index), Ordering::Relaxed);
+    }
+
+    fn is_locked(&self) -> bool {
+        !self.tail.load(Ordering::Relaxed).is_null()
+    }
+}
@@ -0,0 +1,48 @@
+// Where everything lives in the kernel's half of the virtual address space.
+// Virtual addresses are 64 bits, but only the bottom 39 (Sv39) are really used and every bit above those
+// has to be a copy of the top one. So there are two halves with a huge hole in between: the bottom one
+// starting at 0, and the top one ending at 0xffff_ffff_ffff_ffff. Processes get the bottom half (see
+// AddressSpace in vm.rs), and the kernel lives up at the top:
+//
+//   0xffff_ffc0_0000_0000  The direct map: physical address `pa` is at DIRECT_MAP_BASE + pa, for all of
+//                          RAM and the devices. Pages from kalloc are handed out by their address in here
+//   0xffff_ffe0_0000_0000  vmalloc: things that need to be contiguous, made of pages from anywhere (vmalloc.rs)
+//   0xffff_ffe0_4000_0000  Kernel stacks for processes, each with a guard page under it (kstack.rs)
+//   0xffff_ffff_8000_0000  The kernel itself, linked to run here (see linker.ld), but loaded at KERNEL_START
+//
+// The kernel's own addresses are its physical ones plus KERNEL_OFFSET, so it's somewhere in the top 4GiB,
+// and the lowest thing here is the direct map at DIRECT_MAP_BASE, 256GiB from the top. That 256GiB is the
+// whole of Sv39's top half. With Sv48 or Sv57 the top half starts further down, but none of this moves, so
+// the kernel ends up at the same addresses whichever we use.
+
+// The kernel's own addresses are where it was loaded plus this, linker.ld has to agree
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_0000_0000;
+
+pub const DIRECT_MAP_BASE: usize = 0xffff_ffc0_0000_0000;
+// Enough for 128GiB of physical addresses, we're nowhere near that
+pub const DIRECT_MAP_SIZE: usize = 1 << 37;
+
+pub const VMALLOC_START: usize = 0xffff_ffe0_0000_0000;
+pub const VMALLOC_SIZE: usize = 1 << 30;
+
+pub const KSTACK_START: usize = 0xffff_ffe0_4000_0000;
+pub const KSTACK_SIZE: usize = 1 << 30;
+
+// Where physical address `pa` is in the direct map
+#[inline]
+pub const fn phys_to_virt(pa: usize) -> usize {
+    pa + DIRECT_MAP_BASE
+}
+
+// The physical address of a kernel virtual address. Only the direct map and the kernel itself are a
+// fixed distance from where they are in memory, anything in vmalloc or the stack region needs a page table
+// walk (see vm::kvm_translate)
+pub fn virt_to_phys(va: usize) -> usize {
+    if va >= KERNEL_OFFSET {
+        va - KERNEL_OFFSET
+    } else if (DIRECT_MAP_BASE..DIRECT_MAP_BASE + DIRECT_MAP_SIZE).contains(&va) {
+        va - DIRECT_MAP_BASE
+    } else {
+        panic!("virt_to_phys: {va:#x} isn't in the direct map or the kernel");
+    }
+}
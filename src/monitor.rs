@@ -0,0 +1,248 @@
+// A tiny kernel monitor: type a command into the serial console and the kernel runs it.
+// This is for poking at the kernel while it runs (what locks are contended, how often harts wake up...)
+// and isn't meant to be a real shell, that'll be a user program one day.
+
+// The UART's interrupt only goes to the boot hart (see plic.rs), and the interrupt handler just stashes what
+// was typed (see console.rs). The boot hart then wakes up in its idle loop in main.rs and calls poll,
+// so commands run on the boot hart with interrupts on, not in a trap.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    clock,
+    console::{self, BACKSPACE},
+    cpu, hotplug, lockstat, print, println,
+    spinlock::Spinlock,
+    timer, vm,
+};
+
+// How often we check for typed characters when the firmware's console can't tell us (see input_needs_polling)
+const POLL_INTERVAL_MS: u64 = 50;
+
+// When we last asked to be woken up to check the firmware's console
+static NEXT_POLL: AtomicU64 = AtomicU64::new(0);
+
+// The longest line we'll take, anything past this is ignored
+const MAX_LINE: usize = 64;
+
+const PROMPT: &str = "guhkern> ";
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    // Gets everything typed after the command name
+    run: fn(&str),
+}
+
+// Every command we know about, add new ones here
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: cmd_help,
+    },
+    Command {
+        name: "locks",
+        help: "[count] show the most contended locks",
+        run: cmd_locks,
+    },
+    Command {
+        name: "lockdep",
+        help: "show which locks have been taken while holding which",
+        run: cmd_lockdep,
+    },
+    Command {
+        name: "cpus",
+        help: "show which harts are online",
+        run: cmd_cpus,
+    },
+    Command {
+        name: "cpu_offline",
+        help: "<hart> park a hart, handing its work to the others",
+        run: cmd_cpu_offline,
+    },
+    Command {
+        name: "cpu_online",
+        help: "<hart> bring a parked hart back",
+        run: cmd_cpu_online,
+    },
+    Command {
+        name: "vm",
+        help: "[pid] show the kernel's page table, or the one a process is using",
+        run: cmd_vm,
+    },
+    Command {
+        name: "wakeups",
+        help: "show how often each hart wakes up from idle",
+        run: cmd_wakeups,
+    },
+    Command {
+        name: "uptime",
+        help: "show how long we've been running",
+        run: cmd_uptime,
+    },
+];
+
+struct Line {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+}
+
+static LINE: Spinlock<Line> = Spinlock::new(
+    "monitor",
+    Line {
+        bytes: [0; MAX_LINE],
+        len: 0,
+    },
+);
+
+// Say hello, called once the console is ready
+pub fn init() {
+    println!("Kernel monitor ready, type `help` for a list of commands");
+    print!("{PROMPT}");
+}
+
+// Whether there's something typed for poll to look at, so the boot hart doesn't go back to sleep
+pub fn has_input() -> bool {
+    console::has_input()
+}
+
+// Read whatever has been typed since last time, running a command if we got a whole line
+// Called by the boot hart every time it wakes up
+pub fn poll() {
+    read_input();
+    if console::input_needs_polling() {
+        // Nothing's going to wake us up when a key's pressed, so make sure we wake up to check.
+        // Our last wakeup is gone from the timer once it's passed, so we only ever have one waiting.
+        // If the timer's full we'll check whenever something else wakes us up
+        let now = clock::now();
+        if now >= NEXT_POLL.load(Ordering::Relaxed) {
+            let next = now + clock::nanos_to_cycles(POLL_INTERVAL_MS * 1_000_000);
+            NEXT_POLL.store(next, Ordering::Relaxed);
+            let _ = timer::add_deadline(next);
+        }
+    }
+}
+
+fn read_input() {
+    while let Some(byte) = console::get_c() {
+        // Build up the line with the lock held, but run the command without it
+        let mut command = [0; MAX_LINE];
+        let len = {
+            let mut line = LINE.lock();
+            match byte {
+                b'\r' | b'\n' => {
+                    let len = line.len;
+                    command[..len].copy_from_slice(&line.bytes[..len]);
+                    line.len = 0;
+                    Some(len)
+                }
+                // Backspace and delete
+                0x08 | 0x7f => {
+                    if line.len > 0 {
+                        line.len -= 1;
+                        console::put_c(BACKSPACE);
+                    }
+                    None
+                }
+                byte if line.len < MAX_LINE && (0x20..0x7f).contains(&byte) => {
+                    let len = line.len;
+                    line.bytes[len] = byte;
+                    line.len += 1;
+                    console::put_c(byte as char);
+                    None
+                }
+                _ => None,
+            }
+        };
+        if let Some(len) = len {
+            println!("");
+            // We only let printable ASCII in, so this is always valid
+            run(core::str::from_utf8(&command[..len]).unwrap_or(""));
+            print!("{PROMPT}");
+        }
+    }
+}
+
+fn run(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args.trim()),
+        None => println!("Unknown command `{name}`, try `help`"),
+    }
+}
+
+fn cmd_help(_args: &str) {
+    for command in COMMANDS {
+        println!("  {:<12} {}", command.name, command.help);
+    }
+}
+
+fn cmd_locks(args: &str) {
+    let count = args.parse().unwrap_or(10);
+    lockstat::print_most_contended(count);
+}
+
+fn cmd_lockdep(_args: &str) {
+    #[cfg(feature = "lockdep")]
+    crate::lockdep::print_graph();
+    #[cfg(not(feature = "lockdep"))]
+    println!("Lockdep is off, build with the `lockdep` feature");
+}
+
+fn cmd_cpus(_args: &str) {
+    hotplug::print_cpus();
+}
+
+fn cmd_cpu_offline(args: &str) {
+    match args.parse() {
+        Ok(hart) => match hotplug::cpu_offline(hart) {
+            Ok(()) => println!("CPU {hart} is offline"),
+            Err(error) => println!("Couldn't take CPU {hart} offline: {error:?}"),
+        },
+        Err(_) => println!("Usage: cpu_offline <hart>"),
+    }
+}
+
+fn cmd_cpu_online(args: &str) {
+    match args.parse() {
+        Ok(hart) => match hotplug::cpu_online(hart) {
+            Ok(()) => println!("CPU {hart} is online"),
+            Err(error) => println!("Couldn't bring CPU {hart} online: {error:?}"),
+        },
+        Err(_) => println!("Usage: cpu_online <hart>"),
+    }
+}
+
+fn cmd_vm(args: &str) {
+    if args.is_empty() {
+        vm::print_kernel_ranges();
+        return;
+    }
+    match args.parse() {
+        Ok(pid) => {
+            if !vm::print_process_ranges(pid) {
+                println!("No process {pid} running");
+            }
+        }
+        Err(_) => println!("Usage: vm [pid]"),
+    }
+}
+
+fn cmd_wakeups(_args: &str) {
+    cpu::print_wakeup_stats();
+}
+
+fn cmd_uptime(_args: &str) {
+    let millis = clock::now_nanos() / 1_000_000;
+    println!(
+        "up {}.{:03}s, {} ticks",
+        millis / 1000,
+        millis % 1000,
+        clock::ticks()
+    );
+}
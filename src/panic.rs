@@ -2,7 +2,7 @@
 // This is a special case where we don't want to return from the panic handler
 
 use core::panic::PanicInfo;
-use core::sync::atomic::AtomicBool;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::println;
 use crate::println::PRINTLN_LOCK;
@@ -14,19 +14,17 @@ pub static PANICKED: AtomicBool = AtomicBool::new(false);
 // Halt on panic, don't allow us to return
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    unsafe {
-        // First we set the PRINTLN_LOCK to false, meaning
-        // println will no longer attempt to acquire the lock
-        // this is so if a panic occurs in println! we don't
-        // infinitely loop as we try to acquire the lock
-        PRINTLN_LOCK.0 = false;
-        // Now we print the panic message to the screen, this won't
-        // lock as we've set the lock to false
-        println!("Kernel panic");
-        println!("Panic: {}", info);
-        // Finally we set the PANICKED flag to true, this will
-        // prevent other cores from continuing to output messages
-        PANICKED.store(true, core::sync::atomic::Ordering::Relaxed);
-    }
+    // First we turn off PRINTLN_LOCK's locking, meaning
+    // println will no longer attempt to acquire the lock
+    // this is so if a panic occurs in println! we don't
+    // infinitely loop as we try to acquire the lock
+    PRINTLN_LOCK.locking.store(false, Ordering::Relaxed);
+    // Now we print the panic message to the screen, this won't
+    // lock as we've turned locking off
+    println!("Kernel panic");
+    println!("Panic: {}", info);
+    // Finally we set the PANICKED flag to true, this will
+    // prevent other cores from continuing to output messages
+    PANICKED.store(true, Ordering::Relaxed);
     loop {}
 }
@@ -0,0 +1,137 @@
+// Data that every hart has its own copy of, like the Cpu struct or each hart's timer.
+// Before this every file had its own `static mut [Thing; NUM_CPUS]` indexed by Cpu::get_id(), and
+// nothing stopped you from holding on to your hart's entry for too long: once something can move us
+// to another hart (an interrupt that switches processes) we'd be scribbling on the wrong hart's copy.
+// Handing out two `&mut` to the same entry was also just one function call away.
+
+// A PerCpu only gives out references while you hold an InterruptGuard (from disable_interrupts),
+// and the reference can't outlive the guard. With interrupts off nothing can move us to another hart
+// or run an interrupt handler that grabs the same entry, so the reference really is ours.
+// The guard alone can't stop two `&mut`s though, you can always take a second guard while holding the
+// first. So like a RefCell, what you get back is a little guard of its own that keeps count of how
+// this hart's copy is borrowed, and asking for a `get_mut` while anything else has it panics.
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    ptr::addr_of_mut,
+};
+
+use crate::{consts::NUM_CPUS, cpu::Cpu, spinlock::InterruptGuard};
+
+// What `borrows` is while there's a PerCpuRefMut
+const BORROWED_MUT: isize = -1;
+
+pub struct PerCpu<T> {
+    data: UnsafeCell<[T; NUM_CPUS]>,
+    // How each hart's copy is borrowed right now: 0 is not at all, more than that is how many PerCpuRefs
+    // there are, and BORROWED_MUT is a PerCpuRefMut. Only ever touched by its own hart, with interrupts off
+    borrows: [Cell<isize>; NUM_CPUS],
+}
+
+// Safety: each hart only ever touches its own entry (with interrupts off),
+// so the data only needs to be able to move between harts
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    // Make one with every hart's starting value, usually `[const { Thing::new() }; NUM_CPUS]`
+    pub const fn new(data: [T; NUM_CPUS]) -> Self {
+        Self {
+            data: UnsafeCell::new(data),
+            borrows: [const { Cell::new(0) }; NUM_CPUS],
+        }
+    }
+
+    // This hart's copy, for as long as interrupts stay off
+    // Panics if it's borrowed with get_mut right now
+    pub fn get<'a>(&'a self, _guard: &'a InterruptGuard) -> PerCpuRef<'a, T> {
+        let hart = Cpu::get_id();
+        let borrows = &self.borrows[hart];
+        if borrows.get() == BORROWED_MUT {
+            panic!("PerCpu: get on hart {hart} while it's borrowed with get_mut");
+        }
+        borrows.set(borrows.get() + 1);
+        PerCpuRef {
+            // Safety: see the top of the file
+            value: unsafe { &*self.remote(hart) },
+            borrows,
+            _not_send: PhantomData,
+        }
+    }
+
+    // This hart's copy to change, for as long as interrupts stay off
+    // Panics if it's borrowed at all right now
+    pub fn get_mut<'a>(&'a self, _guard: &'a mut InterruptGuard) -> PerCpuRefMut<'a, T> {
+        let hart = Cpu::get_id();
+        let borrows = &self.borrows[hart];
+        if borrows.get() != 0 {
+            panic!("PerCpu: get_mut on hart {hart} while it's already borrowed");
+        }
+        borrows.set(BORROWED_MUT);
+        PerCpuRefMut {
+            // Safety: same as above, and we just checked nobody else has it
+            value: unsafe { &mut *self.remote(hart) },
+            borrows,
+            _not_send: PhantomData,
+        }
+    }
+
+    // A pointer to any hart's copy, without any of the checks above
+    // For things that have to get around them, like disable_interrupts itself (it's what makes the
+    // guard!), early boot before harts have IDs in tp, and reading stats from other harts.
+    // It's up to you to make sure nobody else is using that copy while you do
+    pub fn remote(&self, hart: usize) -> *mut T {
+        // Safety: this doesn't make a reference to the array, just works out where the entry is
+        unsafe { addr_of_mut!((*self.data.get())[hart]) }
+    }
+}
+
+// A hart's copy from PerCpu::get, it's given back when this is dropped
+// Like the InterruptGuard it came from, it stays on the hart that made it
+pub struct PerCpuRef<'a, T> {
+    value: &'a T,
+    borrows: &'a Cell<isize>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for PerCpuRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for PerCpuRef<'_, T> {
+    fn drop(&mut self) {
+        self.borrows.set(self.borrows.get() - 1);
+    }
+}
+
+// A hart's copy from PerCpu::get_mut, nobody else can have it until this is dropped
+pub struct PerCpuRefMut<'a, T> {
+    value: &'a mut T,
+    borrows: &'a Cell<isize>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for PerCpuRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> DerefMut for PerCpuRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T> Drop for PerCpuRefMut<'_, T> {
+    fn drop(&mut self) {
+        self.borrows.set(0);
+    }
+}
@@ -0,0 +1,355 @@
+// This module describes the machine we're running on.
+// Instead of hard-coding where RAM ends or where the UART lives, the boot hart reads the
+// device tree QEMU hands us (see fdt.rs) and fills in the PLATFORM static below.
+// Everything else in the kernel asks this module for addresses.
+
+// If for some reason we don't get a device tree, we fall back to what QEMU's `virt` machine
+// uses with `-m 128M`, which is what this kernel used to assume everywhere.
+
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    consts::{KERNEL_STACK_PAGES, KERNEL_START, MAX_KERNEL_STACK_PAGES, NUM_CPUS},
+    fdt::{Fdt, Node, Region},
+    memlayout::phys_to_virt,
+    plic::PLIC,
+    timer::CLINT_LOC,
+    uart::{UART_LOC0, UART_LOC0_IRQ},
+    virtio::{VIRTIO0, VIRTIO0_IRQ},
+};
+
+// QEMU's virt machine has 8 virtio-mmio slots
+pub const MAX_VIRTIO: usize = 8;
+// How many reserved regions of RAM we keep track of, past that they get lumped in with the last one
+pub const MAX_RESERVED: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+/// A memory-mapped device, where it is (physically), how big its registers are and what IRQ it raises on the PLIC
+pub struct Device {
+    pub base: usize,
+    pub size: usize,
+    pub irq: usize,
+}
+
+impl Device {
+    // Grab the first `reg` region and the first of the `interrupts` of a node
+    fn from_node<'a>(fdt: &Fdt<'a>, node: &Node<'a>) -> Option<Self> {
+        let reg = node.reg().next()?;
+        let irq = fdt
+            .interrupts(node)
+            .and_then(|mut interrupts| interrupts.next())
+            .and_then(|interrupt| interrupt.irq());
+        Some(Self {
+            base: reg.address,
+            size: reg.size,
+            irq: irq.unwrap_or(0) as usize,
+        })
+    }
+
+    // Where the kernel gets at its registers, devices are in the direct map like RAM (see memlayout.rs)
+    pub fn address(&self) -> usize {
+        phys_to_virt(self.base)
+    }
+}
+
+pub struct Platform {
+    /// The RAM we're loaded into, the kernel starts at memory_start
+    pub memory_start: usize,
+    pub memory_end: usize,
+    /// The number of harts (CPUs), capped at NUM_CPUS
+    pub num_harts: usize,
+    /// Which hart IDs there are, bit N for hart N (only harts below NUM_CPUS)
+    pub hart_mask: usize,
+    /// The hart that did all of the setup, this is hart 0 unless SBI firmware picked another one
+    pub boot_hart: usize,
+    /// Whether every hart has the Sstc extension, letting supervisor mode set its own timer
+    pub has_sstc: bool,
+    /// Whether every hart has Svadu, so the hardware can set Accessed and Dirty in PTEs itself
+    pub has_svadu: bool,
+    /// How many times a second the `time` register counts up
+    pub timebase_frequency: u64,
+    /// How many pages of stack each hart and process gets in the kernel, `kstack_pages=N` on the command line
+    pub kernel_stack_pages: usize,
+    pub uart: Device,
+    pub plic: Device,
+    pub clint: Device,
+    pub virtio: [Option<Device>; MAX_VIRTIO],
+    /// Where the device tree itself sits in RAM, we need to not give this out as free memory
+    pub dtb_start: usize,
+    pub dtb_end: usize,
+    /// RAM the firmware wants left alone, from the memory reservation block and /reserved-memory
+    pub reserved: [Option<Region>; MAX_RESERVED],
+    /// Whether any of this came from a device tree
+    pub from_device_tree: bool,
+}
+
+// The defaults for QEMU's virt machine
+static mut PLATFORM: Platform = Platform {
+    memory_start: KERNEL_START,
+    memory_end: KERNEL_START + 128 * 1024 * 1024,
+    num_harts: NUM_CPUS,
+    hart_mask: (1 << NUM_CPUS) - 1,
+    boot_hart: 0,
+    has_sstc: false,
+    has_svadu: false,
+    timebase_frequency: 10_000_000,
+    kernel_stack_pages: KERNEL_STACK_PAGES,
+    uart: Device {
+        base: UART_LOC0,
+        size: 0x100,
+        irq: UART_LOC0_IRQ,
+    },
+    plic: Device {
+        base: PLIC,
+        size: 0x4000000,
+        irq: 0,
+    },
+    clint: Device {
+        base: CLINT_LOC,
+        size: 0x10000,
+        irq: 0,
+    },
+    virtio: [
+        Some(Device {
+            base: VIRTIO0,
+            size: 0x1000,
+            irq: VIRTIO0_IRQ,
+        }),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    ],
+    dtb_start: 0,
+    dtb_end: 0,
+    reserved: [None; MAX_RESERVED],
+    from_device_tree: false,
+};
+
+// Set once the boot hart has finished filling in PLATFORM
+static DISCOVERED: AtomicBool = AtomicBool::new(false);
+
+// Set by the first hart to make it into the kernel when we can't just pick hart 0
+static BOOT_HART_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+// Get the description of our machine
+// This is only ever written by the boot hart before DISCOVERED is set,
+// after that it's read-only so handing out shared references is fine
+pub fn platform() -> &'static Platform {
+    unsafe { &*addr_of!(PLATFORM) }
+}
+
+// Check if a cpu node says it supports a multi-letter ISA extension like "sstc"
+fn has_extension(node: &Node, extension: &str) -> bool {
+    if let Some(extensions) = node.property("riscv,isa-extensions") {
+        return extensions.strings().any(|e| e == extension);
+    }
+    // Older trees only have a single string like "rv64imafdc_zicsr_sstc"
+    node.property("riscv,isa")
+        .and_then(|p| p.as_str())
+        .map(|isa| isa.split('_').skip(1).any(|e| e == extension))
+        .unwrap_or(false)
+}
+
+// Pull `kstack_pages=N` out of the kernel command line (QEMU's -append puts it in /chosen/bootargs),
+// keeping it between 1 and the biggest stack a slot has room for
+fn parse_stack_pages(bootargs: &str) -> Option<usize> {
+    let pages: usize = bootargs
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("kstack_pages="))?
+        .parse()
+        .ok()?;
+    Some(pages.clamp(1, MAX_KERNEL_STACK_PAGES))
+}
+
+// Returns true for exactly one hart, the first one to call this
+pub fn claim_boot_hart() -> bool {
+    !BOOT_HART_CLAIMED.swap(true, Ordering::AcqRel)
+}
+
+// Called by the boot hart in start.rs with the address QEMU gave us in a1
+// This runs before the console is setup, so no printing here!
+// That's a physical address, we read the blob through the direct map (entry.S's boot page table has it)
+pub fn init(boot_hart: usize, dtb: usize) {
+    // Safety: QEMU always puts either 0 or the address of the blob in a1
+    let fdt = if dtb == 0 {
+        None
+    } else {
+        unsafe { Fdt::from_addr(phys_to_virt(dtb)) }
+    };
+    if let Some(fdt) = fdt {
+        // Safety: we're the only hart touching this, the others are waiting in wait_for_discovery
+        let platform = unsafe { &mut *addr_of_mut!(PLATFORM) };
+        platform.discover(&fdt);
+        platform.dtb_start = dtb;
+        platform.dtb_end = dtb + fdt.total_size();
+    }
+    // Safety: same as above
+    unsafe {
+        (*addr_of_mut!(PLATFORM)).boot_hart = boot_hart;
+    }
+    DISCOVERED.store(true, Ordering::Release);
+}
+
+// The other harts need the CLINT address in start.rs, so they wait here until the boot hart is done
+pub fn wait_for_discovery() {
+    while !DISCOVERED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+impl Platform {
+    fn discover(&mut self, fdt: &Fdt) {
+        let mut harts = 0;
+        let mut hart_mask = 0;
+        let mut all_sstc = true;
+        let mut all_svadu = true;
+        let mut virtio = 0;
+        let mut found_uart = false;
+        let mut in_reserved_memory = false;
+
+        self.virtio = [None; MAX_VIRTIO];
+
+        for region in fdt.memory_reservations() {
+            self.reserve(region);
+        }
+
+        for node in fdt.nodes().filter(|node| node.is_enabled()) {
+            // Each child of /reserved-memory with a `reg` is a fixed region nobody else should use.
+            // The ones without just ask for some memory from anywhere, which isn't anything to do with us
+            if node.depth == 1 {
+                in_reserved_memory = node.base_name() == "reserved-memory";
+            } else if node.depth == 2 && in_reserved_memory {
+                for region in node.reg() {
+                    self.reserve(region);
+                }
+            }
+
+            match node.device_type() {
+                Some("memory") => {
+                    // There could be multiple banks of RAM, we want the one we've been loaded into
+                    if let Some(region) = node.reg().find(|r| r.contains(KERNEL_START)) {
+                        self.memory_start = region.address;
+                        self.memory_end = region.end();
+                    }
+                }
+                Some("cpu") => {
+                    // A cpu node's reg is its hart ID, if it doesn't have one guess they're numbered in order
+                    let id = node.reg().next().map_or(harts, |reg| reg.address);
+                    if id < NUM_CPUS {
+                        hart_mask |= 1 << id;
+                    }
+                    harts += 1;
+                    all_sstc &= has_extension(&node, "sstc");
+                    all_svadu &= has_extension(&node, "svadu");
+                }
+                _ => {}
+            }
+
+            if node.depth == 1 && node.base_name() == "cpus" {
+                if let Some(frequency) = node.property("timebase-frequency") {
+                    self.timebase_frequency = frequency.as_usize().unwrap_or(0) as u64;
+                }
+            }
+
+            if node.depth == 1 && node.base_name() == "chosen" {
+                let bootargs = node.property("bootargs").and_then(|p| p.as_str());
+                if let Some(pages) = bootargs.and_then(parse_stack_pages) {
+                    self.kernel_stack_pages = pages;
+                }
+            }
+
+            if !found_uart && node.is_compatible("ns16550a") {
+                if let Some(device) = Device::from_node(fdt, &node) {
+                    self.uart = device;
+                    found_uart = true;
+                }
+            } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+                self.plic = Device::from_node(fdt, &node).unwrap_or(self.plic);
+            } else if node.is_compatible("riscv,clint0") || node.is_compatible("sifive,clint0") {
+                self.clint = Device::from_node(fdt, &node).unwrap_or(self.clint);
+            } else if node.is_compatible("virtio,mmio") && virtio < MAX_VIRTIO {
+                self.virtio[virtio] = Device::from_node(fdt, &node);
+                virtio += 1;
+            }
+        }
+
+        if harts > 0 {
+            self.num_harts = harts.min(NUM_CPUS);
+            self.hart_mask = hart_mask;
+            self.has_sstc = all_sstc;
+            self.has_svadu = all_svadu;
+        }
+        // We divide by this, so make sure a bad tree doesn't give us 0
+        if self.timebase_frequency == 0 {
+            self.timebase_frequency = 10_000_000;
+        }
+        self.from_device_tree = true;
+    }
+
+    // Remember not to hand out `region`. If we're out of room we grow the last one to cover it too, giving
+    // away less memory than we could is better than giving away memory we shouldn't
+    fn reserve(&mut self, region: Region) {
+        if region.size == 0 {
+            return;
+        }
+        if let Some(slot) = self.reserved.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(region);
+        } else if let Some(last) = self.reserved[MAX_RESERVED - 1].as_mut() {
+            let address = last.address.min(region.address);
+            let end = last.end().max(region.end());
+            *last = Region {
+                address,
+                size: end - address,
+            };
+        }
+    }
+
+    // Whether any of [start, end) is reserved, and if so where the reserved region ends
+    pub fn reserved_end(&self, start: usize, end: usize) -> Option<usize> {
+        self.reserved
+            .iter()
+            .flatten()
+            .find(|region| region.address < end && start < region.end())
+            .map(|region| region.end())
+    }
+
+    // Print what we found, called once the console is up
+    pub fn print_summary(&self) {
+        println!(
+            "Platform ({}): RAM {:#x}-{:#x} ({}MiB), {} harts ({:#b})",
+            if self.from_device_tree {
+                "device tree"
+            } else {
+                "defaults"
+            },
+            self.memory_start,
+            self.memory_end,
+            (self.memory_end - self.memory_start) / (1024 * 1024),
+            self.num_harts,
+            self.hart_mask
+        );
+        println!(
+            "  uart {:#x} irq {}, plic {:#x}, clint {:#x}, sstc {}, svadu {}, timebase {}Hz",
+            self.uart.base,
+            self.uart.irq,
+            self.plic.base,
+            self.clint.base,
+            self.has_sstc,
+            self.has_svadu,
+            self.timebase_frequency
+        );
+        for device in self.virtio.iter().flatten() {
+            println!("  virtio {:#x} irq {}", device.base, device.irq);
+        }
+        for region in self.reserved.iter().flatten() {
+            println!("  reserved {:#x}-{:#x}", region.address, region.end());
+        }
+    }
+}
@@ -1 +1,96 @@
+// The PLIC (Platform-Level Interrupt Controller) collects interrupts from devices (the UART, virtio disks...)
+// and hands each one to a hart. Every device has an IRQ number ("source"), and every hart has a few
+// "contexts", one per privilege mode. We set which sources each context wants to hear about, and when one
+// of them goes off that hart gets a supervisor external interrupt.
+// We then "claim" the interrupt to find out which source it was, deal with the device, and "complete" it
+// so the PLIC will send us that source again.
+
+// For now the only device we take interrupts from is the UART, so typing into the console wakes
+// the boot hart up instead of it having to keep checking (see monitor.rs).
+// The virtio disk is still polled, see virtio.rs
+
+use crate::{cpu::Cpu, platform::platform, uart};
+
+// Where QEMU puts the PLIC if we don't get told otherwise by the device tree (see platform.rs)
 pub const PLIC: usize = 0x0c000000;
+
+// Registers, as offsets from the PLIC's base
+// One u32 per source, a source's interrupts only get through if its priority is above a context's threshold
+const PRIORITY: usize = 0;
+// 0x80 bytes per context, one bit per source
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+// 0x1000 bytes per context, the threshold and then the claim/complete register
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0;
+const CLAIM: usize = 4;
+
+// On QEMU's virt machine every hart has a machine mode context and then a supervisor mode one, in hart order.
+// The device tree does spell this out (in the PLIC's interrupts-extended) but every board we run on does it this way
+#[inline]
+fn supervisor_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
+#[inline]
+fn reg(offset: usize) -> *mut u32 {
+    (platform().plic.address() + offset) as *mut u32
+}
+
+// Give the UART's interrupt a priority, anything left at 0 never gets through.
+// Called once by the boot hart before interrupts are on
+pub fn init() {
+    let irq = platform().uart.irq;
+    unsafe {
+        reg(PRIORITY + irq * 4).write_volatile(1);
+    }
+}
+
+// Set up this hart's supervisor context. Only the boot hart listens for the UART, the monitor runs there
+// and it can't be parked (see hotplug.rs)
+pub fn init_hart() {
+    let hart = Cpu::get_id();
+    let context = supervisor_context(hart);
+    if hart == platform().boot_hart {
+        let irq = platform().uart.irq;
+        unsafe {
+            let enable = reg(ENABLE + context * ENABLE_STRIDE + (irq / 32) * 4);
+            enable.write_volatile(enable.read_volatile() | 1 << (irq % 32));
+        }
+    }
+    // Let anything with a priority above 0 through
+    unsafe {
+        reg(CONTEXT + context * CONTEXT_STRIDE + THRESHOLD).write_volatile(0);
+    }
+}
+
+// Ask which source interrupted us, 0 means there's nothing (another hart might have claimed it first)
+fn claim() -> usize {
+    let context = supervisor_context(Cpu::get_id());
+    unsafe { reg(CONTEXT + context * CONTEXT_STRIDE + CLAIM).read_volatile() as usize }
+}
+
+// Tell the PLIC we're done with a source, until then it won't send it again
+fn complete(irq: usize) {
+    let context = supervisor_context(Cpu::get_id());
+    unsafe {
+        reg(CONTEXT + context * CONTEXT_STRIDE + CLAIM).write_volatile(irq as u32);
+    }
+}
+
+// Called by kernel_trap in trap.rs when we get a supervisor external interrupt
+pub fn external_interrupt() {
+    loop {
+        let irq = claim();
+        if irq == 0 {
+            break;
+        }
+        if irq == platform().uart.irq {
+            uart::uart_interrupt();
+        } else {
+            println!("plic: unexpected interrupt from irq {irq}");
+        }
+        complete(irq);
+    }
+}
@@ -1,30 +1,33 @@
 // This module defines how we can print to the console
 // using format strings and arguments
 
-use core::fmt::{self, Write};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use crate::{console, spinlock::Spinlock};
+use crate::{console, ticketlock::TicketLock};
 
-pub struct PrintlnLock(pub bool, pub Option<Spinlock>);
+pub struct PrintlnLock {
+    // Whether we should take the lock to print, the panic handler turns this off
+    pub locking: AtomicBool,
+    // A ticket lock, so harts take turns printing in the order they asked
+    pub output: TicketLock<Output>,
+}
 
 // Have a lock here to prevent multiple cores from printing at the same time
 // Here's an example of what could happen if we don't have a lock:
 // - CCPPUU  12  ssttaarrttiinngg
 // - CCPU PU 21  stsatratrting i
 //   ng
-pub static mut PRINTLN_LOCK: PrintlnLock = PrintlnLock(false, None);
-
-// Initialized our locks and sets the PRINTLN_LOCK.0 to true
-// this means we'll require the lock to print
-pub fn init_println() {
-    unsafe {
-        Spinlock::init(&mut PRINTLN_LOCK.1);
-        PRINTLN_LOCK.0 = true;
-    }
-}
+// The lock owns our Output, so the only way to print is to take it
+pub static PRINTLN_LOCK: PrintlnLock = PrintlnLock {
+    locking: AtomicBool::new(true),
+    output: TicketLock::new("println", Output),
+};
 
 // Unit struct to implement the Write trait
-struct Output;
+pub struct Output;
 
 // This implementation of Write will allow us to use `Write::write_fmt` function
 // in order to output fmt::Arguments to the console
@@ -40,14 +43,11 @@ impl core::fmt::Write for Output {
 // Base print function, this will lock if we're supposed to and
 // then write the arguments to the console
 pub fn print(args: fmt::Arguments) {
-    let _lock = if unsafe { PRINTLN_LOCK.0 } {
-        Some(unsafe { Spinlock::acquire(PRINTLN_LOCK.1.as_mut()) })
+    if PRINTLN_LOCK.locking.load(Ordering::Relaxed) {
+        PRINTLN_LOCK.output.lock().write_fmt(args).unwrap();
     } else {
-        None
-    };
-    let mut out = Output;
-    out.write_fmt(args).unwrap();
-    drop(_lock);
+        Output.write_fmt(args).unwrap();
+    }
 }
 
 // Println function, this will call the print function with a newline
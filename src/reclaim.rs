@@ -0,0 +1,266 @@
+// Taking memory back from address spaces when we run out.
+// When allocate_page finds the free list empty it asks us for a page before giving up. Pages handed out
+// with AddressSpace::alloc_pages get tracked here, and we go round them like the hand of a clock:
+// - A page with Accessed set has been used since the hand last went past, so we clear Accessed and move on.
+//   If it's still clear next time round, nobody's used it in a whole lap
+// - A page with Accessed clear hasn't, so it's the one we take
+// It's an approximation of "least recently used" that only needs one bit per page, and the hardware keeps
+// that bit up to date for us (see vm.rs for how A and D get set).
+
+// A page nothing has been written to (Dirty is clear) is still all zeroes from when it was allocated, so we
+// can just take it: when it's next used handle_page_fault in vm.rs gives it a new zeroed page and nobody
+// can tell the difference. A dirty page has to be written out to swap first (see swap.rs), so without a
+// swap disk those stay put.
+
+// We keep track of the pages by their physical page number, one spot for every page of RAM. A page can
+// only be mapped in one place we're tracking, so nothing ever has to look for an empty spot or go through
+// all of them to find one. The array's made at boot, once we know how much RAM there is.
+
+use core::mem::size_of;
+
+use crate::{
+    kalloc::{free_page, PAGE_SIZE},
+    memlayout::phys_to_virt,
+    platform::platform,
+    println,
+    spinlock::Spinlock,
+    swap, tlb,
+    vm::{self, AddressSpace, Age},
+    vmalloc::vmalloc,
+};
+
+// Taking a page happens in two goes (see age_page and finish_eviction in vm.rs), and in between we can't
+// hold the lock: the page has to be shot out of every TLB and maybe written to swap. So the page stays
+// tracked while it's leaving, which is how we know nobody's unmapped it (see forget) and its page table's
+// still there when we come back to finish. Only one hart can be taking a page at once, the hand skips
+// pages that are already leaving
+
+// Where the page with this physical page number is mapped. A table of 0 means it's not tracked,
+// so the zeroed pages vmalloc gives us start out with nothing tracked
+#[derive(Clone, Copy)]
+struct Tracked {
+    // The address of the page table it's mapped in
+    table: usize,
+    va: usize,
+    // Some hart's in the middle of taking it
+    leaving: bool,
+}
+
+impl Tracked {
+    fn is(&self, table: usize, va: usize) -> bool {
+        self.table == table && self.va == va
+    }
+}
+
+struct Clock {
+    // One for every page of RAM, indexed by how far into RAM it is. Empty until `init`
+    pages: &'static mut [Tracked],
+    hand: usize,
+}
+
+static CLOCK: Spinlock<Clock> = Spinlock::new(
+    "reclaim",
+    Clock {
+        pages: &mut [],
+        hand: 0,
+    },
+);
+
+// Make room to track every page of RAM, called once by the boot hart after the kernel page table is up.
+// Until then nothing gets tracked, and there's nothing to reclaim
+pub fn init() {
+    let npages = (platform().memory_end - platform().memory_start) / PAGE_SIZE;
+    let pages = vmalloc(npages * size_of::<Tracked>()).expect("reclaim: no memory to track pages");
+    // Safety: vmalloc gave us this much zeroed memory and nobody else has it, and all zeroes is "not tracked"
+    CLOCK.lock().pages = unsafe { core::slice::from_raw_parts_mut(pages as *mut Tracked, npages) };
+}
+
+// Which spot the page at `physical` has
+fn index_of(physical: usize) -> Option<usize> {
+    Some(physical.checked_sub(platform().memory_start)? / PAGE_SIZE)
+}
+
+// Start keeping an eye on the page at `va` in the page table at `table`, which is the memory at `physical`
+pub fn track(table: usize, va: usize, physical: usize) {
+    let mut clock = CLOCK.lock();
+    if let Some(page) = index_of(physical).and_then(|index| clock.pages.get_mut(index)) {
+        *page = Tracked {
+            table,
+            va,
+            leaving: false,
+        };
+    }
+}
+
+// Stop tracking the page at `va` in the page table at `table`, it's being unmapped from `physical`
+pub fn forget(table: usize, va: usize, physical: usize) {
+    let mut clock = CLOCK.lock();
+    if let Some(page) = index_of(physical)
+        .and_then(|index| clock.pages.get_mut(index))
+        .filter(|page| page.is(table, va))
+    {
+        page.table = 0;
+    }
+}
+
+// How one go at taking a page went
+enum Attempt {
+    Took,
+    // There's nothing we can take, or the swap disk isn't working
+    Nothing,
+    // We picked one, but a fault put it back or it was unmapped while we were busy
+    Kept,
+}
+
+// Try to free up a page, called by allocate_page when it's run out. Returns whether we did
+pub fn reclaim_one() -> bool {
+    // Taking a page means shooting it out of every hart's TLB, and while we wait for that every other hart
+    // has to be able to take our IPI. If interrupts are off here we could be holding a lock one of them is
+    // spinning on with its interrupts off too, and we'd wait forever. So no reclaiming from in there
+    if !riscv::register::sstatus::read().sie() {
+        return false;
+    }
+    loop {
+        match take_one() {
+            Attempt::Took => return true,
+            Attempt::Nothing => return false,
+            // Whatever it was is being used after all, the hand's moved on so have a go at the next one
+            Attempt::Kept => {}
+        }
+    }
+}
+
+fn take_one() -> Attempt {
+    let victim = {
+        let mut clock = CLOCK.lock();
+        let total = clock.pages.len();
+        let mut victim = None;
+        // Two laps at most: the first clears every Accessed bit, so the second finds something if anything can be taken
+        for _ in 0..2 * total {
+            let hand = clock.hand;
+            clock.hand = (hand + 1) % total;
+            let page = clock.pages[hand];
+            if page.table == 0 || page.leaving {
+                continue;
+            }
+            match vm::age_page(page.table, page.va) {
+                Age::Young | Age::Dirty => {}
+                Age::Gone => clock.pages[hand].table = 0,
+                Age::Leaving {
+                    physical,
+                    swap_slot,
+                } => {
+                    clock.pages[hand].leaving = true;
+                    victim = Some((page, physical, swap_slot));
+                    break;
+                }
+            }
+        }
+        victim
+    };
+
+    // The lock's dropped first, so other harts can get at it (and answer our IPIs) while we wait
+    let Some((page, physical, swap_slot)) = victim else {
+        return Attempt::Nothing;
+    };
+    tlb::shootdown(page.va, PAGE_SIZE);
+    // Now nobody can write to it any more, what's in it can go out to swap. If that doesn't work the page
+    // has to stay, so it goes back to how it was
+    let written = match swap_slot {
+        Some(slot) => swap::write_out(slot, physical),
+        None => Ok(()),
+    };
+
+    let taken = {
+        let mut clock = CLOCK.lock();
+        match index_of(physical)
+            .and_then(|index| clock.pages.get_mut(index))
+            .filter(|tracked| tracked.is(page.table, page.va) && tracked.leaving)
+        {
+            // It was unmapped while we were busy, and whoever did that has the page now
+            None => Attempt::Kept,
+            Some(tracked) => {
+                tracked.leaving = false;
+                if let Err(error) = written {
+                    vm::cancel_eviction(page.table, page.va);
+                    println!(
+                        "reclaim_one: couldn't write a page out to swap, keeping it: {error:?}"
+                    );
+                    Attempt::Nothing
+                } else if vm::finish_eviction(page.table, page.va, swap_slot) {
+                    tracked.table = 0;
+                    Attempt::Took
+                } else {
+                    // A fault put it back, so it's still in use and still tracked
+                    Attempt::Kept
+                }
+            }
+        }
+    };
+
+    if let Attempt::Took = taken {
+        free_page(phys_to_virt(physical) as *mut u8);
+    } else if let Some(slot) = swap_slot {
+        swap::free_slot(slot);
+    }
+    taken
+}
+
+// Map some pages, use some of them, and check the reclaimer takes the right ones and they come back
+// as zeroes. Called once by the boot hart with interrupts on, before anything else tracks pages
+pub fn self_test() {
+    const RW: usize = vm::PTE_READ | vm::PTE_WRITE;
+    let va = vm::max_virtual_address() / 2;
+    let mut space = AddressSpace::new().expect("reclaim self test: no memory");
+    space
+        .alloc_pages(va, 4, RW)
+        .expect("reclaim self test: alloc_pages");
+    let page = |i: usize| (va + i * PAGE_SIZE) as *mut usize;
+
+    // Write to pages 0 and 1, read page 2 and leave page 3 alone
+    vm::switch_to(&space);
+    unsafe {
+        page(0).write_volatile(1);
+        page(1).write_volatile(2);
+        assert_eq!(
+            page(2).read_volatile(),
+            0,
+            "reclaim self test: page not zeroed"
+        );
+    }
+    vm::switch_to_kernel();
+
+    // 3 hasn't been touched, so it goes first. Everything else was used, so that first lap clears their
+    // Accessed bits, after which 2 is the only one that's unused and was never written. 0 and 1 have
+    // been written to, so they can only go if there's swap to put them in
+    let mut reclaimed = 0;
+    while reclaim_one() {
+        reclaimed += 1;
+    }
+    let expected = if swap::available() { 4 } else { 2 };
+    assert_eq!(
+        reclaimed, expected,
+        "reclaim self test: took the wrong pages"
+    );
+
+    vm::switch_to(&space);
+    let seen = unsafe { [0, 1, 2, 3].map(|i| page(i).read_volatile()) };
+    vm::switch_to_kernel();
+    assert_eq!(
+        seen,
+        [1, 2, 0, 0],
+        "reclaim self test: pages came back wrong"
+    );
+
+    space.unmap(va, 4, true).expect("reclaim self test: unmap");
+    space.destroy();
+    println!(
+        "Reclaim self test OK ({} A/D faults so far, {})",
+        vm::ad_faults(),
+        if vm::ad_faults() == 0 {
+            "hardware sets A/D"
+        } else {
+            "we set A/D"
+        }
+    );
+}
@@ -0,0 +1,171 @@
+// A reader-writer spinlock: any number of readers can hold it at once, or a single writer.
+// This is for data that's read all the time and hardly ever changed (like the kernel page table),
+// where making every reader wait in line behind each other would be a waste.
+
+// If readers kept coming a writer could wait forever, so this lock prefers writers: once a writer
+// is waiting no new readers get in, and the writer goes as soon as the current readers are done.
+// The flip side is that taking a read lock you already hold can deadlock if a writer shows up in between,
+// so don't do that.
+
+// Like Spinlock, interrupts are off the whole time you hold either kind of lock.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::Cpu,
+    spinlock::{disable_interrupts, InterruptGuard},
+};
+
+#[cfg(feature = "lockdep")]
+use crate::lockdep::{self, LockClass};
+
+// The top bit of `state` is set while a writer holds the lock, the rest counts the readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+const NO_CPU: usize = usize::MAX;
+
+pub struct RwSpinlock<T> {
+    name: &'static str,
+    state: AtomicUsize,
+    // How many writers are waiting, new readers back off while this isn't 0
+    writers_waiting: AtomicUsize,
+    // Which CPU holds the write lock, to catch a hart trying to take it twice
+    writer_cpu: AtomicUsize,
+    // Readers and writers are the same class as far as lockdep cares, both can get stuck behind the other
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
+    data: UnsafeCell<T>,
+}
+
+// Safety: readers only ever get shared references, so the data has to be Sync too
+unsafe impl<T: Send + Sync> Sync for RwSpinlock<T> {}
+unsafe impl<T: Send> Send for RwSpinlock<T> {}
+
+impl<T> RwSpinlock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            name,
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            writer_cpu: AtomicUsize::new(NO_CPU),
+            #[cfg(feature = "lockdep")]
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Take the lock for reading, other readers can hold it at the same time
+    pub fn read(&self) -> RwSpinlockReadGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        if self.writer_cpu.load(Ordering::Relaxed) == Cpu::get_id() {
+            panic!("rwlock {}: read while holding write", self.name);
+        }
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, self.name);
+        loop {
+            // Let any waiting writers go first
+            while self.writers_waiting.load(Ordering::Relaxed) > 0 {
+                core::hint::spin_loop();
+            }
+            let state = self.state.load(Ordering::Relaxed);
+            if state & WRITER == 0
+                && self
+                    .state
+                    .compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        RwSpinlockReadGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    // Take the lock for writing, we'll be the only one holding it
+    pub fn write(&self) -> RwSpinlockWriteGuard<'_, T> {
+        let interrupts = disable_interrupts();
+        let cpu = Cpu::get_id();
+        if self.writer_cpu.load(Ordering::Relaxed) == cpu {
+            panic!("rwlock {}: write while holding write", self.name);
+        }
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, self.name);
+        self.writers_waiting.fetch_add(1, Ordering::Relaxed);
+        // Wait for the readers to drain out and any other writer to finish
+        while self
+            .state
+            .compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        self.writers_waiting.fetch_sub(1, Ordering::Relaxed);
+        self.writer_cpu.store(cpu, Ordering::Relaxed);
+        RwSpinlockWriteGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+}
+
+pub struct RwSpinlockReadGuard<'a, T> {
+    lock: &'a RwSpinlock<T>,
+    // Turns interrupts back on once we've let go, like SpinlockGuard
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for RwSpinlockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: nobody can be writing while we hold a read lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinlockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.lock.class);
+    }
+}
+
+pub struct RwSpinlockWriteGuard<'a, T> {
+    lock: &'a RwSpinlock<T>,
+    _interrupts: InterruptGuard,
+}
+
+impl<T> Deref for RwSpinlockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: we're the only one holding the lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwSpinlockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: same as above
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinlockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        if self.lock.writer_cpu.load(Ordering::Relaxed) != Cpu::get_id() {
+            panic!("rwlock {}: released by another hart", self.lock.name);
+        }
+        self.lock.writer_cpu.store(NO_CPU, Ordering::Relaxed);
+        self.lock.state.store(0, Ordering::Release);
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.lock.class);
+    }
+}
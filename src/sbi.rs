@@ -0,0 +1,258 @@
+// This module lets us talk to SBI (Supervisor Binary Interface) firmware, like OpenSBI.
+// When we boot with `-bios none` we start in machine mode and do all the machine-level setup
+// ourselves (see start.rs). On real boards, and with QEMU's default firmware, OpenSBI does that
+// for us and jumps into our kernel already in supervisor mode. Anything that needs machine mode
+// (setting timers, poking other harts, rebooting) we then have to *ask* the firmware to do.
+
+// Asking works a lot like a system call: we put an extension ID in a7, a function ID in a6,
+// the arguments in a0-a5 and then run `ecall`. The firmware puts an error code in a0 and
+// a return value in a1.
+
+use core::arch::asm;
+
+// The extensions we know how to use, most IDs are just their name in ASCII
+pub const EXT_BASE: usize = 0x10;
+pub const EXT_TIME: usize = 0x5449_4D45; // "TIME"
+pub const EXT_IPI: usize = 0x0073_5049; // "sPI"
+pub const EXT_HSM: usize = 0x0048_534D; // "HSM"
+pub const EXT_SRST: usize = 0x5352_5354; // "SRST"
+pub const EXT_DBCN: usize = 0x4442_434E; // "DBCN"
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The errors SBI calls can give back
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoSharedMemory,
+    Unknown(isize),
+}
+
+impl SbiError {
+    fn from_code(code: isize) -> Self {
+        match code {
+            -1 => SbiError::Failed,
+            -2 => SbiError::NotSupported,
+            -3 => SbiError::InvalidParam,
+            -4 => SbiError::Denied,
+            -5 => SbiError::InvalidAddress,
+            -6 => SbiError::AlreadyAvailable,
+            -7 => SbiError::AlreadyStarted,
+            -8 => SbiError::AlreadyStopped,
+            -9 => SbiError::NoSharedMemory,
+            code => SbiError::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
+// Make the actual call into the firmware, none of the calls we use need more than three arguments
+#[inline]
+fn sbi_call(
+    extension: usize,
+    function: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+) -> SbiResult<usize> {
+    let error: usize;
+    let value: usize;
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") function,
+            in("a7") extension,
+        );
+    }
+    if error == 0 {
+        Ok(value)
+    } else {
+        Err(SbiError::from_code(error as isize))
+    }
+}
+
+// === Base extension, always available ===
+
+/// The version of the SBI spec the firmware implements, as (major, minor)
+pub fn spec_version() -> (usize, usize) {
+    let version = sbi_call(EXT_BASE, 0, 0, 0, 0).unwrap_or(0);
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+/// Which firmware we're running under, and its version
+pub fn implementation() -> (&'static str, usize) {
+    let id = sbi_call(EXT_BASE, 1, 0, 0, 0).unwrap_or(usize::MAX);
+    let version = sbi_call(EXT_BASE, 2, 0, 0, 0).unwrap_or(0);
+    let name = match id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        _ => "unknown",
+    };
+    (name, version)
+}
+
+/// Check if the firmware supports an extension
+pub fn probe_extension(extension: usize) -> bool {
+    sbi_call(EXT_BASE, 3, extension, 0, 0).unwrap_or(0) != 0
+}
+
+// Print what firmware we're running on, called once the console is up
+pub fn print_info() {
+    let (major, minor) = spec_version();
+    let (name, version) = implementation();
+    println!("SBI v{major}.{minor}, {name} {version:#x}");
+    for (extension, ext_name) in [
+        (EXT_TIME, "TIME"),
+        (EXT_IPI, "IPI"),
+        (EXT_HSM, "HSM"),
+        (EXT_SRST, "SRST"),
+        (EXT_DBCN, "DBCN"),
+    ] {
+        if !probe_extension(extension) {
+            println!("  SBI extension {ext_name} not available!");
+        }
+    }
+}
+
+// === TIME extension ===
+
+/// Ask for a timer interrupt when the `time` CSR reaches `stime_value`
+/// The interrupt shows up as a supervisor timer interrupt, calling this again clears it
+pub fn set_timer(stime_value: u64) -> SbiResult<()> {
+    sbi_call(EXT_TIME, 0, stime_value as usize, 0, 0).map(|_| ())
+}
+
+// === IPI extension ===
+
+/// Send a supervisor software interrupt to the harts in `hart_mask`
+/// Bit N of the mask is hart `hart_mask_base + N`
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    sbi_call(EXT_IPI, 0, hart_mask, hart_mask_base, 0).map(|_| ())
+}
+
+// === HSM (Hart State Management) extension ===
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Start a stopped hart, it'll jump to `start_addr` in supervisor mode with paging off,
+/// its hartid in a0 and `opaque` in a1 (just like how we got here)
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiResult<()> {
+    sbi_call(EXT_HSM, 0, hart_id, start_addr, opaque).map(|_| ())
+}
+
+/// Stop the current hart, this only returns if it failed
+pub fn hart_stop() -> SbiError {
+    match sbi_call(EXT_HSM, 1, 0, 0, 0) {
+        Ok(_) => SbiError::Failed,
+        Err(e) => e,
+    }
+}
+
+/// Get the state of a hart
+pub fn hart_status(hart_id: usize) -> SbiResult<HartState> {
+    let state = match sbi_call(EXT_HSM, 2, hart_id, 0, 0)? {
+        0 => HartState::Started,
+        1 => HartState::Stopped,
+        2 => HartState::StartPending,
+        3 => HartState::StopPending,
+        4 => HartState::Suspended,
+        5 => HartState::SuspendPending,
+        6 => HartState::ResumePending,
+        _ => return Err(SbiError::Failed),
+    };
+    Ok(state)
+}
+
+/// Suspend the current hart until an interrupt comes in
+/// A "retentive" suspend (type 0) acts like `wfi` and returns here
+pub fn hart_suspend(suspend_type: u32, resume_addr: usize, opaque: usize) -> SbiResult<()> {
+    sbi_call(EXT_HSM, 3, suspend_type as usize, resume_addr, opaque).map(|_| ())
+}
+
+// === SRST (System Reset) extension ===
+
+#[derive(Debug, Clone, Copy)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// Reset (or turn off) the whole system, this only returns if it failed
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    match sbi_call(EXT_SRST, 0, reset_type as usize, reason as usize, 0) {
+        Ok(_) => SbiError::Failed,
+        Err(e) => e,
+    }
+}
+
+/// Turn off the machine
+pub fn shutdown() -> SbiError {
+    system_reset(ResetType::Shutdown, ResetReason::NoReason)
+}
+
+// === DBCN (Debug Console) extension ===
+
+/// The firmware reads and writes console buffers by *physical* address. A buffer on a process's kernel
+/// stack (see kstack.rs) is only in the page table, so we look them up there rather than with virt_to_phys.
+/// Only the first page is looked up, so a buffer that crosses into the next has to be in the direct map
+/// or the kernel, where the pages after it are the next ones in memory too
+fn physical(address: usize) -> usize {
+    crate::vm::kvm_translate(address).expect("sbi: console buffer isn't mapped")
+}
+
+/// Write some bytes to the firmware's console, returns how many were written
+pub fn console_write(bytes: &[u8]) -> SbiResult<usize> {
+    sbi_call(
+        EXT_DBCN,
+        0,
+        bytes.len(),
+        physical(bytes.as_ptr() as usize),
+        0,
+    )
+}
+
+/// Read whatever bytes are waiting on the firmware's console, returns how many we got
+pub fn console_read(buffer: &mut [u8]) -> SbiResult<usize> {
+    sbi_call(
+        EXT_DBCN,
+        1,
+        buffer.len(),
+        physical(buffer.as_mut_ptr() as usize),
+        0,
+    )
+}
+
+/// Write a single byte to the firmware's console, waiting until it's sent
+pub fn console_write_byte(byte: u8) -> SbiResult<()> {
+    sbi_call(EXT_DBCN, 2, byte as usize, 0, 0).map(|_| ())
+}
@@ -0,0 +1,113 @@
+// Sleeping and waking up, the way code waits for something that might take a while (like a disk read)
+// without spinning. This works like xv6: you sleep on a "channel", which is just a number
+// (usually the address of whatever you're waiting on), and someone else calls wakeup with the same
+// channel when it's ready.
+
+// We don't have processes or a scheduler yet, so for now the thing that sleeps is the whole hart,
+// it sits in Cpu::idle until wakeup pokes it with an IPI (see ipi.rs). Each hart counts as one kernel "process" with its hart ID
+// as its pid. Once we have a real scheduler, sleep will switch to another process instead and
+// these will be the only functions that need to change.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register;
+
+use crate::{
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    ipi,
+    spinlock::{disable_interrupts, RawLock, SpinlockGuard},
+};
+
+// A process ID
+pub type Pid = usize;
+
+// What each hart is sleeping on, NOT_SLEEPING if it isn't
+static SLEEPING_ON: [AtomicUsize; NUM_CPUS] = [const { AtomicUsize::new(NOT_SLEEPING) }; NUM_CPUS];
+const NOT_SLEEPING: usize = 0;
+
+// The registers we need to save to switch from one thing to another on the same hart.
+// Switching is just a function call, so the caller already saved every register it cares about
+// except the callee-saved ones: ra (where to go back to), sp and s0-s11.
+// Nothing switches yet, this is the scheduler's spot in each Cpu for when it does
+#[repr(C)]
+pub struct Context {
+    pub ra: usize,
+    pub sp: usize,
+    pub s: [usize; 12],
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Self {
+            ra: 0,
+            sp: 0,
+            s: [0; 12],
+        }
+    }
+}
+
+// The pid of whatever is running on this hart
+pub fn current_pid() -> Pid {
+    let interrupts = disable_interrupts();
+    let process = Cpu::mine(&interrupts).process;
+    process.expect("current_pid: nothing running on this hart")
+}
+
+// Give up `guard`'s lock and sleep until someone calls wakeup(channel), then take the lock again
+// Holding the lock while we mark ourselves as sleeping means we can't miss a wakeup, as long as
+// whoever wakes us holds the same lock when they change whatever we're waiting for
+pub fn sleep<'a, T, R: RawLock>(
+    channel: usize,
+    guard: SpinlockGuard<'a, T, R>,
+) -> SpinlockGuard<'a, T, R> {
+    if channel == NOT_SLEEPING {
+        panic!("sleep: channel 0");
+    }
+    if Cpu::in_interrupt() {
+        panic!("sleep: in interrupt handler");
+    }
+    // The lock guard means interrupts are off, so it works for getting at our Cpu
+    let cpu = Cpu::mine(SpinlockGuard::interrupts(&guard));
+    // The lock we were given should be the only thing keeping interrupts off, if we were holding
+    // any other spinlock we'd be sleeping with it, and everyone else waiting on it would spin until we woke up
+    if cpu.interrupt_disable_count != 1 {
+        panic!("sleep: holding other locks");
+    }
+    if !cpu.interrupts_were_on {
+        panic!("sleep: interrupts off, we'd never wake up");
+    }
+    drop(cpu);
+
+    let hart = Cpu::get_id();
+    SLEEPING_ON[hart].store(channel, Ordering::Release);
+    let lock = SpinlockGuard::spinlock(&guard);
+    drop(guard);
+
+    // wakeup sends us an IPI, and we check with interrupts off right before `wfi` so one landing in between
+    // still wakes us up (see Cpu::idle_unless). That means we don't need a timer to come and check for us
+    let woken = || SLEEPING_ON[hart].load(Ordering::Acquire) != channel;
+    while !woken() {
+        Cpu::idle_unless(woken);
+    }
+
+    lock.lock()
+}
+
+// Wake up everything sleeping on `channel`
+pub fn wakeup(channel: usize) {
+    for (hart, sleeping_on) in SLEEPING_ON.iter().enumerate() {
+        let woken = sleeping_on
+            .compare_exchange(channel, NOT_SLEEPING, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        // Poke it, that's the only thing that wakes it up
+        if woken {
+            ipi::send_ipi(hart);
+        }
+    }
+}
+
+// Whether we're allowed to sleep right now, for things that want to check before they try
+pub fn can_sleep() -> bool {
+    register::sstatus::read().sie() && !Cpu::in_interrupt()
+}
@@ -0,0 +1,76 @@
+// A sequence lock lets readers read without taking any lock at all.
+// There's a counter that writers bump before and after they change the data, so it's odd while a
+// write is happening. A reader notes the counter, copies the data out, then checks the counter again:
+// if it's odd or it changed, a writer got in the way and the reader just tries again.
+
+// Readers never make anyone wait, which is great for things that are read constantly and written
+// rarely (like the time). The data has to be Copy since readers might see a half-written copy,
+// which they then throw away. Writers take a normal Spinlock between themselves, so interrupts are
+// off while writing, and an interrupt handler on the same hart can never see a write half done.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+};
+
+use crate::spinlock::Spinlock;
+
+pub struct SeqLock<T: Copy> {
+    sequence: AtomicUsize,
+    // Only one writer at a time
+    writer: Spinlock<()>,
+    data: UnsafeCell<T>,
+}
+
+// Safety: readers only get copies, and writers are kept apart by `writer`
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            sequence: AtomicUsize::new(0),
+            writer: Spinlock::new(name, ()),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // Get a copy of the data, this never waits on a lock, but might have to try a few times
+    pub fn read(&self) -> T {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before & 1 == 1 {
+                // A writer is in the middle of it
+                core::hint::spin_loop();
+                continue;
+            }
+            // Safety: this might race with a writer, but it's a plain copy of a Copy type
+            // and we throw it away below if it did. read_volatile stops the compiler from
+            // assuming nothing changed underneath us
+            let data = unsafe { self.data.get().read_volatile() };
+            // Make sure we've finished reading the data before we look at the counter again
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return data;
+            }
+        }
+    }
+
+    // Change the data, `f` gets the data to change in place
+    pub fn write(&self, f: impl FnOnce(&mut T)) {
+        let guard = self.writer.lock();
+        // Odd: a write is happening
+        self.sequence.fetch_add(1, Ordering::Relaxed);
+        // Make sure readers see the counter change before any of the data does
+        fence(Ordering::Release);
+        // Safety: we're the only writer, readers only make copies
+        unsafe {
+            let mut data = self.data.get().read_volatile();
+            f(&mut data);
+            self.data.get().write_volatile(data);
+        }
+        // Even again: done, this has to come after all of the data
+        self.sequence.fetch_add(1, Ordering::Release);
+        drop(guard);
+    }
+}
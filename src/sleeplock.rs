@@ -0,0 +1,118 @@
+// A sleep lock is a lock you can hold for a long time, like across a disk read.
+// A spinlock keeps interrupts off and makes everyone else waiting spin, which is fine for a few
+// instructions but terrible for a few milliseconds. Waiting for a sleep lock sleeps instead (see sched.rs),
+// and holding one leaves interrupts on.
+
+// The catch is sleeping isn't allowed in an interrupt handler (there's nobody to switch to),
+// so taking a sleep lock from one panics. Filesystem inodes, buffer cache entries and device
+// requests should use these, everything quick should stick to a Spinlock.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{
+    cpu::Cpu,
+    sched::{self, Pid},
+    spinlock::Spinlock,
+};
+
+struct State {
+    locked: bool,
+    // Who is holding the lock, handy for debugging
+    holder: Option<Pid>,
+}
+
+pub struct SleepLock<T> {
+    // Protects `state`, this is only ever held for a moment
+    state: Spinlock<State>,
+    data: UnsafeCell<T>,
+}
+
+// Safety: same as Spinlock, only the holder can get at the data
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+impl<T> SleepLock<T> {
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Self {
+            state: Spinlock::new(
+                name,
+                State {
+                    locked: false,
+                    holder: None,
+                },
+            ),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    // The channel we sleep on while waiting for this lock
+    fn channel(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    // Take the lock, sleeping until it's free
+    // Panics if called from an interrupt handler, or while holding a spinlock
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        if Cpu::in_interrupt() {
+            panic!("sleeplock: acquired in interrupt handler");
+        }
+        let mut state = self.state.lock();
+        if state.holder == Some(sched::current_pid()) {
+            panic!("sleeplock: already holding");
+        }
+        while state.locked {
+            state = sched::sleep(self.channel(), state);
+        }
+        state.locked = true;
+        state.holder = Some(sched::current_pid());
+        drop(state);
+        SleepLockGuard { lock: self }
+    }
+
+    // Whether we're the one holding the lock
+    pub fn holding(&self) -> bool {
+        let state = self.state.lock();
+        state.locked && state.holder == Some(sched::current_pid())
+    }
+
+    // Who is holding the lock right now, if anyone
+    pub fn holder(&self) -> Option<Pid> {
+        self.state.lock().holder
+    }
+}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: we hold the lock, so nobody else is touching the data
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: same as above
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        if state.holder != Some(sched::current_pid()) {
+            panic!("sleeplock: released by someone else");
+        }
+        state.locked = false;
+        state.holder = None;
+        // Wake up anyone waiting while we still hold `state`, so they can't miss it
+        sched::wakeup(self.lock.channel());
+    }
+}
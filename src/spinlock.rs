@@ -3,38 +3,65 @@
 // if it can't, it will keep trying until it can. This is a very simple way to handle mutual
 
 use core::{
-    ops::Deref,
-    sync::atomic::{AtomicBool, Ordering},
+    cell::UnsafeCell,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
 use riscv::register;
 
-use crate::cpu::Cpu;
+use crate::cpu::{Cpu, CPUS};
 
-// First, get whether or not interrupts are enabled so we can set it back to that once the lock is released
+#[cfg(feature = "lockdep")]
+use crate::lockdep::{self, LockClass};
+
+#[cfg(feature = "lock-stats")]
+use crate::lockstat::LockStats;
+
+// Proof that interrupts are off on this hart, you get one from disable_interrupts and when it's dropped
+// interrupts go back to how they were before. PerCpu (see percpu.rs) wants one of these before it
+// hands out this hart's data. It's only true on the hart that made it, so it can't be sent to another one
+pub struct InterruptGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        enable_interrupts();
+    }
+}
+
+// First, get whether or not interrupts are enabled so we can set it back to that once the guard is dropped
 // Then, disable interrupts so we can safely take the lock without being interrupted
-pub fn disable_interrupts() {
+#[must_use = "interrupts go back on as soon as the guard is dropped"]
+pub fn disable_interrupts() -> InterruptGuard {
     unsafe {
         let old = register::sstatus::read().sie();
         register::sstatus::clear_sie();
-        let cpu = Cpu::mine();
+        // We can't use Cpu::mine here since we're the ones who make the guard it needs,
+        // but interrupts are off now so nothing else on this hart can be touching it
+        let cpu = &mut *CPUS.remote(Cpu::get_id());
         if cpu.interrupt_disable_count == 0 {
             cpu.interrupts_were_on = old;
         }
         cpu.interrupt_disable_count += 1;
     }
+    InterruptGuard {
+        _not_send: PhantomData,
+    }
 }
 
-// Once the lock is released, we need to set the interrupt state back to what it was before we took the lock
-pub fn enable_interrupts() {
+// Once the guard is dropped, we need to set the interrupt state back to what it was before we took it
+fn enable_interrupts() {
     unsafe {
         // Check if interrupts are on, if they are, panic as we're enabling interrupts while holding a lock
         let is_on = register::sstatus::read().sie();
         if is_on {
             panic!("interrupts_on_enable");
         }
-        // Get the current CPU, we know interrupts are off so we can call this.
-        let cpu = Cpu::mine();
+        // Get the current CPU, we know interrupts are off so nothing else on this hart is touching it
+        let cpu = &mut *CPUS.remote(Cpu::get_id());
         // Decrement the interrupt disable count
         cpu.interrupt_disable_count -= 1;
         // If the count is 0 and interrupts were on before we disabled them, set the SIE bit again
@@ -46,85 +73,221 @@ pub fn enable_interrupts() {
     }
 }
 
-pub struct Spinlock {
-    pub cpu: Option<usize>,
-    pub locked: AtomicBool,
+// How a lock actually gets taken and given back. The Spinlock below takes care of interrupts,
+// checking for mistakes, statistics and handing out the data, and leaves the actual locking to one of these.
+// There's a few ways to do it with different trade-offs:
+// - TestAndSet (below): simplest, but not fair, a hart can keep losing the race forever
+// - TicketLock (ticketlock.rs): harts get the lock in the order they asked for it
+// - McsLock (mcslock.rs): also fair, and every hart spins on its own memory so they don't fight over a cache line
+pub trait RawLock {
+    // An unlocked lock, an associated const so Spinlock::new can stay a const fn
+    // Clippy warns about consts with atomics in them since every use is a fresh copy,
+    // but a fresh copy is exactly what each new lock wants
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self;
+
+    // WARNING: Must be called with interrupts disabled
+    // Spin until we have the lock, returns how many times we had to go round the loop waiting
+    fn acquire(&self) -> u64;
+
+    // WARNING: Must be called with interrupts disabled, by whoever holds the lock
+    fn release(&self);
+
+    // Whether anyone is holding the lock right now
+    fn is_locked(&self) -> bool;
 }
 
-impl Spinlock {
-    // Constructor for a new Spinlock
-    // This creates a spin::Mutex with a Spinlock inside, and returns it
-    pub const fn new() -> Spinlock {
-        Spinlock {
-            cpu: None,
-            locked: AtomicBool::new(false),
+// The original lock: a single flag, whoever manages to flip it from false to true gets the lock
+pub struct TestAndSet {
+    locked: AtomicBool,
+}
+
+// The most times we'll wait between tries, see acquire below
+const MAX_BACKOFF: u64 = 64;
+
+impl RawLock for TestAndSet {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = TestAndSet {
+        locked: AtomicBool::new(false),
+    };
+
+    fn acquire(&self) -> u64 {
+        let mut spins = 0;
+        let mut backoff = 1;
+        // Keep trying until we can get the lock, this is a very simple way to handle mutual exclusion
+        while self.locked.swap(true, Ordering::Acquire) {
+            // Someone else has it. Instead of hammering it with swaps (which are writes, so every hart's cache
+            // fights over the line) we just read it until it looks free, waiting a bit longer each time we lose
+            while self.locked.load(Ordering::Relaxed) {
+                for _ in 0..backoff {
+                    core::hint::spin_loop();
+                }
+                spins += 1;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
         }
+        spins
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::Release);
     }
 
-    // Initialize a Spinlock, this will set the lock to Some(Spinlock)
-    // We want to make sure only one thread initialized a spinlock
-    // which is why we don't just set it directly in the static and instead
-    // make an option
-    pub fn init(lock: &mut Option<Spinlock>) {
-        *lock = Some(Spinlock::new());
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+// A lock that owns the data it protects, so the only way to get at the data is by taking the lock.
+// Because of that we can put these straight in a normal `static` (no `static mut`, no Option, no init step)
+// and the compiler makes sure we never forget to lock before touching what's inside.
+// `R` is how the locking is done, by default it's the simple TestAndSet above,
+// TicketLock<T> and McsLock<T> are the same thing with a fairer R
+pub struct Spinlock<T, R: RawLock = TestAndSet> {
+    raw: R,
+    // Which CPU is holding the lock, NO_CPU if nobody is
+    cpu: AtomicUsize,
+    // What the lock is called in lock statistics and lockdep reports
+    name: &'static str,
+    // How often this lock gets taken and fought over, only there with the `lock-stats` feature
+    // so locks don't all carry a few counters around for nothing
+    #[cfg(feature = "lock-stats")]
+    stats: LockStats,
+    // Which lockdep class this lock is in, only there with the `lockdep` feature
+    #[cfg(feature = "lockdep")]
+    class: LockClass,
+    // UnsafeCell is how Rust lets us mutate something through a shared reference (&self),
+    // it's up to us to make sure only one person does it at a time, which is what the lock is for
+    data: UnsafeCell<T>,
+}
+
+const NO_CPU: usize = usize::MAX;
+
+// Safety: the lock makes sure only one hart at a time can touch the data, so sharing a Spinlock
+// between harts is fine as long as the data itself can be moved between harts
+unsafe impl<T: Send, R: RawLock + Sync> Sync for Spinlock<T, R> {}
+unsafe impl<T: Send, R: RawLock + Send> Send for Spinlock<T, R> {}
+
+impl<T, R: RawLock> Spinlock<T, R> {
+    // Constructor for a new Spinlock, this is const so it can be used to initialize statics
+    // The name shows up in lock statistics (see lockstat.rs)
+    pub const fn new(name: &'static str, data: T) -> Self {
+        Spinlock {
+            raw: R::UNLOCKED,
+            cpu: AtomicUsize::new(NO_CPU),
+            name,
+            #[cfg(feature = "lock-stats")]
+            stats: LockStats::new(name),
+            #[cfg(feature = "lockdep")]
+            class: LockClass::new(),
+            data: UnsafeCell::new(data),
+        }
     }
 
-    // Acquire a lock on the Spinlock, this will take care of disabling interrupts
-    // and give back a special SpinLockGuard that will enable interrupts and unlock when it's dropped
-    // This function will panic if the lock is already acquired by the current CPU, or if the lock is not initialized
-    pub fn acquire(lock: Option<&mut Spinlock>) -> SpinlockGuard {
+    // Acquire the lock, this will take care of disabling interrupts
+    // and give back a special SpinlockGuard that will enable interrupts and unlock when it's dropped
+    // The guard derefs to the data inside, so `lock.lock().field` just works
+    // This function will panic if the lock is already held by the current CPU
+    pub fn lock(&self) -> SpinlockGuard<'_, T, R> {
         // Disable interrupts as we really really don't want to be interrupted while taking a lock
-        disable_interrupts();
+        let interrupts = disable_interrupts();
         // Get out current CPU to make sure the lock isn't being held by our CPU already
         let cpu = Cpu::get_id();
-        let lock = lock.expect("lock_uninit");
-        if lock.cpu == Some(cpu) {
+        if self.holding() {
             panic!("lock_acq_same_hart");
         }
-        // Keep spinning until we can get the lock, this is a very simple way to handle mutual exclusion
-        while lock.locked.swap(true, Ordering::Acquire) {
-            // Spin until we can get the lock
-        }
+        // Check the order we're taking locks in before we start waiting, if this could deadlock
+        // we want to hear about it rather than actually get stuck
+        #[cfg(feature = "lockdep")]
+        lockdep::acquire(&self.class, self.name);
+        let spins = self.raw.acquire();
         // We now have the lock! Set the CPU to the current CPU and return a SpinlockGuard
-        lock.cpu = Some(cpu);
-        SpinlockGuard(lock)
+        self.cpu.store(cpu, Ordering::Relaxed);
+        #[cfg(feature = "lock-stats")]
+        self.stats.acquired(spins);
+        #[cfg(not(feature = "lock-stats"))]
+        let _ = spins;
+        SpinlockGuard {
+            lock: self,
+            interrupts,
+        }
+    }
+
+    // WARNING: Must be called with interrupts disabled
+    // Whether the current CPU is the one holding this lock
+    pub fn holding(&self) -> bool {
+        self.raw.is_locked() && self.cpu.load(Ordering::Relaxed) == Cpu::get_id()
+    }
+
+    // If we have the only reference to the lock nobody else can be holding it,
+    // so we can get at the data without locking
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
     }
 }
 
-// This is a guard for the Spinlock, it will enable interrupts when it's dropped
-pub struct SpinlockGuard<'a>(&'a mut Spinlock);
+// If a lock goes away we need to make sure lockstat doesn't keep pointing at it
+#[cfg(feature = "lock-stats")]
+impl<T, R: RawLock> Drop for Spinlock<T, R> {
+    fn drop(&mut self) {
+        self.stats.forget();
+    }
+}
 
-// Implement Deref for SpinlockGuard so we can access the Spinlock inside easily
-impl Deref for SpinlockGuard<'_> {
-    type Target = Spinlock;
+// This is a guard for the Spinlock, it unlocks and enables interrupts when it's dropped
+// While you have one of these you're the only one who can touch the data
+pub struct SpinlockGuard<'a, T, R: RawLock = TestAndSet> {
+    lock: &'a Spinlock<T, R>,
+    // Interrupts stay off for as long as we hold the lock, this turns them back on after we let go
+    // (fields get dropped after Drop::drop below runs)
+    interrupts: InterruptGuard,
+}
 
-    fn deref(&self) -> &Spinlock {
-        self.0
+impl<'a, T, R: RawLock> SpinlockGuard<'a, T, R> {
+    // The lock this guard is for, so it can be taken again after letting go (see sched::sleep)
+    // This isn't a method so it doesn't get mixed up with methods on the data inside
+    pub fn spinlock(guard: &Self) -> &'a Spinlock<T, R> {
+        guard.lock
+    }
+
+    // Holding a lock means interrupts are off, so a lock guard works for PerCpu too
+    pub fn interrupts(guard: &Self) -> &InterruptGuard {
+        &guard.interrupts
+    }
+}
+
+// Implement Deref and DerefMut for SpinlockGuard so we can access the data inside easily
+impl<T, R: RawLock> Deref for SpinlockGuard<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: we hold the lock, so nobody else is touching the data
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T, R: RawLock> DerefMut for SpinlockGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: same as above
+        unsafe { &mut *self.lock.data.get() }
     }
 }
 
 // Implement Drop for SpinlockGuard so we can unlock and enable interrupts when it's dropped
-impl Drop for SpinlockGuard<'_> {
+impl<T, R: RawLock> Drop for SpinlockGuard<'_, T, R> {
     fn drop(&mut self) {
         // Get the CPU ID and ensure we're the one that acquired the lock
         // If we're not, panic as something went wrong
-        let cpu = Cpu::get_id();
-        if self.0.cpu != Some(cpu) {
+        if !self.lock.holding() {
             panic!("lock_rel_diff_hart");
         }
-        // We know we have the lock, so we can release it, set the CPU to None
-        // and then re-enable interrupts so other CPUs can take the lock
-        self.0.cpu = None;
-        self.0.locked.store(false, Ordering::Release);
-        enable_interrupts();
+        #[cfg(feature = "lock-stats")]
+        self.lock.stats.released();
+        #[cfg(feature = "lockdep")]
+        lockdep::release(&self.lock.class);
+        // We know we have the lock, so we can release it and set the CPU to nobody
+        // Interrupts come back on right after this, when `interrupts` gets dropped
+        self.lock.cpu.store(NO_CPU, Ordering::Relaxed);
+        self.lock.raw.release();
     }
 }
-
-// Macro to define a new spinlock and give it a static lifetime
-// This starts it out as None, and it should be initialized later
-#[macro_export]
-macro_rules! spinlock {
-    ($name:ident) => {
-        static mut $name: Option<$crate::spinlock::Spinlock> = None;
-    };
-}
@@ -0,0 +1,211 @@
+// Swap: somewhere for pages with something in them to go when memory runs out.
+// The reclaimer (see reclaim.rs) can only throw away pages that were never written to, since those are
+// all zeroes anyway. With a swap disk it can take dirty pages too: what's in the page gets written to a
+// "slot" on the disk (one page's worth of sectors), and the page table entry says which slot it's in.
+// When the page is next used handle_page_fault in vm.rs reads it back into a new page and frees the slot.
+
+// The swap disk is a virtio block device with the serial number "swap", `just qemu-swap` gives QEMU one.
+// Without it we don't swap and only clean pages get reclaimed.
+
+// Taking a page goes: pick a slot, mark the page table entry as leaving, shoot the page out of every TLB,
+// write it to the slot, and only then point the entry at the slot and free the page. A fault while it's
+// leaving just puts the entry back the way it was (see handle_page_fault in vm.rs), so a fault never has
+// to wait for a write, and once an entry says a page is in a slot it really is on the disk. If the write
+// fails the page stays where it is.
+
+// There's two locks: SWAP for which slots are taken, which is only ever held for a moment, and DISK for
+// the disk itself, which is held for as long as a read or write takes. Nothing waits on anything else
+// while holding DISK, so harts waiting on it only have to wait for the disk
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    kalloc::PAGE_SIZE,
+    memlayout::phys_to_virt,
+    platform::platform,
+    println,
+    spinlock::Spinlock,
+    ticketlock::TicketLock,
+    virtio::{self, BlockDevice, VirtioError, SECTOR_SIZE},
+    vm::{self, AddressSpace},
+};
+
+// What QEMU's `serial=` has to be for us to use a disk as swap
+const SWAP_SERIAL: &str = "swap";
+// The most slots we keep track of (128MiB of swap), any more of the disk than that goes unused
+const MAX_SLOTS: usize = 1 << 15;
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+struct Swap {
+    // How many slots the disk has room for, 0 if there's no swap disk
+    slots: usize,
+    // Bit N is set when slot N is taken
+    used: [u64; MAX_SLOTS / 64],
+    // Where to start looking for a free slot
+    next: usize,
+    in_use: usize,
+}
+
+static SWAP: Spinlock<Swap> = Spinlock::new(
+    "swap",
+    Swap {
+        slots: 0,
+        used: [0; MAX_SLOTS / 64],
+        next: 0,
+        in_use: 0,
+    },
+);
+
+// It's a spinlock rather than a sleep lock because pages get swapped in from the page fault handler, which
+// can't sleep. A ticket lock so that a hart that's been waiting for a while gets the disk next
+static DISK: TicketLock<Option<BlockDevice>> = TicketLock::new("swap disk", None);
+
+// How many pages have gone out to the disk and come back
+static SWAPPED_OUT: AtomicUsize = AtomicUsize::new(0);
+static SWAPPED_IN: AtomicUsize = AtomicUsize::new(0);
+
+impl Swap {
+    fn is_used(&self, slot: usize) -> bool {
+        self.used[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn sector(slot: usize) -> u64 {
+        slot as u64 * SECTORS_PER_SLOT
+    }
+}
+
+// Look for the swap disk, called once by the boot hart after paging is on
+pub fn init() {
+    let Some(disk) = virtio::find_block_device(SWAP_SERIAL) else {
+        println!("No swap disk (a virtio disk with serial={SWAP_SERIAL}), only clean pages can be reclaimed");
+        return;
+    };
+    let slots = ((disk.capacity() / SECTORS_PER_SLOT) as usize).min(MAX_SLOTS);
+    println!(
+        "Swap: {} slots ({}MiB) on the virtio disk",
+        slots,
+        slots * PAGE_SIZE / (1024 * 1024)
+    );
+    *DISK.lock() = Some(disk);
+    SWAP.lock().slots = slots;
+}
+
+// Whether there's a swap disk to put pages on
+pub fn available() -> bool {
+    SWAP.lock().slots != 0
+}
+
+// (slots in use, slots there are), (pages swapped out, pages swapped in)
+pub fn stats() -> ((usize, usize), (usize, usize)) {
+    let swap = SWAP.lock();
+    (
+        (swap.in_use, swap.slots),
+        (
+            SWAPPED_OUT.load(Ordering::Relaxed),
+            SWAPPED_IN.load(Ordering::Relaxed),
+        ),
+    )
+}
+
+// Get a slot for a page the caller's about to take away. None if there's no swap disk or it's full
+pub fn reserve() -> Option<usize> {
+    let mut swap = SWAP.lock();
+    let slots = swap.slots;
+    let slot = (0..slots)
+        .map(|i| (swap.next + i) % slots)
+        .find(|&slot| !swap.is_used(slot))?;
+    swap.used[slot / 64] |= 1 << (slot % 64);
+    swap.next = (slot + 1) % slots;
+    swap.in_use += 1;
+    Some(slot)
+}
+
+// Write the page at `physical` out to `slot`. Nobody can be writing to the page any more, it's been shot
+// out of every TLB
+pub fn write_out(slot: usize, physical: usize) -> Result<(), VirtioError> {
+    // There's only slots to hand out when there's a disk
+    DISK.lock()
+        .as_mut()
+        .expect("swap: a slot but no disk")
+        .write(
+            Swap::sector(slot),
+            phys_to_virt(physical) as *const u8,
+            PAGE_SIZE,
+        )?;
+    SWAPPED_OUT.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+// Read what's in `slot` into the page at `page`. The slot's still taken afterwards, free it once the page
+// table entry points at the page
+pub fn read_in(slot: usize, page: *mut u8) -> Result<(), VirtioError> {
+    DISK.lock()
+        .as_mut()
+        .expect("swap: a slot but no disk")
+        .read(Swap::sector(slot), page, PAGE_SIZE)?;
+    SWAPPED_IN.fetch_add(1, Ordering::Relaxed);
+    Ok(())
+}
+
+// Give `slot` back, its page is back in memory or not wanted any more
+pub fn free_slot(slot: usize) {
+    let mut swap = SWAP.lock();
+    assert!(swap.is_used(slot), "free_slot: slot {slot} isn't in use");
+    swap.used[slot / 64] &= !(1 << (slot % 64));
+    swap.in_use -= 1;
+}
+
+// Run something that needs more memory than there is: reserve 16MiB more pages than we have RAM, write
+// to every one of them, then check every one still has what we wrote. Plenty of them have to go out to
+// the disk and come back for that to work. Called by the boot hart with interrupts on when the kernel's
+// built with the `swap-stress` feature
+pub fn stress() {
+    if !available() {
+        println!("Swap stress: no swap disk, skipping");
+        return;
+    }
+    const RW: usize = vm::PTE_READ | vm::PTE_WRITE;
+    let memory = platform().memory_end - platform().memory_start;
+    let npages = (memory + 16 * 1024 * 1024) / PAGE_SIZE;
+    let va = vm::max_virtual_address() / 2;
+    let mut space = AddressSpace::new().expect("swap stress: no memory");
+    space
+        .reserve_pages(va, npages, RW)
+        .expect("swap stress: reserve_pages");
+    let page = |i: usize| (va + i * PAGE_SIZE) as *mut usize;
+    // Something different at both ends of every page, so a page coming back in the wrong place shows up
+    let first = |i: usize| i ^ 0x5a5a_5a5a;
+    let last = |i: usize| !i;
+    let words = PAGE_SIZE / core::mem::size_of::<usize>();
+
+    vm::switch_to(&space);
+    for i in 0..npages {
+        unsafe {
+            page(i).write_volatile(first(i));
+            page(i).add(words - 1).write_volatile(last(i));
+        }
+    }
+    for i in 0..npages {
+        let (a, b) = unsafe {
+            (
+                page(i).read_volatile(),
+                page(i).add(words - 1).read_volatile(),
+            )
+        };
+        assert!(
+            a == first(i) && b == last(i),
+            "swap stress: page {i} came back wrong ({a:#x}, {b:#x})"
+        );
+    }
+    vm::switch_to_kernel();
+
+    space.unmap(va, npages, true).expect("swap stress: unmap");
+    space.destroy();
+    let ((in_use, _), (out, back)) = stats();
+    assert_eq!(in_use, 0, "swap stress: slots left over after unmapping");
+    println!(
+        "Swap stress OK: {}MiB of pages in {}MiB of RAM, {out} pages swapped out and {back} back in",
+        npages * PAGE_SIZE / (1024 * 1024),
+        memory / (1024 * 1024),
+    );
+}
@@ -0,0 +1,81 @@
+// This module handles system calls, the way user programs ask the kernel to do things for them.
+// A program puts the syscall number in a7 and its arguments in a0-a2 and runs `ecall`,
+// we do the work and put the result back in a0. Negative results are errors.
+
+// We don't have user processes yet, so nothing can actually make an `ecall` to us.
+// Once we do, the user trap handler will call `syscall` below with the registers it saved.
+// Until then the kernel calls it directly (see self_test) to make sure everything works.
+
+use crate::{clock, timer_wheel};
+
+// The syscall numbers, these go in a7
+pub const SYS_SLEEP: usize = 1; // sleep(milliseconds)
+pub const SYS_NANOSLEEP: usize = 2; // nanosleep(seconds, nanoseconds)
+pub const SYS_UPTIME: usize = 3; // uptime() -> nanoseconds since boot
+
+// The errors we can give back, as negative numbers in a0
+pub const EINVAL: isize = -22; // An argument didn't make sense
+pub const ENOSYS: isize = -38; // There's no syscall with that number
+
+const NANOS_PER_MILLI: u64 = 1_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Do a system call, `args` are a0-a2
+pub fn syscall(number: usize, args: [usize; 3]) -> isize {
+    match number {
+        SYS_SLEEP => sys_sleep(args[0] as u64),
+        SYS_NANOSLEEP => sys_nanosleep(args[0] as u64, args[1] as u64),
+        SYS_UPTIME => sys_uptime(),
+        _ => ENOSYS,
+    }
+}
+
+// Sleep for some number of milliseconds
+fn sys_sleep(millis: u64) -> isize {
+    match millis.checked_mul(NANOS_PER_MILLI) {
+        Some(nanos) => {
+            timer_wheel::sleep_nanos(nanos);
+            0
+        }
+        None => EINVAL,
+    }
+}
+
+// Sleep for a number of seconds plus some nanoseconds, for waits shorter than a tick
+// Like the real nanosleep, the nanoseconds have to be less than a whole second
+fn sys_nanosleep(seconds: u64, nanos: u64) -> isize {
+    if nanos >= NANOS_PER_SECOND {
+        return EINVAL;
+    }
+    match seconds
+        .checked_mul(NANOS_PER_SECOND)
+        .and_then(|total| total.checked_add(nanos))
+    {
+        Some(total) => {
+            timer_wheel::sleep_nanos(total);
+            0
+        }
+        None => EINVAL,
+    }
+}
+
+// How long we've been running, in nanoseconds
+// This reads the `time` register directly so it's much finer grained than ticks
+fn sys_uptime() -> isize {
+    clock::now_nanos() as isize
+}
+
+// Try out the time syscalls, called once by the boot hart at startup
+pub fn self_test() {
+    let before = syscall(SYS_UPTIME, [0; 3]);
+    assert_eq!(syscall(SYS_SLEEP, [10, 0, 0]), 0);
+    assert_eq!(syscall(SYS_NANOSLEEP, [0, 500_000, 0]), 0);
+    assert_eq!(
+        syscall(SYS_NANOSLEEP, [0, NANOS_PER_SECOND as usize, 0]),
+        EINVAL
+    );
+    assert_eq!(syscall(usize::MAX, [0; 3]), ENOSYS);
+    let slept = syscall(SYS_UPTIME, [0; 3]) - before;
+    assert!(slept >= 10_500_000, "syscall self test: woke up too early");
+    println!("Time syscalls OK, slept {}us", slept / 1000);
+}
@@ -0,0 +1,50 @@
+// A ticket lock works like the ticket machine at a deli counter.
+// Everyone who wants the lock takes the next number, then waits until it's called.
+// Unlike TestAndSet (see spinlock.rs) nobody can cut in line, so harts get the lock in the order
+// they asked for it and nobody waits forever.
+
+// The downside is every waiting hart is reading the same `now_serving` counter, so every release
+// has to update the cache of every waiter. McsLock (see mcslock.rs) fixes that.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::spinlock::{RawLock, Spinlock};
+
+// A Spinlock that hands out the lock in order, use it like any other Spinlock
+pub type TicketLock<T> = Spinlock<T, Ticket>;
+
+pub struct Ticket {
+    // The number the next hart to come along will get
+    next_ticket: AtomicUsize,
+    // The number that's allowed to have the lock right now
+    now_serving: AtomicUsize,
+}
+
+impl RawLock for Ticket {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED: Self = Ticket {
+        next_ticket: AtomicUsize::new(0),
+        now_serving: AtomicUsize::new(0),
+    };
+
+    fn acquire(&self) -> u64 {
+        // Take a number, these wrap around but that's fine, we only ever compare them for equality
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let mut spins = 0;
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            core::hint::spin_loop();
+            spins += 1;
+        }
+        spins
+    }
+
+    fn release(&self) {
+        // Only the holder ever changes now_serving, so we don't need a fetch_add here
+        let next = self.now_serving.load(Ordering::Relaxed).wrapping_add(1);
+        self.now_serving.store(next, Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+}
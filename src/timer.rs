@@ -1,94 +1,309 @@
 // Handles the timer interrupts
 
-use core::{arch::global_asm, ptr::addr_of_mut};
+// There are three ways we can get timer interrupts, from best to worst:
+// 1. Sstc: newer harts have a `stimecmp` register, so supervisor mode can set its own timer
+//    and gets a real supervisor timer interrupt when it goes off. No machine mode involved!
+// 2. SBI: when we're booted under SBI firmware (the `sbi` feature) we can ask it to set the timer,
+//    and it also sends us a supervisor timer interrupt
+// 3. CLINT: otherwise only machine mode gets timer interrupts, so when the timer goes off we land in
+//    timervec.S in machine mode, which then pokes us with a supervisor *software* interrupt
 
-use riscv::register::{self, stvec::TrapMode};
+// Whichever way it is, the timer is "one-shot": we program it for the next thing this hart needs to
+// wake up for and it fires once. Each hart has a heap of deadlines it's waiting on (see deadline.rs)
+// plus its next tick. With the `tickless` feature there are no ticks at all, so a hart with nothing
+// to wait for never gets a timer interrupt and can sleep in `wfi` (see Cpu::idle) until something happens.
 
-use crate::consts::NUM_CPUS;
+use core::arch::asm;
+#[cfg(not(feature = "sbi"))]
+use core::arch::global_asm;
+
+#[cfg(not(feature = "sbi"))]
+use crate::{ipi::clint_msip_loc, memlayout::phys_to_virt};
+
+use crate::{
+    clock::{self, tick_interval},
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    deadline::{DeadlineHeap, DeadlineId},
+    percpu::PerCpu,
+    platform::platform,
+    spinlock::disable_interrupts,
+    timer_wheel,
+};
+
+// Everything a single hart's timer is waiting on
+struct HartTimer {
+    deadlines: DeadlineHeap,
+    // When the next tick is due, u64::MAX if we're tickless
+    next_tick: u64,
+}
+
+impl HartTimer {
+    const fn new() -> Self {
+        Self {
+            deadlines: DeadlineHeap::new(),
+            next_tick: u64::MAX,
+        }
+    }
+
+    // The earliest thing we need to wake up for
+    fn next_wakeup(&self) -> u64 {
+        self.deadlines
+            .peek()
+            .unwrap_or(u64::MAX)
+            .min(self.next_tick)
+    }
+}
+
+static HART_TIMERS: PerCpu<HartTimer> = PerCpu::new([const { HartTimer::new() }; NUM_CPUS]);
+
+// Whether this machine's harts have the Sstc extension (we find this out from the device tree)
+#[inline]
+fn has_sstc() -> bool {
+    platform().has_sstc
+}
+
+// Write the stimecmp register (CSR 0x14D), we get a supervisor timer interrupt once `time` passes it
+// This also clears a pending timer interrupt if the new value is in the future
+#[inline]
+fn write_stimecmp(value: u64) {
+    unsafe {
+        asm!("csrw 0x14D, {}", in(reg) value);
+    }
+}
+
+// Program this hart's timer to go off at `deadline`, u64::MAX means "never"
+fn program_timer(deadline: u64) {
+    if has_sstc() {
+        write_stimecmp(deadline);
+    } else {
+        // If this fails there's no timer, we'll just never get woken up
+        #[cfg(feature = "sbi")]
+        let _ = crate::sbi::set_timer(deadline);
+        // The CLINT is mapped in the kernel page table (see vm.rs), and each hart only writes its own MTIMECMP.
+        // timervec.S sets it back to u64::MAX when it fires, so it only ever fires once
+        #[cfg(not(feature = "sbi"))]
+        unsafe {
+            (phys_to_virt(clint_mtime_cmp_loc(Cpu::get_id())) as *mut usize)
+                .write_volatile(deadline as usize);
+        }
+    }
+}
+
+// Point the timer at whatever this hart needs to wake up for next
+fn reprogram(timer: &HartTimer) {
+    program_timer(timer.next_wakeup());
+}
+
+// Ask for this hart to get a timer interrupt at `deadline` (a value of the `time` register, see clock.rs)
+// Returns None if this hart is already waiting on too many deadlines, then nothing's going to wake us up for it
+pub fn add_deadline(deadline: u64) -> Option<DeadlineId> {
+    let mut interrupts = disable_interrupts();
+    let mut timer = HART_TIMERS.get_mut(&mut interrupts);
+    let id = timer.deadlines.push(deadline);
+    reprogram(&timer);
+    id
+}
+
+// Forget about a deadline this hart added with add_deadline, returns false if it wasn't there (or already passed)
+pub fn cancel_deadline(id: DeadlineId) -> bool {
+    let mut interrupts = disable_interrupts();
+    let mut timer = HART_TIMERS.get_mut(&mut interrupts);
+    let removed = timer.deadlines.remove(id);
+    reprogram(&timer);
+    removed
+}
+
+// Every hart gets timer interrupts, but we only want to count each tick once
+fn count_tick() {
+    if Cpu::get_id() == platform().boot_hart {
+        clock::tick();
+    }
+}
+
+// Called whenever this hart's timer goes off, whichever of the three ways it got to us
+fn handle_timer() {
+    let now = clock::now();
+    // We're in an interrupt handler so interrupts are already off, this just gets us the guard
+    let mut interrupts = disable_interrupts();
+    let mut timer = HART_TIMERS.get_mut(&mut interrupts);
+
+    // Everything that was waiting for now or earlier has happened, so it can go
+    while timer
+        .deadlines
+        .peek()
+        .is_some_and(|deadline| deadline <= now)
+    {
+        timer.deadlines.pop();
+    }
+
+    // Count any ticks we've reached, we add the interval instead of using `now`
+    // so if we're a bit late the ticks don't slowly drift
+    while timer.next_tick <= now {
+        count_tick();
+        timer.next_tick += tick_interval();
+    }
+
+    // Programming the next wakeup also clears the interrupt, otherwise we'd be right back here
+    reprogram(&timer);
+    drop(timer);
+    drop(interrupts);
+
+    // Now run any kernel timers that are due, they might add new deadlines of their own
+    timer_wheel::run_expired(now);
+}
+
+// Stop this hart's timer altogether, called when it's parked (see hotplug.rs)
+// Whatever it was waiting on has already been handed to another hart
+pub fn park() {
+    let mut interrupts = disable_interrupts();
+    let mut timer = HART_TIMERS.get_mut(&mut interrupts);
+    timer.deadlines = DeadlineHeap::new();
+    timer.next_tick = u64::MAX;
+    reprogram(&timer);
+}
+
+// Start this hart's ticks back up when it comes back from being parked
+pub fn unpark() {
+    let mut interrupts = disable_interrupts();
+    let mut timer = HART_TIMERS.get_mut(&mut interrupts);
+    if !cfg!(feature = "tickless") {
+        timer.next_tick = clock::now() + tick_interval();
+    }
+    reprogram(&timer);
+}
+
+// Called by kernel_trap in trap.rs when we get a supervisor timer interrupt
+pub fn timer_interrupt() {
+    handle_timer();
+}
+
+// Called by ipi::software_interrupt when we get a supervisor software interrupt,
+// timervec.S sends us these when we're using the CLINT (it has already been acknowledged)
+// Other harts send them too, then there's just nothing due yet and we only reprogram the timer
+pub fn timer_software_interrupt() {
+    handle_timer();
+}
+
+// Setup a hart's first tick and get when we first want the timer to go off
+// This is called from timer_init before interrupts are on
+fn first_wakeup(hart_id: usize) -> u64 {
+    // Safety: each hart only touches its own timer and nothing can interrupt us this early
+    let timer = unsafe { &mut *HART_TIMERS.remote(hart_id) };
+    timer.next_tick = if cfg!(feature = "tickless") {
+        u64::MAX
+    } else {
+        clock::now() + tick_interval()
+    };
+    timer.next_wakeup()
+}
 
 // This function takes care of requesting the timer interrupt
 // The timer interrupt is a way for the hardware to tell the CPU to switch contexts.
 // We use this as a sort of "heartbeat" for the kernel, we use it to determine when to switch
 // what active task we're working on. Handling these interrupts is handled by our scheduler,
 // but we need to request the interrupt from the hardware first.
-// Unlike other interrupts, these need to be run in machine mode, so we need to request them here
+// Unlike other interrupts, these need machine mode's help, entry.S already pointed machine mode at
+// timervec.S and anything else it has to change we ask for with machine_set below
 // If our hardware was different (not QEMU), we might be able to do this in supervisor mode
+#[cfg(not(feature = "sbi"))]
 pub fn timer_init() {
-    let hart_id = register::mhartid::read();
+    let hart_id = Cpu::get_id();
+
+    // When we first want the timer to go off, from then on kernel_trap takes care of it
+    let first = first_wakeup(hart_id);
+
+    // Whether or not we have Sstc, other harts poke us through the CLINT's MSIP registers (see ipi.rs),
+    // which only machine mode hears about, so timervec.S always has to be there to pass those on
+    init_machine_handler(hart_id);
+
+    // If the hart has Sstc we don't need any of the machine mode timer trickery below!
+    if has_sstc() {
+        // Turn on Sstc for supervisor mode, this is bit 63 (STCE) of menvcfg
+        machine_set(1 << 63, 0);
+        write_stimecmp(first);
+        return;
+    }
 
     // We're going to request a timer from the CLINT (Core Local Interruptor)
     // This is a hardware peripheral that handles interrupts for each core
     // and is used to request timer interrupts per-core.
-    const INTERVAL: usize = 1_000_000; // Number of cycles, ~1/10th (100ms) of a second when running with QEMU
 
     // Instead of using CSRs, we actually directly write to memory to request the timer interrupt
     // This is quite weird so let's break it down:
     // 1. The CLINT is memory-mapped, so we can write to it like any other memory
-    // 2. The base address of the CLINT is 0x200_0000, so we do some math to get the address of the MTIMECMP register
-    // 3. We need the current time in cycles since boot, which we can get from the mtime register
+    // 2. The base address of the CLINT is 0x200_0000 on QEMU (we get it from the device tree),
+    //    so we do some math to get the address of the MTIMECMP register
+    // 3. When the mtime register (the current time in cycles since boot) passes MTIMECMP, we get an interrupt
     // See the constants below this function for more information on exact addresses and calculations
     unsafe {
-        // Here we're doing some casting to tell rust we're pointing to a u64
-        // `as *const u64` means we're casting the address to a pointer to a u64
-        // and `as *mut u64` means we're casting the address to a mutable pointer to a u64
-        // So, we cast the address of the MTIMECMP register to a *mut u64
-        // And then we set the value at that address to the *const current_time + interval
+        // Here we're doing some casting to tell rust we're pointing to a usize
+        // `as *mut usize` means we're casting the address to a mutable pointer to a usize
+        // We're in supervisor mode with paging on, so we get at it through the direct map (see memlayout.rs)
+        // And then we set the value at that address to when we first want to wake up
         // This is how we request the timer interrupt
-        *(clint_mtime_cmp_loc(hart_id)) = *(CLINT_MTIME_LOC as *const usize) + INTERVAL;
+        *(phys_to_virt(clint_mtime_cmp_loc(hart_id)) as *mut usize) = first as usize;
     }
 
+    // We enable the machine timer interrupt (bit 7 of mie) so they actually start happening
+    machine_set(0, 1 << 7);
+}
+
+// Get machine mode ready to pass timer interrupts and IPIs on to supervisor mode through timervec.S
+#[cfg(not(feature = "sbi"))]
+fn init_machine_handler(hart_id: usize) {
     // Next we need to prepare something called the MTIME scratch space
     // TIMER_SCRATCH (defined below) is a 2D array that stores some information about the timer interrupt
-    // for each core. We need to set the interval, and the address of CLINT_MTIMECMP for each core
-    // So, bare with me here
+    // for each core. We need to set the address of CLINT_MTIMECMP for each core
+    // entry.S already pointed mscratch at our slot, and mtvec at timervec.S
+    // Machine mode doesn't page, so these are physical addresses
     unsafe {
         // Accessing these static muts is safe as we're only accessing the part
         // of the array that corresponds to the current core, meaning we won't
         // ever access memory that we shouldn't
 
-        // We set 3 and 4 here as we'll use the other slots later for
+        // We set 2 here as we'll use the other slots later for
         // our handler
-        TIMER_SCRATCH[hart_id][3] = clint_mtime_cmp_loc(hart_id) as usize;
-        TIMER_SCRATCH[hart_id][4] = INTERVAL;
-
-        // Finally, we write the address of the TIMER_SCRATCH to the mscratch register
-        // so we can access it later when we handle the interrupt
-        register::mscratch::write(addr_of_mut!(TIMER_SCRATCH[hart_id]) as usize);
+        TIMER_SCRATCH[hart_id][2] = clint_mtime_cmp_loc(hart_id);
+        // And 3 is where our MSIP register is, so the handler can clear an IPI once it's passed it on
+        TIMER_SCRATCH[hart_id][3] = clint_msip_loc(hart_id);
     }
+}
 
-    // Finally, we're going to point the mtvec register to our timer interrupt handler
-    // and then enable the machine timer interrupt
+// Ask machine mode to set the bits in `menvcfg` in the menvcfg register and the bits in `mie` in mie.
+// Those are the only machine mode registers we need to change once we've left machine mode. We `ecall`, which
+// entry.S left for machine mode to handle, and timervec.S does it for us
+#[cfg(not(feature = "sbi"))]
+pub fn machine_set(menvcfg: usize, mie: usize) {
     unsafe {
-        // We're setting the mtvec register to point to our timer interrupt handler
-        // Direct mode here means we only have one handler for all interrupts
-        // If we used vectored mode, we could have multiple handlers for different interrupts but
-        // we're going to handle branching in our handler
-        register::mtvec::write(timer_entry as usize, TrapMode::Direct);
-
-        // We enable the machine timer interrupt so they actually
-        // start happening
-        register::mstatus::set_mie(); // Enable machine interrupts
-        register::mie::set_mtimer(); // Enable machine timer interrupts specifically
+        asm!("ecall", in("a0") menvcfg, in("a1") mie);
     }
 }
 
-static mut TIMER_SCRATCH: [[usize; 5]; NUM_CPUS] = [[0; 5]; NUM_CPUS]; // Scratch space for the timer interrupt
+// When we're running under SBI firmware, the firmware owns machine mode and the CLINT
+// so instead we set the timer ourselves with Sstc if we can, or ask the firmware to do it.
+// The interrupt comes in as a supervisor timer interrupt directly, no need for the machine mode trampoline below
+// (The firmware turns on Sstc for us if the hart has it)
+#[cfg(feature = "sbi")]
+pub fn timer_init() {
+    program_timer(first_wakeup(Cpu::get_id()));
+}
 
-const CLINT_LOC: usize = 0x200_0000; // The base address of the CLINT in memory
-const CLINT_MTIME_LOC: usize = CLINT_LOC + 0xBFF8; // The address of the MTIME register
+// Scratch space for the timer interrupt, entry.S points each hart's mscratch at its slot.
+// It's there with SBI firmware too (just not used), so entry.S always has something to point at
+pub static mut TIMER_SCRATCH: [[usize; 4]; NUM_CPUS] = [[0; 4]; NUM_CPUS];
 
-// Calculate the memory location of the MTIMECMP register for a given hart_id
-const fn clint_mtime_cmp_loc(hart_id: usize) -> *mut usize {
-    (CLINT_LOC + 0x4000 + hart_id * 8) as *mut usize
+pub const CLINT_LOC: usize = 0x200_0000; // The default base address of the CLINT in memory (see platform.rs)
+
+// Calculate the physical address of the MTIMECMP register for a given hart_id
+#[cfg(not(feature = "sbi"))]
+fn clint_mtime_cmp_loc(hart_id: usize) -> usize {
+    platform().clint.base + 0x4000 + hart_id * 8
 }
 
 // This asm! block is our timer interrupt handler
 // We use it to handle the interrupt and pass a software interrupt to the supervisor
 // It's a bit complicated, but I'll break it down in the actual file: timervec.S
 // I'd recommend reading *this* file first, as it explains the setup for this handler
+#[cfg(not(feature = "sbi"))]
 global_asm!(include_str!("timervec.S"));
-
-// Expose the timer interrupt entry point to our rust code
-extern "C" {
-    fn timer_entry();
-}
@@ -0,0 +1,330 @@
+// Kernel timers: "call this function at this time".
+// timer.rs only knows how to wake a hart up at a deadline, this module lets the rest of the kernel
+// hang some work off of a deadline. Drivers can use it for timeouts and sleep uses it to wake up.
+
+// The timers live in a "timer wheel". Picture a clock face with WHEEL_SLOTS slots, each one tick wide.
+// A timer goes in the slot its deadline lands in, wrapping around the face if it's far in the future.
+// When the timer goes off we only need to look at the slots between the last time we looked and now,
+// instead of every timer there is. Timers a whole lap (or more) away share a slot with nearer ones,
+// so we still check each deadline before firing it.
+
+// Like deadline.rs we don't want to call into the allocator from the timer interrupt,
+// so all the timers come out of a fixed pool and each slot is a linked list of indexes into it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use riscv::register;
+
+use crate::{
+    clock::{self, tick_interval},
+    consts::NUM_CPUS,
+    cpu::Cpu,
+    deadline::DeadlineId,
+    ipi,
+    spinlock::{disable_interrupts, Spinlock},
+    timer::{add_deadline, cancel_deadline},
+};
+
+// How many slots there are around the wheel, at HZ = 10 one lap is 6.4 seconds
+const WHEEL_SLOTS: usize = 64;
+
+// The most timers that can be waiting at once
+pub const MAX_TIMERS: usize = 64;
+
+// What a timer calls when it goes off, with the argument it was added with
+// WARNING: This runs from the timer interrupt with interrupts off, so keep it short and don't sleep!
+pub type TimerCallback = fn(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// A handle to a timer, used to cancel it
+/// The generation makes sure we don't cancel someone else's timer that reused the same slot in the pool
+pub struct TimerId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    deadline: u64,
+    // None means this entry in the pool is free
+    callback: Option<TimerCallback>,
+    arg: usize,
+    // The hart whose timer we asked to wake up for this one, and what it gave us to cancel that with
+    hart: usize,
+    wakeup: Option<DeadlineId>,
+    // Goes up every time the entry is freed
+    generation: u32,
+    // Which slot it's in, and the next timer in the same slot
+    slot: usize,
+    next: Option<usize>,
+}
+
+impl Timer {
+    const fn empty() -> Self {
+        Self {
+            deadline: 0,
+            callback: None,
+            arg: 0,
+            hart: 0,
+            wakeup: None,
+            generation: 0,
+            slot: 0,
+            next: None,
+        }
+    }
+}
+
+struct TimerWheel {
+    // The first timer in each slot
+    slots: [Option<usize>; WHEEL_SLOTS],
+    timers: [Timer; MAX_TIMERS],
+    // The slot (counted in ticks since boot, not wrapped around) we've looked at up to
+    current: u64,
+}
+
+static WHEEL: Spinlock<TimerWheel> = Spinlock::new(
+    "timer_wheel",
+    TimerWheel {
+        slots: [None; WHEEL_SLOTS],
+        timers: [Timer::empty(); MAX_TIMERS],
+        current: 0,
+    },
+);
+
+// Called once by the boot hart before interrupts are turned on
+pub fn init() {
+    WHEEL.lock().current = slot_of(clock::now());
+}
+
+// Which slot (not wrapped around) a time lands in
+#[inline]
+fn slot_of(time: u64) -> u64 {
+    time / tick_interval()
+}
+
+impl TimerWheel {
+    fn insert(&mut self, index: usize) {
+        // Anything that should have already gone off goes in the slot we're at, so we find it next time
+        let slot =
+            (slot_of(self.timers[index].deadline).max(self.current) % WHEEL_SLOTS as u64) as usize;
+        self.timers[index].slot = slot;
+        self.timers[index].next = self.slots[slot];
+        self.slots[slot] = Some(index);
+    }
+
+    // Take a timer out of its slot
+    fn unlink(&mut self, index: usize) {
+        let slot = self.timers[index].slot;
+        let mut link = self.slots[slot];
+        let mut previous: Option<usize> = None;
+        while let Some(current) = link {
+            if current == index {
+                let next = self.timers[current].next;
+                match previous {
+                    Some(previous) => self.timers[previous].next = next,
+                    None => self.slots[slot] = next,
+                }
+                return;
+            }
+            previous = Some(current);
+            link = self.timers[current].next;
+        }
+    }
+
+    fn free(&mut self, index: usize) {
+        let timer = &mut self.timers[index];
+        timer.callback = None;
+        timer.next = None;
+        timer.generation = timer.generation.wrapping_add(1);
+    }
+}
+
+// Call `callback(arg)` once the `time` register reaches `deadline` (see clock.rs)
+// The timer is run from a timer interrupt on whichever hart notices first, usually the one that added it
+// Returns None if there are already MAX_TIMERS timers waiting, or this hart can't wait on any more deadlines
+pub fn timer_add(deadline: u64, callback: TimerCallback, arg: usize) -> Option<TimerId> {
+    let mut wheel = WHEEL.lock();
+    let index = wheel.timers.iter().position(|t| t.callback.is_none())?;
+    // Make sure we actually wake up for it. We're holding a spinlock, so we can't move to another hart
+    // between asking and writing down which hart we asked
+    let wakeup = add_deadline(deadline)?;
+    let hart = Cpu::get_id();
+    let timer = &mut wheel.timers[index];
+    timer.deadline = deadline;
+    timer.callback = Some(callback);
+    timer.arg = arg;
+    timer.hart = hart;
+    timer.wakeup = Some(wakeup);
+    let generation = timer.generation;
+    wheel.insert(index);
+    Some(TimerId { index, generation })
+}
+
+// Stop a timer from going off, returns false if it already went off (or was already cancelled)
+pub fn timer_cancel(id: TimerId) -> bool {
+    let (wakeup, hart) = {
+        let mut wheel = WHEEL.lock();
+        let timer = wheel.timers[id.index];
+        if timer.callback.is_none() || timer.generation != id.generation {
+            return false;
+        }
+        wheel.unlink(id.index);
+        wheel.free(id.index);
+        (timer.wakeup, timer.hart)
+    };
+    // We can only touch our own hart's deadlines, if it was another hart's it'll just wake up for nothing
+    let interrupts = disable_interrupts();
+    if let Some(wakeup) = wakeup.filter(|_| Cpu::get_id() == hart) {
+        cancel_deadline(wakeup);
+    }
+    drop(interrupts);
+    true
+}
+
+// Run on the hart a timer was just handed to by migrate, so that hart wakes up for it
+fn take_over(index: usize) {
+    let mut wheel = WHEEL.lock();
+    let me = Cpu::get_id();
+    let timer = &mut wheel.timers[index];
+    // It might have gone off (or been cancelled and reused) since migrate let go of the lock,
+    // anything added since already has a wakeup
+    if timer.callback.is_some() && timer.hart == me && timer.wakeup.is_none() {
+        // If we're waiting on too many deadlines already it runs late, the next time our timer goes off
+        timer.wakeup = add_deadline(timer.deadline);
+    }
+}
+
+// Hand every timer waiting on hart `from`'s timer over to hart `to`, called when `from` is being parked
+// (see hotplug.rs). Only a hart can set its own timer, so we get `to` to add the deadlines itself
+pub fn migrate(from: usize, to: usize) {
+    let mut moved = [0; MAX_TIMERS];
+    let mut count = 0;
+    {
+        let mut wheel = WHEEL.lock();
+        for (index, timer) in wheel
+            .timers
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, timer)| timer.callback.is_some() && timer.hart == from)
+        {
+            timer.hart = to;
+            // Its deadline on `from` goes when `from` parks its timer
+            timer.wakeup = None;
+            moved[count] = index;
+            count += 1;
+        }
+    }
+    for &index in &moved[..count] {
+        ipi::run_on(1 << to, take_over, index);
+    }
+}
+
+// Called from the timer interrupt (see handle_timer in timer.rs), runs every timer that's due
+pub fn run_expired(now: u64) {
+    let mut expired: [Option<(TimerCallback, usize)>; MAX_TIMERS] = [None; MAX_TIMERS];
+    let mut count = 0;
+
+    {
+        let mut wheel = WHEEL.lock();
+        let now_slot = slot_of(now);
+        // If we haven't looked in a whole lap or more, look at every slot once
+        let slots = (now_slot.saturating_sub(wheel.current) + 1).min(WHEEL_SLOTS as u64);
+        for offset in 0..slots {
+            let slot = ((wheel.current + offset) % WHEEL_SLOTS as u64) as usize;
+            let mut link = wheel.slots[slot];
+            while let Some(index) = link {
+                link = wheel.timers[index].next;
+                let timer = wheel.timers[index];
+                if timer.deadline <= now {
+                    wheel.unlink(index);
+                    wheel.free(index);
+                    expired[count] = timer.callback.map(|callback| (callback, timer.arg));
+                    count += 1;
+                }
+            }
+        }
+        // We don't move past now_slot, timers later on in it still need to be found next time
+        wheel.current = wheel.current.max(now_slot);
+    }
+
+    // Run the callbacks without holding the lock, so they can add new timers
+    for (callback, arg) in expired.iter().take(count).flatten() {
+        callback(*arg);
+    }
+}
+
+// Set by wake_sleeper when a hart's sleep is over
+static SLEEP_DONE: [AtomicBool; NUM_CPUS] = [const { AtomicBool::new(false) }; NUM_CPUS];
+
+fn wake_sleeper(hart: usize) {
+    SLEEP_DONE[hart].store(true, Ordering::Release);
+}
+
+// Wait until the `time` register reaches `deadline`, without spinning
+// We don't have processes yet, so for now the whole hart sleeps (in Cpu::idle) until the timer wakes it up.
+// Once we have a scheduler this should switch to another process instead
+pub fn sleep_until(deadline: u64) {
+    if !register::sstatus::read().sie() {
+        panic!("sleep_until: interrupts off, we'd never wake up");
+    }
+    // We can't be moved to another hart, so it's fine to grab our ID with interrupts on here
+    let hart = Cpu::get_id();
+    SLEEP_DONE[hart].store(false, Ordering::Release);
+    if timer_add(deadline, wake_sleeper, hart).is_none() {
+        // No timers left, make do with waking up for the deadline directly
+        match add_deadline(deadline) {
+            Some(wakeup) => {
+                while clock::now() < deadline {
+                    Cpu::idle();
+                }
+                // Something else might have woken us up right at the deadline, before our own interrupt
+                cancel_deadline(wakeup);
+            }
+            // Nothing is going to wake us up, so all we can do is spin
+            None => {
+                while clock::now() < deadline {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        return;
+    }
+    while !SLEEP_DONE[hart].load(Ordering::Acquire) {
+        Cpu::idle();
+    }
+}
+
+// Sleep for some number of nanoseconds
+pub fn sleep_nanos(nanos: u64) {
+    sleep_until(clock::now() + clock::nanos_to_cycles(nanos));
+}
+
+// Check adding, cancelling and firing timers works, called once by the boot hart at startup
+pub fn self_test() {
+    static FIRED: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+    fn fire(which: usize) {
+        FIRED[which].store(true, Ordering::Relaxed);
+    }
+
+    let soon = clock::now() + clock::nanos_to_cycles(20_000_000);
+    let kept = timer_add(soon, fire, 0).expect("timer self test: timer_add failed");
+    let cancelled = timer_add(soon, fire, 1).expect("timer self test: timer_add failed");
+    assert!(timer_cancel(cancelled), "timer self test: cancel failed");
+    assert!(!timer_cancel(cancelled), "timer self test: cancelled twice");
+
+    sleep_nanos(50_000_000);
+
+    assert!(
+        FIRED[0].load(Ordering::Relaxed),
+        "timer self test: timer didn't fire"
+    );
+    assert!(
+        !FIRED[1].load(Ordering::Relaxed),
+        "timer self test: cancelled timer fired"
+    );
+    assert!(
+        !timer_cancel(kept),
+        "timer self test: cancelled a timer that already fired"
+    );
+    println!("Kernel timers OK");
+}
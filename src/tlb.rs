@@ -0,0 +1,103 @@
+// Keeping every hart's TLB in line with the page tables.
+// The TLB is the hart's cache of virtual -> physical translations, so it doesn't have to walk the page
+// table on every access. When we change a page table the TLB doesn't notice, we have to flush the old
+// entries out with `sfence.vma`, and that only flushes the hart that runs it. Every hart uses the kernel
+// page table, so after taking a mapping away (or making it stricter) every other hart could still be using
+// the old one. Flushing every hart's TLB is called a "TLB shootdown", we do it with a cross-call (see ipi.rs).
+
+// Adding a mapping doesn't need a shootdown, there was no old translation for anyone to have cached.
+
+// Entries are tagged with the ASID they were cached under (see asid.rs). `sfence.vma` with a register
+// for the ASID only flushes that one ASID, *even if the register holds 0*, only x0 means every ASID.
+// The kernel's mappings get cached under every address space's ASID too, so kernel flushes go for all of them.
+
+use crate::{ipi, kalloc::PAGE_SIZE};
+
+// Past this many pages it's quicker to flush the whole TLB than go page by page
+const FULL_FLUSH_PAGES: usize = 32;
+
+// What to flush, passed to every hart by address since cross-calls only get a usize
+struct FlushRange {
+    start: usize,
+    size: usize,
+    // Just this ASID, or None for every ASID
+    asid: Option<usize>,
+}
+
+// Flush [start, start + size) from this hart's TLB, for every ASID
+pub fn flush_local(start: usize, size: usize) {
+    if size / PAGE_SIZE > FULL_FLUSH_PAGES {
+        flush_local_all();
+        return;
+    }
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < start + size {
+        unsafe {
+            core::arch::asm!("sfence.vma {}, zero", in(reg) page);
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+// Flush [start, start + size) from this hart's TLB, only for address space `asid`
+pub fn flush_local_asid(asid: usize, start: usize, size: usize) {
+    if size / PAGE_SIZE > FULL_FLUSH_PAGES {
+        unsafe {
+            core::arch::asm!("sfence.vma zero, {}", in(reg) asid);
+        }
+        return;
+    }
+    let mut page = start & !(PAGE_SIZE - 1);
+    while page < start + size {
+        unsafe {
+            riscv::asm::sfence_vma(asid, page);
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+// Flush everything from this hart's TLB
+pub fn flush_local_all() {
+    unsafe {
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+fn flush_range_call(range: usize) {
+    // Safety: run_on doesn't return until every hart has done this, so the range is still on the caller's stack
+    let range = unsafe { &*(range as *const FlushRange) };
+    match range.asid {
+        Some(asid) => flush_local_asid(asid, range.start, range.size),
+        None => flush_local(range.start, range.size),
+    }
+}
+
+fn flush_all_call(_arg: usize) {
+    flush_local_all();
+}
+
+// Flush [start, start + size) from every hart's TLB, and wait until they all have
+// Call this after taking a mapping away, before reusing the memory it pointed at
+pub fn shootdown(start: usize, size: usize) {
+    let range = FlushRange {
+        start,
+        size,
+        asid: None,
+    };
+    ipi::run_on_all(flush_range_call, &range as *const FlushRange as usize);
+}
+
+// The same, but only for address space `asid`, for taking a page out of a process's page table
+pub fn shootdown_asid(asid: usize, start: usize, size: usize) {
+    let range = FlushRange {
+        start,
+        size,
+        asid: Some(asid),
+    };
+    ipi::run_on_all(flush_range_call, &range as *const FlushRange as usize);
+}
+
+// Flush every hart's whole TLB, and wait until they all have
+pub fn shootdown_all() {
+    ipi::run_on_all(flush_all_call, 0);
+}
@@ -0,0 +1,143 @@
+// This module handles traps that happen while we're running in the kernel (supervisor mode).
+// A trap is either an interrupt (something outside of our code wants attention, like the timer)
+// or an exception (our code did something wrong, like reading memory that isn't mapped).
+// Either way the CPU stops what it's doing and jumps to the address in the `stvec` register,
+// which we point at `kernelvec` in kernelvec.S, which then calls `kernel_trap` below.
+
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::Ordering,
+};
+
+use riscv::register::{self, sstatus::SPP, stvec::TrapMode};
+
+use crate::{cpu::Cpu, ipi, kstack, plic, spinlock::disable_interrupts, timer, vm};
+
+// The cause codes (from the scause register) for the interrupts we care about
+const INTERRUPT_SUPERVISOR_SOFTWARE: usize = 1;
+const INTERRUPT_SUPERVISOR_TIMER: usize = 5;
+const INTERRUPT_SUPERVISOR_EXTERNAL: usize = 9;
+
+// Human readable names for exception codes, so panics are easier to read
+fn exception_name(code: usize) -> &'static str {
+    match code {
+        0 => "instruction address misaligned",
+        1 => "instruction access fault",
+        2 => "illegal instruction",
+        3 => "breakpoint",
+        4 => "load address misaligned",
+        5 => "load access fault",
+        6 => "store address misaligned",
+        7 => "store access fault",
+        8 => "environment call from user mode",
+        9 => "environment call from supervisor mode",
+        12 => "instruction page fault",
+        13 => "load page fault",
+        15 => "store page fault",
+        _ => "unknown exception",
+    }
+}
+
+// Point this hart's stvec at kernelvec so we handle traps ourselves
+// Every hart has its own stvec, so every hart needs to call this
+pub fn init_hart() {
+    unsafe {
+        // Direct mode means every trap goes to the same place, we figure out what happened in kernel_trap
+        register::stvec::write(kernelvec as usize, TrapMode::Direct);
+        // kernelvec checks there's room left on our stack before it uses it, this is how far down it can go
+        register::sscratch::write(kstack::stack_bottom(Cpu::get_id()));
+    }
+}
+
+// Turn on interrupts for this hart, after this kernel_trap can be called at any time
+pub fn interrupts_on() {
+    unsafe {
+        register::sstatus::set_sie();
+    }
+}
+
+#[no_mangle]
+// Called from kernelvec when a trap happens in supervisor mode
+// When we get here interrupts are automatically turned off, and sepc has the address we were at
+extern "C" fn kernel_trap() {
+    let sepc = register::sepc::read();
+    // All of sstatus too, not just the bits we look at. The page fault handler can turn interrupts back on
+    // (see allocate_page_for_fault), and if one comes in then its sret leaves SPP saying user mode. Our own
+    // sret would then go back to the kernel in user mode
+    let saved_sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) saved_sstatus) };
+    let sstatus = register::sstatus::read();
+    let scause = register::scause::read();
+
+    // Some sanity checks, we should only be here from the kernel and with interrupts off
+    if sstatus.spp() != SPP::Supervisor {
+        panic!("kernel_trap: not from supervisor mode");
+    }
+    if sstatus.sie() {
+        panic!("kernel_trap: interrupts enabled");
+    }
+
+    if scause.is_interrupt() {
+        // Let everyone know they're in an interrupt handler, so things like SleepLock can refuse to wait
+        // Interrupts are already off, the guard is just so we can get at our Cpu
+        let mut interrupts = disable_interrupts();
+        {
+            // Given back before the handlers run, they look at our Cpu too
+            let mut cpu = Cpu::mine_mut(&mut interrupts);
+            cpu.interrupt_depth += 1;
+            cpu.stats.interrupts.fetch_add(1, Ordering::Relaxed);
+        }
+        match scause.code() {
+            // A timer interrupt that was sent to us directly, either via Sstc or SBI firmware
+            INTERRUPT_SUPERVISOR_TIMER => timer::timer_interrupt(),
+            // Another hart poking us (see ipi.rs), or when we don't have Sstc and aren't under SBI
+            // firmware, the timer going through timervec.S in machine mode which sends us one of these instead
+            INTERRUPT_SUPERVISOR_SOFTWARE => ipi::software_interrupt(),
+            // A device wants attention, the PLIC knows which one
+            INTERRUPT_SUPERVISOR_EXTERNAL => plic::external_interrupt(),
+            code => panic!("kernel_trap: unknown interrupt {code}"),
+        }
+        Cpu::mine_mut(&mut interrupts).interrupt_depth -= 1;
+    } else {
+        // A page fault in one of the guard pages under the stacks means that hart (or process) ran out of stack
+        // (12, 13 and 15 are instruction, load and store page faults)
+        let stval = register::stval::read();
+        let page_fault = matches!(scause.code(), 12 | 13 | 15);
+        if page_fault {
+            if let Some(hart) = kstack::guard_page_owner(stval) {
+                panic!("stack overflow on hart {hart} (sepc={sepc:#x} stval={stval:#x})");
+            }
+            if let Some(slot) = kstack::process_guard_page_owner(stval) {
+                panic!("stack overflow on process kernel stack {slot} (sepc={sepc:#x} stval={stval:#x})");
+            }
+        }
+        // Some page faults are expected: setting Accessed/Dirty, or a page the reclaimer took (see vm.rs).
+        // Those get fixed up and we go back and run the same instruction again
+        if !(page_fault && vm::handle_page_fault(stval, scause.code())) {
+            // We don't expect any other exceptions in the kernel, so if one happens something has gone very wrong
+            panic!(
+                "kernel_trap: {} ({}) sepc={:#x} stval={:#x}",
+                exception_name(scause.code()),
+                scause.code(),
+                sepc,
+                stval
+            );
+        }
+    }
+
+    // Put sepc and sstatus back in case anything we called in here caused a trap of its own
+    register::sepc::write(sepc);
+    unsafe { asm!("csrw sstatus, {}", in(reg) saved_sstatus) };
+}
+
+// Like entry.S, we fill in where the spare stacks for overflows are and how big they are
+global_asm!(
+    include_str!("kernelvec.S"),
+    sym kstack::OVERFLOW_STACKS,
+    const kstack::OVERFLOW_STACK_SIZE
+);
+
+// Expose the trap handler entry point to our rust code
+extern "C" {
+    fn kernelvec();
+}
@@ -8,16 +8,19 @@
 use core::sync::atomic::Ordering;
 
 use crate::{
+    console,
     panic::PANICKED,
-    spinlock::{disable_interrupts, enable_interrupts},
+    platform::platform,
+    spinlock::{disable_interrupts, Spinlock},
 };
 
-spinlock!(UART_LOCK);
+static UART_LOCK: Spinlock<()> = Spinlock::new("uart", ());
 
 // The UART like the CLINT is memory-mapped, so we need to know where it is in memory
-// QEMU sets the UART to be at 0x10000000
+// QEMU sets the UART to be at 0x10000000, but we read the real address from the device tree
+// these are just the defaults if we don't have one (see platform.rs)
 pub const UART_LOC0: usize = 0x10000000;
-const UART_LOC0_IRQ: usize = 10;
+pub const UART_LOC0_IRQ: usize = 10;
 
 // Various "registers" of the UART, note that these are not real registers but rather memory addresses
 // When we use memory-mapped I/O, we treat these memory addresses as registers
@@ -39,10 +42,10 @@ mod registers {
 }
 
 // This is a helper function to convert a register number to a memory address
-// It simply adds the base address of the UART to the register number
+// It simply adds the address of the UART (in the direct map) to the register number
 #[inline]
-const fn reg_map(reg: usize) -> usize {
-    UART_LOC0 + reg
+fn reg_map(reg: usize) -> usize {
+    platform().uart.address() + reg
 }
 
 // Sets the value of a register
@@ -102,13 +105,24 @@ pub fn uart_init() {
     write_reg(registers::FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
 
     // Finally, we're going to re-enable interrupts for the UART
-    // This will let us know when the UART has received a byte or is ready to transmit a byte
-    write_reg(registers::IER, IER_RX_ENABLE | IER_TX_ENABLE);
+    // This will let us know when the UART has received a byte (through the PLIC, see plic.rs).
+    // We wait for it to be ready to transmit ourselves (see uart_put_c_sync), so we don't ask for that one
+    write_reg(registers::IER, IER_RX_ENABLE);
+}
+
+// Read a byte from the UART if one has come in, this doesn't wait
+fn uart_get_c() -> Option<u8> {
+    if read_reg(registers::LSR) & LSR_RX_READY != 0 {
+        Some(read_reg(registers::RHR))
+    } else {
+        None
+    }
 }
 
 pub fn uart_put_c_sync(c: char) {
     // Disable interrupts as we don't want to be interrupted while writing to the UART
-    disable_interrupts();
+    // They come back on when `_interrupts` is dropped at the end
+    let _interrupts = disable_interrupts();
 
     // If we've panicked we wanna spin here, so we don't lose the panic message
     if PANICKED.load(Ordering::Relaxed) {
@@ -124,7 +138,12 @@ pub fn uart_put_c_sync(c: char) {
 
     // Write the character to the UART
     write_reg(registers::THR, c as u8);
+}
 
-    // Re-enable interrupts
-    enable_interrupts();
+// Called by plic::external_interrupt when the UART has something for us.
+// Reading every byte that's come in is what clears the interrupt, they go to the console for whoever wants them
+pub fn uart_interrupt() {
+    while let Some(byte) = uart_get_c() {
+        console::receive(byte);
+    }
 }
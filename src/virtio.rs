@@ -1,2 +1,379 @@
+// Talking to virtio devices, just block devices (disks) for now.
+// Virtio is how QEMU's virtual devices talk to us: each one sits in a "virtio-mmio" slot with a few
+// registers, and we hand it requests through a "virtqueue", a ring of buffers in our memory that it
+// reads from and writes back to. We only do the modern (version 2) interface, which is why the justfile
+// passes `-global virtio-mmio.force-legacy=false`.
+
+// A block request is three buffers chained together: a header saying what to do (read or write, and where),
+// the data, and one byte the device writes back to say how it went. We only ever have one request going at a
+// time and spin until the device is done with it, or until we give up on it (see request). That's slow next to
+// a real driver with interrupts, but QEMU is quick about it, and it means we can use a disk from places we
+// can't sleep (like swapping a page back in from the page fault handler, see swap.rs).
+
+use core::sync::atomic::{fence, Ordering};
+
+use crate::{
+    clock,
+    kalloc::{allocate_page, free_page},
+    memlayout::virt_to_phys,
+    platform::platform,
+};
+
+// How long we wait for a request before deciding the device is never going to finish it
+const REQUEST_TIMEOUT_NANOS: u64 = 1_000_000_000;
+
+// Where QEMU puts the first virtio device if we don't get told otherwise by the device tree (see platform.rs)
 pub const VIRTIO0: usize = 0x10001000;
 pub const VIRTIO0_IRQ: usize = 1;
+
+// The registers, as offsets from the device's base address
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DEVICE_FEATURES: usize = 0x010;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+// A block device's config starts with how many sectors it has
+const CONFIG_CAPACITY: usize = 0x100;
+
+// "virt" in little endian
+const MAGIC: u32 = 0x74726976;
+const DEVICE_ID_BLOCK: u32 = 2;
+
+// Bits in STATUS, we set them one at a time as we get further through setting the device up
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+
+// The only feature we ask for: "this is a modern driver" (bit 32, so bit 0 of the second set of features)
+const FEATURE_VERSION_1: u32 = 1;
+
+const REQUEST_READ: u32 = 0;
+const REQUEST_WRITE: u32 = 1;
+const REQUEST_GET_ID: u32 = 8;
+
+// Disks count in 512 byte sectors, whatever size the real thing has
+pub const SECTOR_SIZE: usize = 512;
+
+// How many descriptors our queue has, we only need 3 but the device might want more
+const QUEUE_SIZE: usize = 8;
+
+const DESC_FLAG_NEXT: u16 = 1;
+// The device writes to this buffer, rather than reading it
+const DESC_FLAG_WRITE: u16 = 2;
+
+// The queue and the request's header and status all live in one page, laid out like so
+const DESC_OFFSET: usize = 0;
+const AVAIL_OFFSET: usize = 256;
+const USED_OFFSET: usize = 512;
+const HEADER_OFFSET: usize = 1024;
+const STATUS_OFFSET: usize = 1040;
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// The "available" ring: requests we've handed to the device
+#[repr(C)]
+struct Avail {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// The "used" ring: requests the device has finished with
+#[repr(C)]
+struct Used {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    // Nothing (or not a virtio device) at that address
+    NotVirtio,
+    // A legacy (version 1) device, see above
+    WrongVersion(u32),
+    NotABlockDevice,
+    // The device didn't like the features we asked for
+    FeaturesRejected,
+    QueueTooSmall,
+    NoMemory,
+    // Past the end of the disk
+    OutOfRange,
+    // The device said the request failed, with the status it gave
+    Io(u8),
+    // The device didn't finish a request in time, so we reset it and it's no use any more
+    TimedOut,
+}
+
+pub struct BlockDevice {
+    base: usize,
+    // The page with the queue in it (see the offsets above)
+    queue: usize,
+    // How far through the used ring we've seen
+    last_used: u16,
+    // How many sectors the disk has
+    capacity: u64,
+    // A request timed out and we reset the device, so every request from now on fails
+    dead: bool,
+}
+
+impl BlockDevice {
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write_reg(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    // Set up the virtio-mmio device whose registers are at `base` as a block device.
+    // The kernel page table maps every virtio slot in the direct map (see vm.rs), so this works once paging is on
+    pub fn new(base: usize) -> Result<Self, VirtioError> {
+        let mut device = Self {
+            base,
+            queue: 0,
+            last_used: 0,
+            capacity: 0,
+            dead: false,
+        };
+        if device.read_reg(MAGIC_VALUE) != MAGIC {
+            return Err(VirtioError::NotVirtio);
+        }
+        let version = device.read_reg(VERSION);
+        if version != 2 {
+            return Err(VirtioError::WrongVersion(version));
+        }
+        // Empty slots have device ID 0
+        if device.read_reg(DEVICE_ID) != DEVICE_ID_BLOCK {
+            return Err(VirtioError::NotABlockDevice);
+        }
+
+        // Reset it, then tell it we've seen it and know how to drive it
+        device.write_reg(STATUS, 0);
+        let mut status = STATUS_ACKNOWLEDGE;
+        device.write_reg(STATUS, status);
+        status |= STATUS_DRIVER;
+        device.write_reg(STATUS, status);
+
+        // We don't want any of the optional features, just to be a modern driver
+        device.write_reg(DEVICE_FEATURES_SEL, 1);
+        if device.read_reg(DEVICE_FEATURES) & FEATURE_VERSION_1 == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        device.write_reg(DRIVER_FEATURES_SEL, 0);
+        device.write_reg(DRIVER_FEATURES, 0);
+        device.write_reg(DRIVER_FEATURES_SEL, 1);
+        device.write_reg(DRIVER_FEATURES, FEATURE_VERSION_1);
+        status |= STATUS_FEATURES_OK;
+        device.write_reg(STATUS, status);
+        if device.read_reg(STATUS) & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+
+        // Queue 0 is the only one a block device has
+        device.write_reg(QUEUE_SEL, 0);
+        if (device.read_reg(QUEUE_NUM_MAX) as usize) < QUEUE_SIZE {
+            return Err(VirtioError::QueueTooSmall);
+        }
+        device.queue = allocate_page().ok_or(VirtioError::NoMemory)? as usize;
+        device.write_reg(QUEUE_NUM, QUEUE_SIZE as u32);
+        for (low, high, offset) in [
+            (QUEUE_DESC_LOW, QUEUE_DESC_HIGH, DESC_OFFSET),
+            (QUEUE_DRIVER_LOW, QUEUE_DRIVER_HIGH, AVAIL_OFFSET),
+            (QUEUE_DEVICE_LOW, QUEUE_DEVICE_HIGH, USED_OFFSET),
+        ] {
+            // The device doesn't go through our page table, it wants physical addresses
+            let address = virt_to_phys(device.queue + offset);
+            device.write_reg(low, address as u32);
+            device.write_reg(high, (address >> 32) as u32);
+        }
+        device.write_reg(QUEUE_READY, 1);
+
+        status |= STATUS_DRIVER_OK;
+        device.write_reg(STATUS, status);
+
+        let capacity = unsafe { ((base + CONFIG_CAPACITY) as *const u64).read_volatile() };
+        device.capacity = capacity;
+        Ok(device)
+    }
+
+    // Reset the device so it stops using our queue, and give the queue's page back
+    pub fn release(self) {
+        self.write_reg(STATUS, 0);
+        free_page(self.queue as *mut u8);
+    }
+
+    // How many sectors the disk has
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn descriptor(&self, index: usize) -> *mut Descriptor {
+        (self.queue + DESC_OFFSET + index * core::mem::size_of::<Descriptor>()) as *mut Descriptor
+    }
+
+    // Send one request and spin until the device has done it.
+    // `buffer` is `len` bytes the device reads from, or writes to if `device_writes`.
+    // The device gets the buffer's physical address, so it has to be in the direct map or the kernel
+    // (virt_to_phys panics otherwise). Something from vmalloc isn't contiguous in physical memory
+    fn request(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        buffer: usize,
+        len: usize,
+        device_writes: bool,
+    ) -> Result<(), VirtioError> {
+        if self.dead {
+            return Err(VirtioError::TimedOut);
+        }
+        let header = (self.queue + HEADER_OFFSET) as *mut RequestHeader;
+        let status = (self.queue + STATUS_OFFSET) as *mut u8;
+        unsafe {
+            header.write_volatile(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            // Anything but 0 means it failed, so start with something that isn't
+            status.write_volatile(0xff);
+
+            self.descriptor(0).write_volatile(Descriptor {
+                address: virt_to_phys(header as usize) as u64,
+                len: core::mem::size_of::<RequestHeader>() as u32,
+                flags: DESC_FLAG_NEXT,
+                next: 1,
+            });
+            self.descriptor(1).write_volatile(Descriptor {
+                address: virt_to_phys(buffer) as u64,
+                len: len as u32,
+                flags: DESC_FLAG_NEXT | if device_writes { DESC_FLAG_WRITE } else { 0 },
+                next: 2,
+            });
+            self.descriptor(2).write_volatile(Descriptor {
+                address: virt_to_phys(status as usize) as u64,
+                len: 1,
+                flags: DESC_FLAG_WRITE,
+                next: 0,
+            });
+
+            // Put the chain (it starts at descriptor 0) in the next slot of the available ring, then bump idx.
+            // The fences make sure the device sees everything we wrote before it sees the new idx
+            let avail = (self.queue + AVAIL_OFFSET) as *mut Avail;
+            let idx = (*avail).idx;
+            core::ptr::addr_of_mut!((*avail).ring[idx as usize % QUEUE_SIZE]).write_volatile(0);
+            fence(Ordering::SeqCst);
+            core::ptr::addr_of_mut!((*avail).idx).write_volatile(idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            self.write_reg(QUEUE_NOTIFY, 0);
+
+            // Whoever's waiting could be holding a lock other harts want, so we don't wait forever. If it never
+            // finishes, resetting the device is the only way to be sure it won't write into `buffer` later on,
+            // after it's been given to someone else
+            let used = (self.queue + USED_OFFSET) as *const Used;
+            let give_up = clock::now() + clock::nanos_to_cycles(REQUEST_TIMEOUT_NANOS);
+            while core::ptr::addr_of!((*used).idx).read_volatile() == self.last_used {
+                if clock::now() >= give_up {
+                    self.write_reg(STATUS, 0);
+                    self.dead = true;
+                    return Err(VirtioError::TimedOut);
+                }
+                core::hint::spin_loop();
+            }
+            fence(Ordering::SeqCst);
+            self.last_used = self.last_used.wrapping_add(1);
+        }
+        // We don't use its interrupt, but acknowledge it anyway so it doesn't stay pending
+        self.write_reg(INTERRUPT_ACK, self.read_reg(INTERRUPT_STATUS));
+
+        match unsafe { status.read_volatile() } {
+            0 => Ok(()),
+            error => Err(VirtioError::Io(error)),
+        }
+    }
+
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), VirtioError> {
+        let sectors = len.div_ceil(SECTOR_SIZE) as u64;
+        if sector + sectors > self.capacity {
+            return Err(VirtioError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    // Read `len` bytes (a whole number of sectors) starting at `sector` into `buffer`
+    pub fn read(&mut self, sector: u64, buffer: *mut u8, len: usize) -> Result<(), VirtioError> {
+        self.check_range(sector, len)?;
+        self.request(REQUEST_READ, sector, buffer as usize, len, true)
+    }
+
+    // Write `len` bytes (a whole number of sectors) from `buffer` to the disk starting at `sector`
+    pub fn write(&mut self, sector: u64, buffer: *const u8, len: usize) -> Result<(), VirtioError> {
+        self.check_range(sector, len)?;
+        self.request(REQUEST_WRITE, sector, buffer as usize, len, false)
+    }
+
+    // The disk's serial number, QEMU gives it whatever `serial=` was on the command line.
+    // It's padded with zeroes, and doesn't have one on the end if it's the full 20 bytes
+    pub fn serial(&mut self) -> Result<[u8; 20], VirtioError> {
+        // The device writes into this, so it has to be somewhere we know the physical address of
+        let buffer = self.queue + STATUS_OFFSET + 16;
+        self.request(REQUEST_GET_ID, 0, buffer, 20, true)?;
+        let mut serial = [0; 20];
+        unsafe { core::ptr::copy_nonoverlapping(buffer as *const u8, serial.as_mut_ptr(), 20) };
+        Ok(serial)
+    }
+}
+
+// Find the block device with the serial number `wanted` and set it up
+pub fn find_block_device(wanted: &str) -> Option<BlockDevice> {
+    for device in platform().virtio.iter().flatten() {
+        let Ok(mut disk) = BlockDevice::new(device.address()) else {
+            continue;
+        };
+        let matches = disk.serial().is_ok_and(|serial| {
+            let len = serial.iter().position(|&b| b == 0).unwrap_or(serial.len());
+            &serial[..len] == wanted.as_bytes()
+        });
+        if matches {
+            return Some(disk);
+        }
+        disk.release();
+    }
+    None
+}
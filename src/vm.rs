@@ -1,11 +1,53 @@
+use core::{
+    fmt,
+    marker::PhantomData,
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
 use crate::{
-    consts::{KERNEL_START, PHYS_STOP},
-    kalloc::{allocate_page, set_memory, MAX_VIRTUAL_ADDRESS, PAGE_SIZE},
-    plic::PLIC,
-    uart::UART_LOC0,
-    virtio::VIRTIO0,
+    asid::{self, Asid, KERNEL_ASID},
+    consts::NUM_CPUS,
+    hotplug, ipi,
+    kalloc::{allocate_page, free_page, get_page_round_up, set_memory, PAGE_SIZE},
+    kstack,
+    memlayout::{
+        phys_to_virt, virt_to_phys, DIRECT_MAP_BASE, DIRECT_MAP_SIZE, KERNEL_OFFSET, KSTACK_SIZE,
+        KSTACK_START, VMALLOC_SIZE, VMALLOC_START,
+    },
+    platform::{platform, Device},
+    reclaim,
+    sched::Pid,
+    spinlock::Spinlock,
+    swap, tlb,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    // An address or size that isn't a whole number of pages
+    NotAligned,
+    // Asked to map, unmap or protect nothing
+    ZeroSize,
+    // Something's already mapped there
+    Remap,
+    // Part of the range isn't mapped
+    NotMapped,
+    // Ran out of memory for page tables
+    OutOfMemory,
+    // Not somewhere this page table is allowed to map, like the kernel's part of an address space
+    BadAddress,
+    // No R, W or X: the hardware would take that for a pointer to another table
+    BadPermissions,
+    // The range runs off the end of the address space, or is too big to even add up
+    Overflow,
+}
+
+// Permissions for mapping pages from outside this file
+pub const PTE_READ: usize = PageTableEntry::FLAG_READ;
+pub const PTE_WRITE: usize = PageTableEntry::FLAG_WRITE;
+pub const PTE_EXEC: usize = PageTableEntry::FLAG_EXEC;
+pub const PTE_USER: usize = PageTableEntry::FLAG_USER;
+
 #[repr(transparent)]
 #[derive(Debug, Copy, Clone)]
 /// Represents an entry within a page table
@@ -21,65 +63,293 @@ impl PageTableEntry {
     pub const FLAG_WRITE: usize = 1 << 2;
     pub const FLAG_EXEC: usize = 1 << 3;
     pub const FLAG_USER: usize = 1 << 4; // Can be accessed in user-mode
+    pub const FLAG_GLOBAL: usize = 1 << 5; // The same in every address space
+    pub const FLAG_ACCESSED: usize = 1 << 6; // The page has been used since this was last cleared
+    pub const FLAG_DIRTY: usize = 1 << 7; // The page has been written to since this was last cleared
+
+    // Bits 8 and 9 are ours, the hardware ignores them.
+    // The reclaimer took this page away (see reclaim.rs): it's not valid, but the flags are still what the
+    // page had, so a fault on it gets a new page with the same permissions
+    pub const FLAG_EVICTED: usize = 1 << 8;
+    // Along with EVICTED: what was in the page is in a swap slot (see swap.rs), and the PPN is the slot number.
+    // On its own: the reclaimer's in the middle of taking the page. It's not valid, but the PPN is still the
+    // page, so a fault on it can just put it back (see age_page)
+    pub const FLAG_SWAPPED: usize = 1 << 9;
 
     #[inline]
     pub fn extract_flags(&self) -> usize {
         // Extract the 10 flag bits
-        // (there's only 5 that are useful for us, hence why there's only 5 constants defined above)
         self.0 & 0x3FF
     }
 
+    // Whether there's a page here, or was one until the reclaimer took it
+    #[inline]
+    pub fn is_present(&self) -> bool {
+        self.0 & (Self::FLAG_VALID | Self::FLAG_EVICTED) != 0
+    }
+
+    // Whether the page's out in a swap slot
+    #[inline]
+    pub fn is_swapped(&self) -> bool {
+        self.0 & (Self::FLAG_EVICTED | Self::FLAG_SWAPPED)
+            == Self::FLAG_EVICTED | Self::FLAG_SWAPPED
+    }
+
+    // Whether the reclaimer's taking the page right now
+    #[inline]
+    pub fn is_leaving(&self) -> bool {
+        self.0 & (Self::FLAG_VALID | Self::FLAG_EVICTED | Self::FLAG_SWAPPED) == Self::FLAG_SWAPPED
+    }
+
+    // The entry as an atomic, for entries the hardware (Svadu) or another hart might be changing under us
+    #[inline]
+    fn atomic(entry: *mut PageTableEntry) -> &'static AtomicUsize {
+        unsafe { &*(entry as *const AtomicUsize) }
+    }
+
     #[inline]
     pub fn extract_physical_page_number(&self) -> usize {
         // We don't want the flag bits, but we do want the PPN
         (self.0 >> 10) << 12
     }
 
+    // Which swap slot a swapped out page is in
     #[inline]
-    pub fn new(physical: usize, flags: usize) -> Self {
+    pub fn swap_slot(&self) -> usize {
+        self.0 >> 10
+    }
+
+    #[inline]
+    pub const fn new(physical: usize, flags: usize) -> Self {
         // Get rid of the 12-bit offset of the address at the start
         // Then get rid of the 10 reserved bit at the end
         Self(((physical >> 12) << 10) | flags)
     }
 
+    // A leaf points at memory rather than at another table. Only leaves have any of R, W or X set,
+    // and a leaf above the bottom level is a huge page
+    #[inline]
+    pub fn is_leaf(&self) -> bool {
+        self.0 & (Self::FLAG_READ | Self::FLAG_WRITE | Self::FLAG_EXEC) != 0
+    }
+
+    // The entry has the table's physical address, we get at it through the direct map
     #[inline]
-    pub fn as_table(&self) -> PageTable {
+    pub fn as_table<M: PagingMode>(&self) -> PageTable<M> {
         let pg = self.extract_physical_page_number();
-        PageTable(pg as *mut usize)
+        PageTable::from_address(phys_to_virt(pg))
     }
 
     #[inline]
-    pub fn allocate_as_new_table(flags: usize) -> Option<(Self, PageTable)> {
-        let table = PageTable::new()?;
-        Some((Self::new(table.0 as usize, flags), table))
+    pub fn allocate_as_new_table<M: PagingMode>(flags: usize) -> Option<(Self, PageTable<M>)> {
+        let table = PageTable::<M>::new()?;
+        Some((Self::new(virt_to_phys(table.0 as usize), flags), table))
     }
 }
 
+// The page table entry.S turns paging on with, before kalloc is around to give us pages for a real one.
+// It's a single Sv39 table of 1GiB pages, so it can be filled in at compile time:
+// - The first 4GiB of physical memory mapped to itself. entry.S is still running at its physical address
+//   when it turns paging on, this keeps it from falling off a cliff until it jumps up to the kernel
+// - The same 4GiB up where the kernel is linked (KERNEL_OFFSET higher), which is where it jumps to
+// - All of the direct map, so kalloc and kvm_make can get at RAM and the device tree
+// kvm_make builds the real kernel table once we're going, this one's only used until kvm_init_hart
+#[repr(C, align(4096))]
+pub struct BootPageTable([usize; 512]);
+
+pub static BOOT_PAGE_TABLE: BootPageTable = boot_page_table();
+
+const fn boot_page_table() -> BootPageTable {
+    const GIB: usize = 1 << 30;
+    const RWX: usize = PageTableEntry::FLAG_VALID
+        | PageTableEntry::FLAG_READ
+        | PageTableEntry::FLAG_WRITE
+        | PageTableEntry::FLAG_EXEC
+        | PageTableEntry::FLAG_ACCESSED
+        | PageTableEntry::FLAG_DIRTY;
+    let mut table = [0; 512];
+    let mut gib = 0;
+    while gib < 4 {
+        table[gib] = PageTableEntry::new(gib * GIB, RWX).0;
+        table[(KERNEL_OFFSET / GIB) % 512 + gib] =
+            PageTableEntry::new(gib * GIB, RWX | PageTableEntry::FLAG_GLOBAL).0;
+        gib += 1;
+    }
+    let mut gib = 0;
+    while gib < DIRECT_MAP_SIZE / GIB {
+        table[(DIRECT_MAP_BASE / GIB) % 512 + gib] =
+            PageTableEntry::new(gib * GIB, RWX | PageTableEntry::FLAG_GLOBAL).0;
+        gib += 1;
+    }
+    BootPageTable(table)
+}
+
+fn boot_page_table_address() -> usize {
+    virt_to_phys(addr_of!(BOOT_PAGE_TABLE) as usize)
+}
+
+// How many levels of page table we're using: 3 for Sv39 (512GiB of virtual addresses), 4 for Sv48
+// (256TiB) or 5 for Sv57 (128PiB). Each level is the same 512 entry table, a 4 level address just has
+// one more 9-bit index on top. Each mode is a type and PageTable and VirtualAddr are generic over it,
+// so walking a table always knows how many levels it has without looking anything up
+pub trait PagingMode: Copy + fmt::Debug {
+    const LEVELS: usize;
+    // What goes in the MODE field of satp (the top 4 bits)
+    const SATP_MODE: usize;
+    const NAME: &'static str;
+
+    // Virtual addresses have to be "canonical": every bit above the top index has to match the top bit of it.
+    // Processes get the bottom half, everything below this. The kernel has the top half (see memlayout.rs)
+    const MAX_VIRTUAL_ADDRESS: usize = 1 << (9 * Self::LEVELS + 12 - 1);
+
+    // Copy the top bit of `va` up through the rest, for addresses we've put together out of table indexes
+    fn canonical(va: usize) -> usize {
+        let unused = 64 - (9 * Self::LEVELS + 12);
+        (((va << unused) as isize) >> unused) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sv39;
+
+impl PagingMode for Sv39 {
+    const LEVELS: usize = 3;
+    const SATP_MODE: usize = 8;
+    const NAME: &'static str = "Sv39";
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sv48;
+
+impl PagingMode for Sv48 {
+    const LEVELS: usize = 4;
+    const SATP_MODE: usize = 9;
+    const NAME: &'static str = "Sv48";
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sv57;
+
+impl PagingMode for Sv57 {
+    const LEVELS: usize = 5;
+    const SATP_MODE: usize = 10;
+    const NAME: &'static str = "Sv57";
+}
+
+// The mode the kernel and every address space page with. The `sv48` and `sv57` features ask for more
+// levels, kvm_init_base checks the hart can actually do it
+#[cfg(not(any(feature = "sv48", feature = "sv57")))]
+pub type Mode = Sv39;
+#[cfg(all(feature = "sv48", not(feature = "sv57")))]
+pub type Mode = Sv48;
+#[cfg(feature = "sv57")]
+pub type Mode = Sv57;
+
+// The top of the bottom half, where processes live
+pub fn max_virtual_address() -> usize {
+    Mode::MAX_VIRTUAL_ADDRESS
+}
+
+// A and D: the hardware sets Accessed when a page is used and Dirty when it's written, but there are two
+// ways it can go about it. With Svadu it just writes them into the PTE itself. Without it (Svade) using a
+// page with A clear, or writing one with D clear, is a page fault and we set them in handle_page_fault.
+// The kernel's own pages are mapped with both already set so it never faults on them.
+// We turn Svadu on in start.rs when the device tree says every hart has it
+
+// How many times we've had to set A or D ourselves
+static AD_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn ad_faults() -> usize {
+    AD_FAULTS.load(Ordering::Relaxed)
+}
+
+// A PTE's flags as letters for printing: `rwxugad`, with a `-` for each one that isn't set
+struct Flags(usize);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (flag, letter) in [
+            (PageTableEntry::FLAG_READ, 'r'),
+            (PageTableEntry::FLAG_WRITE, 'w'),
+            (PageTableEntry::FLAG_EXEC, 'x'),
+            (PageTableEntry::FLAG_USER, 'u'),
+            (PageTableEntry::FLAG_GLOBAL, 'g'),
+            (PageTableEntry::FLAG_ACCESSED, 'a'),
+            (PageTableEntry::FLAG_DIRTY, 'd'),
+        ] {
+            write!(f, "{}", if self.0 & flag != 0 { letter } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+// A size in the biggest unit it's a whole number of, like `12KiB` or `2MiB`
+struct Size(usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (unit, name) = [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")]
+            .into_iter()
+            .find(|&(unit, _)| self.0 >= unit && self.0 % unit == 0)
+            .unwrap_or((1, "B"));
+        write!(f, "{}{name}", self.0 / unit)
+    }
+}
+
+// How much memory one entry maps at `level`: 4KiB at the bottom (level 0), 2MiB one up, 1GiB at the top
+// Sv39 lets any level hold a leaf, so we map big aligned ranges (free RAM, the PLIC) with 2MiB and 1GiB
+// "huge pages" instead of hundreds of thousands of 4KiB ones. Fewer entries to fill in at boot, and
+// each TLB entry covers far more
+pub const fn page_size_at_level(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+fn page_size_name(level: usize) -> &'static str {
+    ["4KiB", "2MiB", "1GiB", "512GiB", "256TiB"][level]
+}
+
+// How many bytes `npages` pages is, for anything that takes a number of pages
+fn pages_to_size(npages: usize) -> Result<usize, VmError> {
+    npages.checked_mul(PAGE_SIZE).ok_or(VmError::Overflow)
+}
+
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug)]
-struct VirtualAddr(usize);
+struct VirtualAddr<M: PagingMode = Mode>(usize, PhantomData<M>);
+
+impl<M: PagingMode> VirtualAddr<M> {
+    #[inline]
+    pub fn new(va: usize) -> Self {
+        Self(va, PhantomData)
+    }
 
-impl VirtualAddr {
     #[inline]
     pub fn extract_index_at_level(&self, level: usize) -> usize {
+        debug_assert!(level < M::LEVELS);
         (self.0 >> (12 + (level * 9))) & 0x1FF // Want 9 bits
     }
 }
 
 #[repr(transparent)]
 #[derive(Copy, Clone, Debug)]
-/// Represents a page table, contains a pointer to the physical page containing the table entries
-struct PageTable(pub *mut usize);
+/// Represents a page table, contains a pointer to the page containing the table entries (in the direct map)
+struct PageTable<M: PagingMode = Mode>(pub *mut usize, PhantomData<M>);
 
-impl PageTable {
+impl<M: PagingMode> PageTable<M> {
     #[inline]
     pub fn new() -> Option<Self> {
         allocate_page().map(|page| {
             set_memory(page, PAGE_SIZE, 0);
-            Self(page as *mut usize)
+            Self::from_address(page as usize)
         })
     }
 
+    // The table at `address`, in the direct map
+    #[inline]
+    pub fn from_address(address: usize) -> Self {
+        Self(address as *mut usize, PhantomData)
+    }
+
     pub fn set(&mut self, index: usize, entry: PageTableEntry) {
         if index >= 512 {
             panic!("page_table_set");
@@ -115,55 +385,161 @@ impl PageTable {
     }
 
     pub fn dump(&self) {
+        self.dump_level(M::LEVELS - 1);
+    }
+
+    fn dump_level(&self, level: usize) {
+        let indent = (M::LEVELS - 1 - level) * 3;
         for idx in 0..512 {
             if let Some(a) = self.lookup(idx) {
-                println!(
-                    "{idx} -> {:#x} : {:#b}",
-                    a.extract_physical_page_number(),
-                    a.extract_flags()
-                );
-                for idx2 in 0..512 {
-                    if let Some(b) = a.as_table().lookup(idx2) {
-                        println!(
-                            "   {idx2} -> {:#x} : {:#b}",
-                            b.extract_physical_page_number(),
-                            b.extract_flags()
-                        );
-                        for idx3 in 0..512 {
-                            if let Some(c) = b.as_table().lookup(idx3) {
-                                println!(
-                                    "      {idx3} -> {:#x} : {:#b}",
-                                    c.extract_physical_page_number(),
-                                    c.extract_flags()
-                                );
-                            }
-                        }
+                if a.is_leaf() && level > 0 {
+                    println!(
+                        "{:indent$}{idx} -> {:#x} {} ({})",
+                        "",
+                        a.extract_physical_page_number(),
+                        Flags(a.extract_flags()),
+                        page_size_name(level)
+                    );
+                } else if a.is_leaf() {
+                    println!(
+                        "{:indent$}{idx} -> {:#x} {}",
+                        "",
+                        a.extract_physical_page_number(),
+                        Flags(a.extract_flags())
+                    );
+                } else {
+                    println!(
+                        "{:indent$}{idx} -> table at {:#x}",
+                        "",
+                        a.extract_physical_page_number()
+                    );
+                }
+                // A huge page has no table under it
+                if level > 0 && !a.is_leaf() {
+                    a.as_table::<M>().dump_level(level - 1);
+                }
+            }
+        }
+    }
+
+    // Print every mapping in the table as "VA range -> PA range flags (size)", in order. Pages next to each
+    // other that map to memory next to each other with the same flags are one range, so 128MiB of RAM
+    // mapped in one go is one line rather than 32768
+    pub fn print_ranges(&self) {
+        // (va, pa, size, flags) of the range we're building up
+        let mut current: Option<(usize, usize, usize, usize)> = None;
+        let mut ranges = 0;
+        let mut print = |(va, pa, size, flags): (usize, usize, usize, usize)| {
+            println!(
+                "{va:#x}-{:#x} -> {pa:#x}-{:#x} {} ({})",
+                va + size,
+                pa + size,
+                Flags(flags),
+                Size(size)
+            );
+            ranges += 1;
+        };
+        self.for_each_leaf(&mut |va, level, entry| {
+            let pa = entry.extract_physical_page_number();
+            let flags = entry.extract_flags();
+            let page = page_size_at_level(level);
+            match current.as_mut() {
+                Some((start, start_pa, size, range_flags))
+                    if *start + *size == va && *start_pa + *size == pa && *range_flags == flags =>
+                {
+                    *size += page
+                }
+                _ => {
+                    if let Some(range) = current.replace((va, pa, page, flags)) {
+                        print(range);
                     }
                 }
             }
+        });
+        if let Some(range) = current {
+            print(range);
         }
+        println!("{ranges} ranges");
     }
 
-    pub fn walk(&self, va: VirtualAddr, alloc: bool) -> Option<*mut PageTableEntry> {
-        let mut current_table = self.clone();
+    // Call `f(va, level, entry)` for every leaf in the table, huge pages included
+    fn for_each_leaf(&self, f: &mut impl FnMut(usize, usize, PageTableEntry)) {
+        self.for_each_leaf_at(M::LEVELS - 1, 0, f);
+    }
 
-        for level in [2, 1] {
-            let idx = va.extract_index_at_level(level);
-            let tbl = current_table.lookup(idx).map(|pte| pte.as_table());
-            if let Some(tbl) = tbl {
-                current_table = tbl;
-            } else if alloc {
-                let (pte, new_table) =
-                    PageTableEntry::allocate_as_new_table(PageTableEntry::FLAG_VALID)?;
-                current_table.set(idx, pte);
-                current_table = new_table;
-            } else {
-                return None;
+    fn for_each_leaf_at(
+        &self,
+        level: usize,
+        base: usize,
+        f: &mut impl FnMut(usize, usize, PageTableEntry),
+    ) {
+        for idx in 0..512 {
+            if let Some(entry) = self.lookup(idx) {
+                let va = M::canonical(base + idx * page_size_at_level(level));
+                if entry.is_leaf() || level == 0 {
+                    f(va, level, entry);
+                } else {
+                    entry.as_table::<M>().for_each_leaf_at(level - 1, va, f);
+                }
             }
         }
+    }
+
+    // Find the entry for `va` at `level`, making any missing tables on the way down if `alloc`.
+    // If there's a huge page in the way we stop at it, so this returns the entry and the level it's
+    // actually at, which is higher than `level` if we ran into one
+    fn walk_to(
+        &self,
+        va: VirtualAddr<M>,
+        level: usize,
+        alloc: bool,
+    ) -> Option<(*mut PageTableEntry, usize)> {
+        let mut current_table = *self;
 
-        let idx = va.extract_index_at_level(0);
-        Some(current_table.get_ref(idx))
+        for l in (level + 1..M::LEVELS).rev() {
+            let idx = va.extract_index_at_level(l);
+            match current_table.lookup(idx) {
+                Some(pte) if pte.is_leaf() => return Some((current_table.get_ref(idx), l)),
+                Some(pte) => current_table = pte.as_table(),
+                None if alloc => {
+                    let (pte, new_table) =
+                        PageTableEntry::allocate_as_new_table(PageTableEntry::FLAG_VALID)?;
+                    current_table.set(idx, pte);
+                    current_table = new_table;
+                }
+                None => return None,
+            }
+        }
+
+        let idx = va.extract_index_at_level(level);
+        Some((current_table.get_ref(idx), level))
+    }
+
+    // Find the entry that maps `va`, and the level it's at: 0 for a normal 4KiB page, higher for a huge page
+    pub fn walk(&self, va: VirtualAddr<M>, alloc: bool) -> Option<(*mut PageTableEntry, usize)> {
+        self.walk_to(va, 0, alloc)
+    }
+
+    // Break the huge page in `entry` up into a table of the next size down, mapping the same memory
+    // the same way, so we can change part of it. Every address still goes to the same place afterwards
+    fn split(entry: *mut PageTableEntry, level: usize) -> Option<()> {
+        let huge = unsafe { *entry };
+        let (pte, mut table) =
+            PageTableEntry::allocate_as_new_table::<M>(PageTableEntry::FLAG_VALID)?;
+        let step = page_size_at_level(level - 1);
+        for idx in 0..512 {
+            table.set(
+                idx,
+                PageTableEntry::new(
+                    huge.extract_physical_page_number() + idx * step,
+                    huge.extract_flags(),
+                ),
+            );
+        }
+        unsafe {
+            *entry = pte;
+        }
+        Some(())
     }
 
     pub fn kvm_map(
@@ -177,96 +553,1224 @@ impl PageTable {
             "kvm_map: {virtual_addr:#x}-{:#x} -> {physical_address:#x} ({size:#x})",
             virtual_addr + size
         );
-        self.map_pages(virtual_addr, size, physical_address, perm)
-            .expect("Uh oh");
+        // Kernel mappings are the same in every address space, and we don't want A/D faults on them
+        let perm = perm
+            | PageTableEntry::FLAG_GLOBAL
+            | PageTableEntry::FLAG_ACCESSED
+            | PageTableEntry::FLAG_DIRTY;
+        if let Err(error) = self.map_pages(virtual_addr, size, physical_address, perm) {
+            panic!("kvm_map: {error:?}");
+        }
+    }
+
+    // Map a memory-mapped device's registers where the direct map says they are
+    pub fn kvm_map_device(&mut self, device: &Device, perm: usize) {
+        self.kvm_map(
+            device.address(),
+            get_page_round_up(device.size),
+            device.base,
+            perm,
+        );
+    }
+
+    // Find where `va` stops: the first entry on the way down that's either a leaf or not valid, and its level.
+    // Unlike walk this also tells us how big a hole is, and finds leaves unmap has marked invalid
+    fn find_entry(&self, va: VirtualAddr<M>) -> (*mut PageTableEntry, usize) {
+        let mut current_table = *self;
+        for level in (1..M::LEVELS).rev() {
+            let idx = va.extract_index_at_level(level);
+            match current_table.lookup(idx) {
+                Some(pte) if !pte.is_leaf() => current_table = pte.as_table(),
+                _ => return (current_table.get_ref(idx), level),
+            }
+        }
+        (current_table.get_ref(va.extract_index_at_level(0)), 0)
     }
 
+    // Check [virtual_addr, virtual_addr + size) is whole pages and something, and return where it ends.
+    // Everything else here adds size on without checking, so it has to go through this first
+    fn check_range(virtual_addr: usize, size: usize) -> Result<usize, VmError> {
+        if virtual_addr % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(VmError::NotAligned);
+        }
+        if size == 0 {
+            return Err(VmError::ZeroSize);
+        }
+        virtual_addr.checked_add(size).ok_or(VmError::Overflow)
+    }
+
+    // Whether anything at all in [virtual_addr, virtual_addr + size) is mapped.
+    // Holes are skipped a whole entry at a time, so checking a big empty range is quick
+    fn any_mapped(&self, virtual_addr: usize, size: usize) -> bool {
+        let end = virtual_addr + size;
+        let mut a = virtual_addr;
+        while a < end {
+            let (entry, level) = self.find_entry(VirtualAddr::new(a));
+            if unsafe { (*entry).is_present() } {
+                return true;
+            }
+            let page = page_size_at_level(level);
+            a = (a & !(page - 1)) + page;
+        }
+        false
+    }
+
+    // Make sure every page in [virtual_addr, virtual_addr + size) is mapped, and that no huge page sticks out
+    // either end of it, splitting any that do. After this every leaf in the range is entirely inside it.
+    // Splitting doesn't change where anything goes, so it's fine to stop half way through with an error
+    fn split_to_fit(&mut self, virtual_addr: usize, size: usize) -> Result<(), VmError> {
+        let end = virtual_addr + size;
+        let mut a = virtual_addr;
+        while a < end {
+            let (entry, level) = self.find_entry(VirtualAddr::new(a));
+            if unsafe { !(*entry).is_present() } {
+                return Err(VmError::NotMapped);
+            }
+            let page = page_size_at_level(level);
+            // Only part of a huge page is in the range, split it up and go round again
+            if a % page != 0 || end - a < page {
+                Self::split(entry, level).ok_or(VmError::OutOfMemory)?;
+                continue;
+            }
+            a += page;
+        }
+        Ok(())
+    }
+
+    // Call `f(entry, level)` on every leaf in [virtual_addr, virtual_addr + size), valid or not.
+    // Only call this on a range split_to_fit has been over
+    fn for_each_leaf_in(
+        &self,
+        virtual_addr: usize,
+        size: usize,
+        mut f: impl FnMut(*mut PageTableEntry, usize),
+    ) {
+        let end = virtual_addr + size;
+        let mut a = virtual_addr;
+        while a < end {
+            let (entry, level) = self.find_entry(VirtualAddr::new(a));
+            f(entry, level);
+            a += page_size_at_level(level);
+        }
+    }
+
+    // Map [virtual_addr, virtual_addr + size) to memory starting at physical_address.
+    // Nothing in the range can be mapped already, if anything is we don't map any of it.
+    // Running out of memory for tables part way through means taking it all back, which needs a TLB shootdown
     pub fn map_pages(
         &mut self,
         virtual_addr: usize,
         size: usize,
-        mut physical_address: usize,
+        physical_address: usize,
         perm: usize,
-    ) -> Option<()> {
-        if virtual_addr % PAGE_SIZE != 0 {
-            panic!("map_pages: va not aligned");
-        }
+    ) -> Result<(), VmError> {
+        self.map_pages_then(virtual_addr, size, physical_address, perm, tlb::shootdown)
+    }
 
-        if size % PAGE_SIZE != 0 || size == 0 {
-            panic!("map_pages: size not aligned or is 0")
+    // map_pages, but `flush(start, size)` gets rid of the old translations if we have to take it back
+    fn map_pages_then(
+        &mut self,
+        virtual_addr: usize,
+        size: usize,
+        physical_address: usize,
+        perm: usize,
+        flush: impl FnOnce(usize, usize),
+    ) -> Result<(), VmError> {
+        let end = Self::check_range(virtual_addr, size)?;
+        physical_address
+            .checked_add(size)
+            .ok_or(VmError::Overflow)?;
+        if self.any_mapped(virtual_addr, size) {
+            return Err(VmError::Remap);
         }
 
         let mut a = virtual_addr;
-        let last = virtual_addr + size - PAGE_SIZE;
+        let mut pa = physical_address;
 
-        loop {
-            let entry = self.walk(VirtualAddr(a), true)?;
+        while a < end {
+            // Use the biggest page we can: both addresses have to be aligned to it, and it can't run past the end
+            let level = (0..M::LEVELS)
+                .rev()
+                .find(|&level| {
+                    let page = page_size_at_level(level);
+                    a % page == 0 && pa % page == 0 && end - a >= page
+                })
+                .unwrap();
+            let Some((entry, _)) = self.walk_to(VirtualAddr::new(a), level, true) else {
+                // Out of memory for tables: take back what we did map, and the tables we made for it
+                if a > virtual_addr {
+                    self.for_each_leaf_in(virtual_addr, a - virtual_addr, |entry, _| unsafe {
+                        *entry = PageTableEntry(0)
+                    });
+                }
+                self.free_empty_tables(virtual_addr, end, flush);
+                return Err(VmError::OutOfMemory);
+            };
             unsafe {
-                if ((*entry).extract_flags() & PageTableEntry::FLAG_VALID) != 0 {
-                    panic!("map_pages: remap");
+                *entry = PageTableEntry::new(pa, perm | PageTableEntry::FLAG_VALID);
+            }
+            a += page_size_at_level(level);
+            pa += page_size_at_level(level);
+        }
+
+        Ok(())
+    }
+
+    // After running out of memory part way through a map: free every table under [virtual_addr, end) that
+    // has nothing in it any more, so the ones we made on the way aren't left behind. The tables right under
+    // the top level stay, the kernel's are shared with every address space (see AddressSpace).
+    // Another hart could have one of them cached as the way to an address, so they only go back to kalloc
+    // after `flush`. Until then they're strung together through their first entry: that's a page aligned
+    // address, so V is clear and anything still walking through one just finds nothing mapped
+    fn free_empty_tables(&self, virtual_addr: usize, end: usize, flush: impl FnOnce(usize, usize)) {
+        // The walk puts addresses together out of indexes, so it goes by them without the sign extension
+        let offset = |va: usize| va & ((1 << (9 * M::LEVELS + 12)) - 1);
+        let mut unlinked = 0;
+        self.unlink_empty_tables(
+            M::LEVELS - 1,
+            0,
+            offset(virtual_addr),
+            offset(end - 1),
+            &mut unlinked,
+        );
+        flush(virtual_addr, end - virtual_addr);
+        while unlinked != 0 {
+            let next = unsafe { *(unlinked as *const usize) };
+            free_page(unlinked as *mut u8);
+            unlinked = next;
+        }
+    }
+
+    // The work for free_empty_tables: this table is at `level` and starts at `base`, and we're after the
+    // tables under it with anything in [first, last]. Empty ones are unlinked and pushed onto `unlinked`
+    fn unlink_empty_tables(
+        &self,
+        level: usize,
+        base: usize,
+        first: usize,
+        last: usize,
+        unlinked: &mut usize,
+    ) {
+        let size = page_size_at_level(level);
+        for idx in 0..512 {
+            let va = base + idx * size;
+            if va > last || va + (size - 1) < first {
+                continue;
+            }
+            let entry = self.get_ref(idx);
+            let pte = unsafe { *entry };
+            if level == 0 || pte.0 & PageTableEntry::FLAG_VALID == 0 || pte.is_leaf() {
+                continue;
+            }
+            let table = pte.as_table::<M>();
+            table.unlink_empty_tables(level - 1, va, first, last, unlinked);
+            let empty = (0..512).all(|idx| unsafe { (*table.get_ref(idx)).0 == 0 });
+            if empty && level < M::LEVELS - 1 {
+                unsafe {
+                    *entry = PageTableEntry(0);
+                    *table.0 = *unlinked;
                 }
-                *entry = PageTableEntry::new(physical_address, perm | PageTableEntry::FLAG_VALID);
-                if a == last {
-                    break;
+                *unlinked = table.0 as usize;
+            }
+        }
+    }
+
+    // Take away the mappings for `npages` pages from virtual_addr, and if `free_phys` give the memory they
+    // pointed at back to kalloc. Every page in the range has to be mapped, or nothing is unmapped.
+    // Every hart uses the kernel page table, so once they're gone we shoot them out of every hart's TLB,
+    // for every ASID, after this returns nobody can still be using them
+    pub fn unmap_pages(
+        &mut self,
+        virtual_addr: usize,
+        npages: usize,
+        free_phys: bool,
+    ) -> Result<(), VmError> {
+        self.unmap_pages_then(virtual_addr, npages, free_phys, tlb::shootdown)
+    }
+
+    // unmap_pages, but `flush(start, size)` gets rid of the old translations.
+    // We can't free the memory until nobody can get at it any more, so the entries are only marked invalid
+    // at first (the address is still in them), then we flush, and only then free the memory and clear them out
+    fn unmap_pages_then(
+        &mut self,
+        virtual_addr: usize,
+        npages: usize,
+        free_phys: bool,
+        flush: impl FnOnce(usize, usize),
+    ) -> Result<(), VmError> {
+        let size = pages_to_size(npages)?;
+        Self::check_range(virtual_addr, size)?;
+        self.split_to_fit(virtual_addr, size)?;
+
+        self.for_each_leaf_in(virtual_addr, size, |entry, _| unsafe {
+            (*entry).0 &= !PageTableEntry::FLAG_VALID;
+        });
+        flush(virtual_addr, size);
+        self.for_each_leaf_in(virtual_addr, size, |entry, level| {
+            let old = unsafe { *entry };
+            // An evicted page has already been freed, but if it's in swap the slot has to go back
+            if old.is_swapped() {
+                swap::free_slot(old.swap_slot());
+            }
+            if free_phys && old.0 & PageTableEntry::FLAG_EVICTED == 0 {
+                let start = old.extract_physical_page_number();
+                for page in (start..start + page_size_at_level(level)).step_by(PAGE_SIZE) {
+                    free_page(phys_to_virt(page) as *mut u8);
                 }
-                a += PAGE_SIZE;
-                physical_address += PAGE_SIZE;
             }
+            unsafe { *entry = PageTableEntry(0) };
+        });
+        Ok(())
+    }
+
+    // Change the permissions (R, W, X and U) of every page in [virtual_addr, virtual_addr + size) to `perm`.
+    // Every page has to be mapped. Taking a permission away needs a TLB shootdown, so we always do one
+    pub fn protect(
+        &mut self,
+        virtual_addr: usize,
+        size: usize,
+        perm: usize,
+    ) -> Result<(), VmError> {
+        self.protect_then(virtual_addr, size, perm, tlb::shootdown)
+    }
+
+    fn protect_then(
+        &mut self,
+        virtual_addr: usize,
+        size: usize,
+        perm: usize,
+        flush: impl FnOnce(usize, usize),
+    ) -> Result<(), VmError> {
+        const PERM_BITS: usize = PageTableEntry::FLAG_READ
+            | PageTableEntry::FLAG_WRITE
+            | PageTableEntry::FLAG_EXEC
+            | PageTableEntry::FLAG_USER;
+        // A leaf with none of R, W or X would look like a pointer to another table
+        if perm
+            & (PageTableEntry::FLAG_READ | PageTableEntry::FLAG_WRITE | PageTableEntry::FLAG_EXEC)
+            == 0
+        {
+            return Err(VmError::BadPermissions);
         }
+        Self::check_range(virtual_addr, size)?;
+        self.split_to_fit(virtual_addr, size)?;
+        self.for_each_leaf_in(virtual_addr, size, |entry, _| unsafe {
+            (*entry).0 = ((*entry).0 & !PERM_BITS) | (perm & PERM_BITS);
+        });
+        flush(virtual_addr, size);
+        Ok(())
+    }
 
-        Some(())
+    // Free this table and every table under it, `level` is the level this one is at.
+    // Only the tables go back to kalloc, not the memory their leaves point at
+    pub fn free_table(self, level: usize) {
+        if level > 0 {
+            for idx in 0..512 {
+                if let Some(entry) = self.lookup(idx).filter(|entry| !entry.is_leaf()) {
+                    entry.as_table::<M>().free_table(level - 1);
+                }
+            }
+        }
+        free_page(self.0 as *mut u8);
     }
 }
 
-const TRAMPOLINE: usize = MAX_VIRTUAL_ADDRESS - PAGE_SIZE;
-
+// Where each part of the kernel starts and ends, these come from linker.ld (up where the kernel's linked)
 extern "system" {
+    static text_start: u8;
     static text_end: u8;
+    static rodata_start: u8;
+    static rodata_end: u8;
+    static data_start: u8;
+    static kernel_end: u8;
+}
+
+// The kernel's sections, and what each one is allowed to do.
+// Nothing is ever both writable and executable ("W^X"), so a stray write can't turn into running
+// code we didn't write, and nothing can scribble over the code or the constants in .rodata
+struct KernelSections {
+    // Code: read and execute
+    text: (usize, usize),
+    // Constants and strings: read only
+    rodata: (usize, usize),
+    // Globals (.data and .bss, which includes the stacks): read and write
+    data: (usize, usize),
+}
+
+fn kernel_sections() -> KernelSections {
+    let address = |symbol: &u8| symbol as *const u8 as usize;
+    unsafe {
+        KernelSections {
+            text: (address(&text_start), address(&text_end)),
+            rodata: (address(&rodata_start), address(&rodata_end)),
+            data: (address(&data_start), address(&kernel_end)),
+        }
+    }
+}
+
+// Where the kernel stops in physical memory, RAM after this is kalloc's
+fn kernel_end_physical() -> usize {
+    virt_to_phys(kernel_sections().data.1)
 }
 
 fn kvm_make() -> Option<PageTable> {
-    let mut kernel_table = PageTable::new().expect("kvm_init: page alloc failed");
+    let mut kernel_table = PageTable::<Mode>::new().expect("kvm_init: page alloc failed");
 
     const RW: usize = PageTableEntry::FLAG_READ | PageTableEntry::FLAG_WRITE;
 
-    kernel_table.kvm_map(UART_LOC0, PAGE_SIZE, UART_LOC0, RW);
+    let platform = platform();
+
+    kernel_table.kvm_map_device(&platform.uart, RW);
+
+    for virtio in platform.virtio.iter().flatten() {
+        kernel_table.kvm_map_device(virtio, RW);
+    }
 
-    kernel_table.kvm_map(VIRTIO0, PAGE_SIZE, VIRTIO0, RW);
+    kernel_table.kvm_map_device(&platform.plic, RW);
 
-    kernel_table.kvm_map(PLIC, 0x4000000, PLIC, RW);
+    // Without Sstc or SBI firmware, supervisor mode sets its timer by writing MTIMECMP in the CLINT
+    #[cfg(not(feature = "sbi"))]
+    kernel_table.kvm_map_device(&platform.clint, RW);
 
-    let etext = unsafe { &text_end as *const u8 as usize };
+    // The kernel itself, up where it's linked
+    let sections = kernel_sections();
+    for ((start, end), perm) in [
+        (
+            sections.text,
+            PageTableEntry::FLAG_READ | PageTableEntry::FLAG_EXEC,
+        ),
+        (sections.rodata, PageTableEntry::FLAG_READ),
+        (sections.data, RW),
+    ] {
+        // A section can be empty (no constants, say), and map_pages doesn't like mapping nothing
+        if end > start {
+            kernel_table.kvm_map(start, end - start, virt_to_phys(start), perm);
+        }
+    }
 
+    // The direct map: all of RAM again, at DIRECT_MAP_BASE + its physical address. This is how we get at
+    // pages from kalloc, page tables, the device tree, anything we only have the physical address of.
+    // The kernel's own memory is in here a second time, but only to read: writing to its code or constants
+    // through here would get around W^X above. Nothing in here is executable
+    let kernel_end = kernel_end_physical();
+    let memory_start = platform.memory_start;
     kernel_table.kvm_map(
-        KERNEL_START,
-        etext - KERNEL_START,
-        KERNEL_START,
-        PageTableEntry::FLAG_EXEC | PageTableEntry::FLAG_READ,
+        phys_to_virt(memory_start),
+        kernel_end - memory_start,
+        memory_start,
+        PageTableEntry::FLAG_READ,
+    );
+    kernel_table.kvm_map(
+        phys_to_virt(kernel_end),
+        platform.memory_end - kernel_end,
+        kernel_end,
+        RW,
     );
 
-    kernel_table.kvm_map(etext, PHYS_STOP - etext, etext, RW);
+    // vmalloc and the kernel stacks get mapped a bit at a time later on. Address spaces start out with
+    // a copy of the top level of this table (see AddressSpace), so anything under a top level entry we
+    // haven't made yet wouldn't show up in them. Make the tables right under the top for both regions now
+    for (start, size) in [(VMALLOC_START, VMALLOC_SIZE), (KSTACK_START, KSTACK_SIZE)] {
+        for va in (start..start + size).step_by(page_size_at_level(Mode::LEVELS - 1)) {
+            kernel_table
+                .walk_to(VirtualAddr::new(va), Mode::LEVELS - 2, true)
+                .expect("kvm_make: region table");
+        }
+    }
 
-    //kernel_table.kvm_map(TRAMPOLINE, PAGE_SIZE, TRAMPOLINE, PageTableEntry::FLAG_EXEC | PageTableEntry::FLAG_READ);
+    // Punch the guard pages under every hart's boot stack back out, so running off the end of a stack
+    // faults instead of scribbling over the next one (see kstack.rs)
+    // Nobody is using this table yet, so the TLB shootdown in unmap_pages doesn't have anyone to shoot
+    for hart in 0..NUM_CPUS {
+        kernel_table
+            .unmap_pages(kstack::boot_guard_page(hart), 1, false)
+            .expect("kvm_make: guard page");
+    }
 
     Some(kernel_table)
 }
 
 static mut KERNEL_TABLE: Option<PageTable> = None;
 
+// Whether this hart can page with mode `M`. satp ignores a write with a mode the hart doesn't
+// support, so we write it and see if it stuck. If it did, paging is on with that table right there and then,
+// so it had better map us where we are. Everything we're using (the kernel, our stack, the direct map) is at or
+// above DIRECT_MAP_BASE, 256GiB from the top, and with more levels that's all under the last entry of each
+// level above the 1GiB one.
+// So the probe is a new top level (or two, for Sv57) whose last entry points down at BOOT_PAGE_TABLE, and
+// nothing moves. Then we go back to the boot table
+fn mode_supported<M: PagingMode>() -> bool {
+    let mut probe: [Option<PageTable<M>>; 2] = [None; 2];
+    let mut below = boot_page_table_address();
+    let mut made_all = true;
+    for table in probe.iter_mut().take(M::LEVELS - 3) {
+        let Some(mut new) = PageTable::<M>::new() else {
+            made_all = false;
+            break;
+        };
+        new.set(511, PageTableEntry::new(below, PageTableEntry::FLAG_VALID));
+        below = virt_to_phys(new.0 as usize);
+        *table = Some(new);
+    }
+    let supported = made_all
+        && unsafe {
+            riscv::register::satp::write((M::SATP_MODE << 60) | (below >> 12));
+            riscv::asm::sfence_vma_all();
+            let stuck = riscv::register::satp::read().bits() >> 60 == M::SATP_MODE;
+            riscv::register::satp::write(
+                (Sv39::SATP_MODE << 60) | (boot_page_table_address() >> 12),
+            );
+            riscv::asm::sfence_vma_all();
+            stuck
+        };
+    for table in probe.into_iter().flatten() {
+        free_page(table.0 as *mut u8);
+    }
+    supported
+}
+
+// Called by the boot hart while it's still on BOOT_PAGE_TABLE. The mode was picked when the kernel was built
+// (see Mode), so if the hart can't do it all we can do is say what it can do instead.
+// We only check this hart, they're all the same kind of hart on the machines we run on
 pub fn kvm_init_base() {
+    if !mode_supported::<Mode>() {
+        let best = if mode_supported::<Sv57>() {
+            Sv57::NAME
+        } else if mode_supported::<Sv48>() {
+            Sv48::NAME
+        } else {
+            Sv39::NAME
+        };
+        panic!(
+            "kvm_init: this hart can't do {} paging, build for {best} instead (see Cargo.toml)",
+            Mode::NAME
+        );
+    }
+    println!("kvm_init: using {} paging", Mode::NAME);
+
     unsafe {
         KERNEL_TABLE = Some(kvm_make().expect("kvm_init: table"));
     }
 }
 
+// Walk the kernel page table and make sure everything got the permissions kvm_make meant to give it,
+// called once by the boot hart right after kvm_init_base
+pub fn kvm_self_test() {
+    let table = unsafe { (*addr_of_mut!(KERNEL_TABLE)).expect("kvm_self_test: no kernel table") };
+    const R: usize = PageTableEntry::FLAG_READ;
+    const W: usize = PageTableEntry::FLAG_WRITE;
+    const X: usize = PageTableEntry::FLAG_EXEC;
+    const RWX: usize = R | W | X;
+
+    // What every page of each section should be. Then the same for the direct map: the kernel's own memory
+    // can only be read through it
+    let sections = kernel_sections();
+    let direct = |(start, end): (usize, usize)| {
+        (
+            phys_to_virt(virt_to_phys(start)),
+            phys_to_virt(virt_to_phys(end)),
+        )
+    };
+    let free = (
+        phys_to_virt(kernel_end_physical()),
+        phys_to_virt(platform().memory_end),
+    );
+    let checks = [
+        ("text", sections.text, R | X),
+        ("rodata", sections.rodata, R),
+        ("data", sections.data, R | W),
+        ("text in the direct map", direct(sections.text), R),
+        ("data in the direct map", direct(sections.data), R),
+        ("free memory", free, R | W),
+    ]
+    .map(|(name, (start, end), expected)| (name, (start, get_page_round_up(end)), expected));
+
+    // Go through every page in the table. Anything that's in one of the sections has to have its permissions,
+    // nothing is both writable and executable, and the guard pages under the boot stacks are holes
+    let mut mapped = checks.map(|_| 0);
+    let mut pages = [0; 5];
+    table.for_each_leaf(&mut |va, level, leaf| {
+        let perm = leaf.extract_flags() & RWX;
+        if perm & (W | X) == W | X {
+            panic!("kvm_self_test: {va:#x} is writable and executable");
+        }
+        // The very last page's end wraps around to 0
+        let end = va.saturating_add(page_size_at_level(level));
+        for (i, &(name, (start, stop), expected)) in checks.iter().enumerate() {
+            let overlap = end.min(stop).saturating_sub(va.max(start));
+            if overlap == 0 {
+                continue;
+            }
+            if perm != expected {
+                panic!("kvm_self_test: {name} page {va:#x} is {perm:#b}, should be {expected:#b}");
+            }
+            mapped[i] += overlap;
+        }
+        for hart in 0..NUM_CPUS {
+            if (va..end).contains(&kstack::boot_guard_page(hart)) {
+                panic!("kvm_self_test: hart {hart}'s boot stack guard page is mapped");
+            }
+        }
+        pages[level] += 1;
+    });
+
+    // And every page of each section is in there somewhere, bar the guard pages (they're in .bss)
+    for (i, &(name, (start, stop), _)) in checks.iter().enumerate() {
+        let holes = (0..NUM_CPUS)
+            .map(kstack::boot_guard_page)
+            .filter(|guard| (start..stop).contains(guard))
+            .count();
+        let size = stop.saturating_sub(start).saturating_sub(holes * PAGE_SIZE);
+        if mapped[i] != size {
+            panic!(
+                "kvm_self_test: {name} is {size:#x} bytes but only {:#x} are mapped",
+                mapped[i]
+            );
+        }
+    }
+
+    println!(
+        "Kernel page table self test OK ({}: {} 1GiB, {} 2MiB, {} 4KiB pages)",
+        Mode::NAME,
+        pages[2],
+        pages[1],
+        pages[0]
+    );
+}
+
+// Take pages out of the kernel page table, see unmap_pages
+pub fn kvm_unmap(virtual_addr: usize, npages: usize, free_phys: bool) -> Result<(), VmError> {
+    kernel_table().unmap_pages(virtual_addr, npages, free_phys)
+}
+
+// Change the permissions on part of the kernel page table, see protect
+pub fn kvm_protect(virtual_addr: usize, size: usize, perm: usize) -> Result<(), VmError> {
+    kernel_table().protect(virtual_addr, size, perm)
+}
+
+// Held while mapping into the kernel page table after boot. Two harts vmalloc'ing at once could both find
+// the same table missing and each make one. Unmapping and protect never make tables, so they don't need it
+static KVM_MAP_LOCK: Spinlock<()> = Spinlock::new("kvm_map", ());
+
+// Map [virtual_addr, virtual_addr + size) to memory starting at physical_address in the kernel page table,
+// like kvm_map but for after boot (vmalloc, process stacks), so it's an error rather than a panic if it fails.
+// It has to be somewhere kvm_make made tables for (see memlayout.rs), or address spaces won't see it
+pub fn kvm_map_pages(
+    virtual_addr: usize,
+    size: usize,
+    physical_address: usize,
+    perm: usize,
+) -> Result<(), VmError> {
+    let perm = perm
+        | PageTableEntry::FLAG_GLOBAL
+        | PageTableEntry::FLAG_ACCESSED
+        | PageTableEntry::FLAG_DIRTY;
+    let _guard = KVM_MAP_LOCK.lock();
+    kernel_table().map_pages(virtual_addr, size, physical_address, perm)
+}
+
+// The physical address kernel virtual address `va` goes to, if it's mapped.
+// memlayout::virt_to_phys is quicker for the direct map and the kernel, this is for everything else
+pub fn kvm_translate(va: usize) -> Option<usize> {
+    let (entry, level) = kernel_table().find_entry(VirtualAddr::new(va));
+    let pte = unsafe { *entry };
+    (pte.0 & PageTableEntry::FLAG_VALID != 0 && pte.is_leaf())
+        .then(|| pte.extract_physical_page_number() + (va & (page_size_at_level(level) - 1)))
+}
+
+fn kernel_table() -> PageTable {
+    unsafe { (*addr_of_mut!(KERNEL_TABLE)).expect("no kernel table") }
+}
+
 pub fn kvm_init_hart() {
     unsafe {
         // Ensure page table memory has been cleared
-        riscv::asm::sfence_vma(0, 0);
+        riscv::asm::sfence_vma_all();
 
-        riscv::register::satp::write((8_usize << 60) | (KERNEL_TABLE.unwrap().0 as usize >> 12));
+        riscv::register::satp::write(asid::satp_for(
+            Mode::SATP_MODE,
+            KERNEL_ASID,
+            virt_to_phys(kernel_table().0 as usize),
+        ));
 
         // Flush stale entries
-        riscv::asm::sfence_vma(0, 0);
+        riscv::asm::sfence_vma_all();
+    }
+}
+
+// A page table of its own, tagged with an ASID (see asid.rs), what each process will get once there are processes.
+// The kernel is shared with it: the top level starts out as a copy of the kernel table's, so the entries
+// under it are the kernel's own tables and the kernel is still there after we switch. The kernel's all in
+// the top half (see memlayout.rs), so an address space gets the whole bottom half to itself.
+// Anything the kernel maps under a new top level entry later on won't show up in address spaces made before that,
+// which is why kvm_make makes the ones for vmalloc and the process stacks up front
+pub struct AddressSpace {
+    table: PageTable,
+    asid: Asid,
+}
+
+impl AddressSpace {
+    pub fn new() -> Option<Self> {
+        let kernel = kernel_table();
+        let mut table = PageTable::<Mode>::new()?;
+        for idx in 0..512 {
+            if let Some(entry) = kernel.lookup(idx) {
+                table.set(idx, entry);
+            }
+        }
+        Some(Self {
+            table,
+            asid: Asid::new(),
+        })
+    }
+
+    // Make sure none of [virtual_addr, virtual_addr + size) is under one of the kernel's top level entries
+    fn check_not_kernels(&self, virtual_addr: usize, size: usize) -> Result<(), VmError> {
+        if size == 0 {
+            return Err(VmError::ZeroSize);
+        }
+        let end = virtual_addr.checked_add(size).ok_or(VmError::Overflow)?;
+        let top = Mode::LEVELS - 1;
+        let first = VirtualAddr::<Mode>::new(virtual_addr).extract_index_at_level(top);
+        let last = VirtualAddr::<Mode>::new(end - 1).extract_index_at_level(top);
+        if end > max_virtual_address()
+            || (first..=last).any(|idx| kernel_table().lookup(idx).is_some())
+        {
+            return Err(VmError::BadAddress);
+        }
+        Ok(())
+    }
+
+    pub fn map(
+        &mut self,
+        virtual_addr: usize,
+        size: usize,
+        physical_address: usize,
+        perm: usize,
+    ) -> Result<(), VmError> {
+        self.check_not_kernels(virtual_addr, size)?;
+        let asid = self.asid.number();
+        self.table
+            .map_pages_then(virtual_addr, size, physical_address, perm, |start, size| {
+                tlb::shootdown_asid(asid, start, size)
+            })
+    }
+
+    // Allocate `npages` zeroed pages and map them from virtual_addr, with `perm`.
+    // The reclaimer can take these back when memory runs out. It only looks at the Dirty bit to see if
+    // a page has anything in it, so fill them in through this mapping, not through the kernel's
+    pub fn alloc_pages(
+        &mut self,
+        virtual_addr: usize,
+        npages: usize,
+        perm: usize,
+    ) -> Result<(), VmError> {
+        self.check_not_kernels(virtual_addr, pages_to_size(npages)?)?;
+        for i in 0..npages {
+            let va = virtual_addr + i * PAGE_SIZE;
+            let mapped = allocate_page()
+                .ok_or(VmError::OutOfMemory)
+                .and_then(|page| {
+                    let physical = virt_to_phys(page as usize);
+                    self.map(va, PAGE_SIZE, physical, perm)
+                        .inspect_err(|_| free_page(page))
+                        .map(|_| physical)
+                });
+            let physical = match mapped {
+                Ok(physical) => physical,
+                Err(error) => {
+                    // We just mapped these, so taking them back can't fail
+                    if i > 0 {
+                        self.unmap(virtual_addr, i, true)
+                            .expect("alloc_pages: unmapping after running out");
+                    }
+                    return Err(error);
+                }
+            };
+            reclaim::track(self.table.0 as usize, va, physical);
+        }
+        Ok(())
+    }
+
+    // Map `npages` pages from virtual_addr with `perm`, but don't allocate anything for them yet.
+    // Each one starts off looking like the reclaimer already took it, so the first time it's touched
+    // handle_page_fault gives it a zeroed page (and from then on the reclaimer tracks it)
+    pub fn reserve_pages(
+        &mut self,
+        virtual_addr: usize,
+        npages: usize,
+        perm: usize,
+    ) -> Result<(), VmError> {
+        let size = pages_to_size(npages)?;
+        self.check_not_kernels(virtual_addr, size)?;
+        let end = PageTable::<Mode>::check_range(virtual_addr, size)?;
+        if perm
+            & (PageTableEntry::FLAG_READ | PageTableEntry::FLAG_WRITE | PageTableEntry::FLAG_EXEC)
+            == 0
+        {
+            return Err(VmError::BadPermissions);
+        }
+        if self.table.any_mapped(virtual_addr, size) {
+            return Err(VmError::Remap);
+        }
+        for i in 0..npages {
+            let va = virtual_addr + i * PAGE_SIZE;
+            let Some((entry, _)) = self.table.walk_to(VirtualAddr::new(va), 0, true) else {
+                // Nothing's been handed out for these yet, so there's nothing to free but the tables
+                if i > 0 {
+                    self.table
+                        .for_each_leaf_in(virtual_addr, i * PAGE_SIZE, |entry, _| unsafe {
+                            *entry = PageTableEntry(0)
+                        });
+                }
+                let asid = self.asid.number();
+                self.table
+                    .free_empty_tables(virtual_addr, end, |start, size| {
+                        tlb::shootdown_asid(asid, start, size)
+                    });
+                return Err(VmError::OutOfMemory);
+            };
+            unsafe { *entry = PageTableEntry(perm | PageTableEntry::FLAG_EVICTED) };
+        }
+        Ok(())
+    }
+
+    // See unmap_pages, but we only flush the TLB for our own ASID
+    pub fn unmap(
+        &mut self,
+        virtual_addr: usize,
+        npages: usize,
+        free_phys: bool,
+    ) -> Result<(), VmError> {
+        let size = pages_to_size(npages)?;
+        self.check_not_kernels(virtual_addr, size)?;
+        self.forget_pages(virtual_addr, size);
+        let asid = self.asid.number();
+        self.table
+            .unmap_pages_then(virtual_addr, npages, free_phys, |start, size| {
+                tlb::shootdown_asid(asid, start, size)
+            })
+    }
+
+    // Tell the reclaimer to stop tracking the pages in [virtual_addr, virtual_addr + size), they're going.
+    // It only tracks small pages, so holes and huge pages get skipped over whole. A page that's leaving isn't
+    // valid but still counts, the reclaimer mustn't finish taking it once we've started unmapping it
+    fn forget_pages(&self, virtual_addr: usize, size: usize) {
+        let table = self.table.0 as usize;
+        let end = virtual_addr.saturating_add(size);
+        let mut va = virtual_addr;
+        while va < end {
+            let (entry, level) = self.table.find_entry(VirtualAddr::new(va));
+            let pte = unsafe { *entry };
+            if level == 0 && (pte.0 & PageTableEntry::FLAG_VALID != 0 || pte.is_leaving()) {
+                reclaim::forget(table, va, pte.extract_physical_page_number());
+            }
+            let page = page_size_at_level(level);
+            va = (va & !(page - 1)).saturating_add(page);
+        }
+    }
+
+    // See protect, again only flushing our own ASID
+    pub fn protect(
+        &mut self,
+        virtual_addr: usize,
+        size: usize,
+        perm: usize,
+    ) -> Result<(), VmError> {
+        self.check_not_kernels(virtual_addr, size)?;
+        let asid = self.asid.number();
+        self.table
+            .protect_then(virtual_addr, size, perm, |start, size| {
+                tlb::shootdown_asid(asid, start, size)
+            })
+    }
+
+    // Print our page table, see PageTable::print_ranges. Nobody else can change it while we have it
+    pub fn print_ranges(&self) {
+        println!(
+            "Address space (ASID {}, {} at {:#x}):",
+            self.asid.number(),
+            Mode::NAME,
+            self.table.0 as usize
+        );
+        self.table.print_ranges();
+    }
+
+    // Free our page tables (not the memory they map). It mustn't be running on any hart.
+    // Our ASID isn't given back, it's just not used again until the next generation
+    pub fn destroy(self) {
+        let kernel = kernel_table();
+        for idx in 0..512 {
+            if kernel.lookup(idx).is_some() {
+                continue;
+            }
+            if let Some(entry) = self.table.lookup(idx).filter(|entry| !entry.is_leaf()) {
+                // Only what's under our own top level entries is ours to forget, the kernel's never get tracked
+                let top = page_size_at_level(Mode::LEVELS - 1);
+                self.forget_pages(Mode::canonical(idx * top), top);
+                entry.as_table::<Mode>().free_table(Mode::LEVELS - 2);
+            }
+        }
+        free_page(self.table.0 as *mut u8);
+    }
+}
+
+fn set_satp(table: PageTable, asid: usize, flush: bool) {
+    unsafe {
+        riscv::register::satp::write(asid::satp_for(
+            Mode::SATP_MODE,
+            asid,
+            virt_to_phys(table.0 as usize),
+        ));
+        if flush {
+            riscv::asm::sfence_vma_all();
+        }
+    }
+}
+
+// Switch this hart over to `space`. Thanks to ASIDs there's nothing to flush, unless we've just run out of them
+pub fn switch_to(space: &AddressSpace) {
+    let (asid, flush) = asid::activate(&space.asid);
+    set_satp(space.table, asid, flush);
+}
+
+// Switch this hart back to the kernel's own page table
+pub fn switch_to_kernel() {
+    let flush = asid::activate_kernel();
+    set_satp(kernel_table(), KERNEL_ASID, flush);
+}
+
+// Map the same address to different memory in two address spaces, and check switching between them
+// (without flushing anything) sees the right one every time, then that protect and unmap do what
+// they say. Called once by the boot hart
+pub fn address_space_self_test() {
+    const RW: usize = PageTableEntry::FLAG_READ | PageTableEntry::FLAG_WRITE;
+    // Half way up the bottom half, which is all ours now the kernel lives up top
+    let va = max_virtual_address() / 2;
+    let mut spaces =
+        [0, 1].map(|_| AddressSpace::new().expect("address space self test: no memory"));
+    let pages = [0, 1].map(|_| allocate_page().expect("address space self test: no memory"));
+    for (i, (space, page)) in spaces.iter_mut().zip(pages).enumerate() {
+        space
+            .map(va, PAGE_SIZE, virt_to_phys(page as usize), RW)
+            .expect("address space self test: no memory");
+        // kalloc gives us the page in the direct map, so we can fill it in from here
+        unsafe { (page as *mut usize).write_volatile(i + 1) };
+    }
+
+    for _ in 0..2 {
+        for (i, space) in spaces.iter().enumerate() {
+            switch_to(space);
+            let seen = unsafe { (va as *const usize).read_volatile() };
+            switch_to_kernel();
+            assert_eq!(seen, i + 1, "address space self test: saw the wrong page");
+        }
+    }
+
+    let flags_of = |space: &AddressSpace| {
+        space
+            .table
+            .walk(VirtualAddr::new(va), false)
+            .map(|(entry, _)| unsafe { (*entry).extract_flags() })
+            .filter(|flags| flags & PageTableEntry::FLAG_VALID != 0)
+    };
+
+    spaces[1]
+        .protect(va, PAGE_SIZE, PageTableEntry::FLAG_READ)
+        .expect("address space self test: protect");
+    assert_eq!(
+        flags_of(&spaces[1]).map(|flags| flags & RW),
+        Some(PageTableEntry::FLAG_READ),
+        "address space self test: protect didn't take"
+    );
+    assert_eq!(
+        spaces[1].map(va, PAGE_SIZE, virt_to_phys(pages[1] as usize), RW),
+        Err(VmError::Remap),
+        "address space self test: mapped over a page"
+    );
+
+    for space in spaces.iter_mut() {
+        space
+            .unmap(va, 1, true)
+            .expect("address space self test: unmap");
+        assert!(
+            flags_of(space).is_none(),
+            "address space self test: unmap left the page mapped"
+        );
+    }
+    assert_eq!(
+        spaces[0].unmap(va, 1, false),
+        Err(VmError::NotMapped),
+        "address space self test: unmapped a page twice"
+    );
+
+    for space in spaces {
+        space.destroy();
+    }
+    println!("Address space self test OK ({} ASID bits)", asid::bits());
+}
+
+// Print the kernel page table, for the `vm` monitor command.
+// We hold KVM_MAP_LOCK so nobody frees a table out from under us (see free_empty_tables)
+pub fn print_kernel_ranges() {
+    let _guard = KVM_MAP_LOCK.lock();
+    println!(
+        "Kernel page table ({} at {:#x}):",
+        Mode::NAME,
+        kernel_table().0 as usize
+    );
+    kernel_table().print_ranges();
+}
+
+// The page table this hart is using, out of satp
+fn current_table() -> PageTable {
+    let ppn = riscv::register::satp::read().bits() & ((1 << 44) - 1);
+    PageTable::from_address(phys_to_virt(ppn << 12))
+}
+
+// Cross-call: write the page table this hart is using to the usize at `out`
+fn current_table_call(out: usize) {
+    // Safety: print_process_ranges waits for us, the usize is on its stack
+    unsafe { *(out as *mut usize) = current_table().0 as usize };
+}
+
+// Print the page table process `pid` is using, for `vm <pid>`.
+// Processes don't move between harts yet, so we ask the hart it's on for whatever's in its satp right now.
+// If that's the kernel's we print it. If it's an address space of its own we only say where it is: it
+// belongs to whoever made it and could be changed or freed while we walked it, so only they can print it
+// (see AddressSpace::print_ranges). Returns false if there's no such process running
+pub fn print_process_ranges(pid: Pid) -> bool {
+    let Some(hart) = (0..NUM_CPUS).find(|&hart| {
+        // Safety: just a peek for debugging, it might be out of date by the time we use it
+        hotplug::is_online(hart) && unsafe { (*crate::cpu::CPUS.remote(hart)).process } == Some(pid)
+    }) else {
+        return false;
+    };
+    let mut table = 0;
+    if ipi::run_on(
+        1 << hart,
+        current_table_call,
+        &mut table as *mut usize as usize,
+    ) == 0
+    {
+        return false;
+    }
+    if table == kernel_table().0 as usize {
+        println!("Process {pid} (on CPU {hart}) is using the kernel page table:");
+        print_kernel_ranges();
+    } else {
+        println!("Process {pid} (on CPU {hart}) has its own page table at {table:#x}");
+    }
+    true
+}
+
+// Get a page for handle_page_fault. If whatever faulted had interrupts on we turn them back on while we
+// look, so that if we're out the reclaimer can take one from somewhere (it won't run with them off, see
+// reclaim_one). Whoever faulted can't have been holding a spinlock, so that's as safe as it was for them
+fn allocate_page_for_fault() -> Option<*mut u8> {
+    let interrupts_were_on = riscv::register::sstatus::read().spie();
+    if interrupts_were_on {
+        unsafe { riscv::register::sstatus::set_sie() };
+    }
+    let page = allocate_page();
+    if interrupts_were_on {
+        unsafe { riscv::register::sstatus::clear_sie() };
+    }
+    page
+}
+
+// Called by kernel_trap for a page fault at `va`, `cause` is 12, 13 or 15 (instruction, load or store).
+// Returns true if it was one we can fix, and then the instruction just runs again:
+// - A or D needed setting and the hardware doesn't do that itself (see AD_FAULTS)
+// - The reclaimer is taking the page, so we put it back
+// - The reclaimer took the page, so we give it a new zeroed one, or read it back in if it went to swap
+// Anything else is a real fault, and so is running out of memory or the swap disk failing on us: there's
+// nothing we can give back that would be right, so it's up to whoever gets the fault what happens next.
+// The kernel can only touch user pages when SUM is set, and pages the reclaimer takes are as likely to be
+// user pages as anything, so those get fixed the same way when SUM is on. User mode's own faults can come
+// through here too once there is one
+pub fn handle_page_fault(va: usize, cause: usize) -> bool {
+    const A: usize = PageTableEntry::FLAG_ACCESSED;
+    const D: usize = PageTableEntry::FLAG_DIRTY;
+    let table = current_table();
+    let (entry, level) = table.find_entry(VirtualAddr::new(va));
+    let pte = unsafe { *entry };
+    let needed = match cause {
+        12 => PageTableEntry::FLAG_EXEC,
+        13 => PageTableEntry::FLAG_READ,
+        _ => PageTableEntry::FLAG_WRITE,
+    };
+    // Not allowed at all, or a user page when the kernel hasn't asked to touch those
+    let user_allowed = riscv::register::sstatus::read().sum();
+    if pte.0 & needed == 0 || (pte.0 & PageTableEntry::FLAG_USER != 0 && !user_allowed) {
+        return false;
+    }
+    let ad = if cause == 15 { A | D } else { A };
+
+    if pte.0 & PageTableEntry::FLAG_VALID != 0 {
+        // Another hart (or the hardware) might have set them since, that's fine, we just want them set
+        PageTableEntry::atomic(entry).fetch_or(ad, Ordering::AcqRel);
+        AD_FAULTS.fetch_add(1, Ordering::Relaxed);
+        // Our TLB might have the old entry without them
+        tlb::flush_local(va, PAGE_SIZE);
+        return true;
+    }
+
+    if pte.is_leaving() && level == 0 {
+        // The page is still right there, so we take it back. If the reclaimer finished first this fails, and
+        // running the instruction again gets us a fault on an evicted page instead
+        let back = (pte.0 & !PageTableEntry::FLAG_SWAPPED) | PageTableEntry::FLAG_VALID | ad;
+        let _ = PageTableEntry::atomic(entry).compare_exchange(
+            pte.0,
+            back,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+        return true;
+    }
+
+    if pte.0 & PageTableEntry::FLAG_EVICTED != 0 && level == 0 {
+        // allocate_page already asks the reclaimer for one if it's out, if even that can't find one we're stuck
+        let Some(page) = allocate_page_for_fault() else {
+            return false;
+        };
+        let mut flags = (pte.extract_flags()
+            & !(PageTableEntry::FLAG_EVICTED | PageTableEntry::FLAG_SWAPPED))
+            | PageTableEntry::FLAG_VALID
+            | ad;
+        let slot = pte.is_swapped().then(|| pte.swap_slot());
+        if let Some(slot) = slot {
+            if let Err(error) = swap::read_in(slot, page) {
+                println!("handle_page_fault: couldn't read {va:#x} back from swap slot {slot}: {error:?}");
+                free_page(page);
+                return false;
+            }
+            // It's not all zeroes, so if the reclaimer takes it again it has to go back out to swap
+            flags |= D;
+        }
+        let new = PageTableEntry::new(virt_to_phys(page as usize), flags);
+        // Another hart could have faulted on the same page at the same time, if it won it can keep its page
+        if PageTableEntry::atomic(entry)
+            .compare_exchange(pte.0, new.0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            reclaim::track(
+                table.0 as usize,
+                va & !(PAGE_SIZE - 1),
+                virt_to_phys(page as usize),
+            );
+            if let Some(slot) = slot {
+                swap::free_slot(slot);
+            }
+        } else {
+            free_page(page);
+        }
+        return true;
+    }
+
+    false
+}
+
+// What the reclaimer found when it looked at a page
+pub enum Age {
+    // It's been used since last time we looked, we've cleared Accessed so we'll know if it's used again
+    Young,
+    // Not used, but it's been written to, so there's something in it we can't just throw away, and
+    // there's no swap to put it in
+    Dirty,
+    // Not used, so we've started taking it: the entry's marked as leaving, and still points at the page at
+    // `physical`. Before finish_eviction it needs shooting out of everyone's TLB, and if it was dirty
+    // writing out to `swap_slot`
+    Leaving {
+        physical: usize,
+        swap_slot: Option<usize>,
+    },
+    // Not mapped any more (or not a normal page), stop tracking it
+    Gone,
+}
+
+// One tick of the reclaimer's clock hand over the page at `va` in the page table at `table`
+pub fn age_page(table: usize, va: usize) -> Age {
+    const A: usize = PageTableEntry::FLAG_ACCESSED;
+    const D: usize = PageTableEntry::FLAG_DIRTY;
+    let (entry, level) = PageTable::<Mode>::from_address(table).find_entry(VirtualAddr::new(va));
+    let pte = unsafe { *entry };
+    if level != 0 || pte.0 & PageTableEntry::FLAG_VALID == 0 || !pte.is_leaf() {
+        return Age::Gone;
+    }
+    if pte.0 & A != 0 {
+        // We don't flush the TLB for this, so a hart with it cached won't set A again until it's
+        // flushed for some other reason. Everyone does this, a flush on every tick would cost far more
+        PageTableEntry::atomic(entry).fetch_and(!A, Ordering::AcqRel);
+        return Age::Young;
+    }
+    // A dirty page needs somewhere to go. D stays set while it's leaving, so if a fault puts it back
+    // it's still dirty
+    let swap_slot = if pte.0 & D != 0 {
+        let Some(slot) = swap::reserve() else {
+            return Age::Dirty;
+        };
+        Some(slot)
+    } else {
+        None
+    };
+    let leaving = (pte.0 & !PageTableEntry::FLAG_VALID) | PageTableEntry::FLAG_SWAPPED;
+    // If A or D got set since we looked it's been used after all, leave it be
+    match PageTableEntry::atomic(entry).compare_exchange(
+        pte.0,
+        leaving,
+        Ordering::AcqRel,
+        Ordering::Acquire,
+    ) {
+        Ok(_) => Age::Leaving {
+            physical: pte.extract_physical_page_number(),
+            swap_slot,
+        },
+        Err(_) => {
+            if let Some(slot) = swap_slot {
+                swap::free_slot(slot);
+            }
+            Age::Young
+        }
+    }
+}
+
+// Finish taking the leaving page at `va` in the page table at `table`: it's been shot out of every TLB and
+// written out to `swap_slot` if it needed to be. Keep the flags so a fault can bring it back the same, but
+// swap the address for the slot (or nothing). Returns false if a fault put it back first, and then the page
+// is still in use. The caller has to know the table is still around, see reclaim_one
+pub fn finish_eviction(table: usize, va: usize, swap_slot: Option<usize>) -> bool {
+    let (entry, _) = PageTable::<Mode>::from_address(table).find_entry(VirtualAddr::new(va));
+    let pte = unsafe { *entry };
+    if !pte.is_leaving() {
+        return false;
+    }
+    let flags = (pte.extract_flags()
+        & !(PageTableEntry::FLAG_SWAPPED
+            | PageTableEntry::FLAG_ACCESSED
+            | PageTableEntry::FLAG_DIRTY))
+        | PageTableEntry::FLAG_EVICTED;
+    let evicted = match swap_slot {
+        Some(slot) => PageTableEntry::new(slot << 12, flags | PageTableEntry::FLAG_SWAPPED),
+        None => PageTableEntry(flags),
+    };
+    PageTableEntry::atomic(entry)
+        .compare_exchange(pte.0, evicted.0, Ordering::AcqRel, Ordering::Acquire)
+        .is_ok()
+}
+
+// Give up on taking the leaving page at `va` in the page table at `table` and make it valid again,
+// unless a fault already has
+pub fn cancel_eviction(table: usize, va: usize) {
+    let (entry, _) = PageTable::<Mode>::from_address(table).find_entry(VirtualAddr::new(va));
+    let pte = unsafe { *entry };
+    if pte.is_leaving() {
+        let back = (pte.0 & !PageTableEntry::FLAG_SWAPPED) | PageTableEntry::FLAG_VALID;
+        let _ = PageTableEntry::atomic(entry).compare_exchange(
+            pte.0,
+            back,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
     }
 }
@@ -0,0 +1,165 @@
+// vmalloc: memory that's contiguous in virtual addresses but not in physical ones.
+// kalloc only hands out single pages, and after a while of running there might not be two free pages
+// next to each other anywhere. Anything bigger than a page gets pages from wherever kalloc has them,
+// and they're mapped one after the other up in the vmalloc region (see memlayout.rs).
+
+// Each allocation is an "area": a run of mapped pages, with one page after it we leave unmapped.
+// Running off the end of an area faults on that page instead of scribbling over the next one.
+// We find room for a new area the simple way: the lowest address it fits at ("first fit").
+
+// Since the pages aren't next to each other in physical memory, nothing in here can be handed to a device
+// as one buffer (see BlockDevice::request in virtio.rs)
+
+use crate::{
+    kalloc::{allocate_page, free_page, PAGE_SIZE},
+    memlayout::{virt_to_phys, VMALLOC_SIZE, VMALLOC_START},
+    println,
+    spinlock::Spinlock,
+    vm::{self, VmError},
+};
+
+// How many areas there can be at once
+const MAX_AREAS: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Area {
+    start: usize,
+    // Not counting the guard page after it
+    npages: usize,
+}
+
+impl Area {
+    // Where the next area could start: after our pages and the guard page
+    fn end(&self) -> usize {
+        self.start + (self.npages + 1) * PAGE_SIZE
+    }
+}
+
+// Only held while we pick an address, the mapping happens once it's dropped (allocating pages can end up
+// in the reclaimer, which waits on other harts)
+static AREAS: Spinlock<[Option<Area>; MAX_AREAS]> = Spinlock::new("vmalloc", [None; MAX_AREAS]);
+
+// The lowest address `npages` pages (and a guard page) fit at without running into another area
+fn find_room(areas: &[Option<Area>; MAX_AREAS], npages: usize) -> Option<usize> {
+    let size = (npages + 1) * PAGE_SIZE;
+    // An area can only start at the bottom of the region or right after another one
+    core::iter::once(VMALLOC_START)
+        .chain(areas.iter().flatten().map(Area::end))
+        .filter(|&start| start + size <= VMALLOC_START + VMALLOC_SIZE)
+        .filter(|&start| {
+            areas
+                .iter()
+                .flatten()
+                .all(|area| start + size <= area.start || start >= area.end())
+        })
+        .min()
+}
+
+// Take `start`'s area back out, once nothing's mapped in it any more
+fn forget(start: usize) {
+    let mut areas = AREAS.lock();
+    if let Some(area) = areas
+        .iter_mut()
+        .find(|area| area.is_some_and(|area| area.start == start))
+    {
+        *area = None;
+    }
+}
+
+// Get `size` bytes (rounded up to whole pages) of zeroed memory, None if we're out of memory or room
+pub fn vmalloc(size: usize) -> Option<*mut u8> {
+    let npages = size.div_ceil(PAGE_SIZE);
+    if npages == 0 {
+        return None;
+    }
+    let start = {
+        let mut areas = AREAS.lock();
+        let slot = areas.iter().position(|area| area.is_none())?;
+        let start = find_room(&areas, npages)?;
+        areas[slot] = Some(Area { start, npages });
+        start
+    };
+
+    for i in 0..npages {
+        let va = start + i * PAGE_SIZE;
+        let mapped = allocate_page()
+            .ok_or(VmError::OutOfMemory)
+            .and_then(|page| {
+                vm::kvm_map_pages(
+                    va,
+                    PAGE_SIZE,
+                    virt_to_phys(page as usize),
+                    vm::PTE_READ | vm::PTE_WRITE,
+                )
+                .inspect_err(|_| free_page(page))
+            });
+        if mapped.is_err() {
+            if i > 0 {
+                vm::kvm_unmap(start, i, true).expect("vmalloc: unmapping after running out");
+            }
+            forget(start);
+            return None;
+        }
+    }
+    Some(start as *mut u8)
+}
+
+// Give back memory from vmalloc, pages and all
+pub fn vfree(ptr: *mut u8) {
+    let start = ptr as usize;
+    let area = AREAS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|area| area.start == start)
+        .copied();
+    let Some(area) = area else {
+        panic!("vfree: {start:#x} didn't come from vmalloc");
+    };
+    // Unmapped before the area's forgotten, so nobody else can be given the addresses while they're still mapped
+    vm::kvm_unmap(start, area.npages, true).expect("vfree: unmap");
+    forget(start);
+}
+
+// Get more than a page from vmalloc and use all of it, check the guard page after it isn't mapped, then free
+// it and check it's all gone. Called once by the boot hart with interrupts on
+pub fn self_test() {
+    const PAGES: usize = 4;
+    let words = PAGES * PAGE_SIZE / core::mem::size_of::<usize>();
+    let ptr = vmalloc(PAGES * PAGE_SIZE).expect("vmalloc self test: vmalloc failed") as *mut usize;
+    let start = ptr as usize;
+    for i in 0..words {
+        unsafe { ptr.add(i).write_volatile(i) };
+    }
+    for i in 0..words {
+        let seen = unsafe { ptr.add(i).read_volatile() };
+        assert_eq!(seen, i, "vmalloc self test: word {i} came back wrong");
+    }
+    assert!(
+        vm::kvm_translate(start + PAGES * PAGE_SIZE).is_none(),
+        "vmalloc self test: the guard page is mapped"
+    );
+
+    // A second area goes after the first one's guard page
+    let second = vmalloc(1).expect("vmalloc self test: second vmalloc failed");
+    assert_eq!(
+        second as usize,
+        start + (PAGES + 1) * PAGE_SIZE,
+        "vmalloc self test: second area isn't right after the first"
+    );
+
+    vfree(ptr as *mut u8);
+    assert!(
+        (0..PAGES).all(|i| vm::kvm_translate(start + i * PAGE_SIZE).is_none()),
+        "vmalloc self test: vfree left pages mapped"
+    );
+    // First fit, so the space the first one left gets used again
+    let again = vmalloc(PAGE_SIZE).expect("vmalloc self test: vmalloc after vfree failed");
+    assert_eq!(
+        again as usize, start,
+        "vmalloc self test: didn't reuse the freed space"
+    );
+    vfree(again);
+    vfree(second);
+    println!("vmalloc self test OK ({PAGES} pages at {start:#x})");
+}